@@ -0,0 +1,43 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use loramesh::stack::*;
+use std::time::Duration;
+
+// Raw bytes as heard over the air, split into length prefixed frames so
+// chunks of the same frame can be reassembled. Nothing may panic.
+fuzz_target!(|data: &[u8]| {
+    let mut reassembly = Reassembly::new(Duration::from_secs(10));
+    let mut rest = data;
+    while let Some((len, tail)) = rest.split_first() {
+        let len = (*len as usize).min(tail.len());
+        let (bytes, tail) = tail.split_at(len);
+        rest = tail;
+
+        let frame = match Frame::from_bytes(&bytes.to_vec()) {
+            Ok(frame) => frame,
+            Err(_) => continue,
+        };
+        let mut frame = match reassembly.push(frame) {
+            Ok(Some(frame)) => frame,
+            _ => continue,
+        };
+
+        // decoded frames encode back into frames that decode
+        let mut again = Frame::from_bytes(&frame.to_bytes()).expect("Re-encoded frame did not decode");
+        assert_eq!(again.payload(), frame.payload());
+
+        match frame.msgtype() {
+            MessageType::Broadcast => { let _ = BroadcastMessage::from_frame(&mut frame); },
+            MessageType::IPAssignSuccess => { let _ = IPAssignSuccessMessage::from_frame(&mut frame); },
+            MessageType::IPAssignFailure => { let _ = IPAssignFailureMessage::from_frame(&mut frame); },
+            MessageType::RouteDiscovery => { let _ = RouteDiscoveryMessage::from_frame(&mut frame); },
+            MessageType::RouteSuccess => { let _ = RouteSuccessMessage::from_frame(&mut frame); },
+            MessageType::RouteFailure => { let _ = RouteFailureMessage::from_frame(&mut frame); },
+            MessageType::TransmitRequest => { let _ = TransmitRequestMessage::from_frame(&mut frame); },
+            MessageType::TransmitConfirm => { let _ = TransmitConfirmMessage::from_frame(&mut frame); },
+            MessageType::IPPacket => { let _ = IPPacketMessage::from_frame(&mut frame); },
+            MessageType::Ack => { let _ = AckMessage::from_frame(&mut frame); },
+            MessageType::CompressedIPPacket => { let _ = CompressedIPPacketMessage::from_frame(&mut frame); },
+        }
+    }
+});
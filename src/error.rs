@@ -0,0 +1,92 @@
+use std::fmt;
+use std::io;
+use std::net::Ipv4Addr;
+use crate::stack::NodeId;
+
+/// Why bytes received over the air couldn't be decoded into a frame or message
+/* Anything a radio hears ends up here rather than in a panic, so garbage
+or hostile frames are dropped without taking the node down. */
+#[derive(Clone, Debug, PartialEq)]
+pub enum FrameError {
+    /// the data ends before the named field
+    Truncated(&'static str),
+    /// the frame was written in a protocol version we don't speak
+    UnsupportedVersion(u8),
+    /// the transmission state isn't one we know
+    UnknownTransmissionState(u8),
+    /// the message type isn't one we know
+    UnknownMessageType(u8),
+    /// the chunk index doesn't fit the chunk count or transmission state
+    InvalidChunk { chunk: u8, chunks: u8 },
+    /// a frame would need more chunks than a chunk count can number
+    TooManyChunks(usize),
+    /// a field the message can't do without is absent
+    Missing(&'static str),
+    /// a boolean field is neither 0 nor 1
+    InvalidBool(u8),
+    /// a subnet's prefix length is out of range
+    InvalidPrefixLength(u8),
+    /// a subnet isn't within a private or shared network
+    NonLocalSubnet(Ipv4Addr, u8),
+    /// a text field isn't valid UTF-8
+    InvalidUtf8,
+    /// the payload isn't a valid IPv4 packet
+    InvalidPacket,
+    /// the compressed payload couldn't be expanded
+    Compression(String),
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FrameError::Truncated(field) => write!(f, "Frame truncated before its {}", field),
+            FrameError::UnsupportedVersion(version) => write!(f, "Unsupported protocol version {}", version),
+            FrameError::UnknownTransmissionState(flag) => write!(f, "Unknown transmission state {}", flag),
+            FrameError::UnknownMessageType(msgtype) => write!(f, "Unknown message type {}", msgtype),
+            FrameError::InvalidChunk { chunk, chunks } => write!(f, "Invalid chunk {} of {}", chunk, chunks),
+            FrameError::TooManyChunks(chunks) => write!(f, "Frame needs {} chunks, more than {}", chunks, u8::MAX),
+            FrameError::Missing(field) => write!(f, "Frame is missing its {}", field),
+            FrameError::InvalidBool(byte) => write!(f, "Invalid boolean {}", byte),
+            FrameError::InvalidPrefixLength(len) => write!(f, "Invalid subnet prefix length {}", len),
+            FrameError::NonLocalSubnet(prefix, len) => write!(f, "Subnet {}/{} isn't a private or shared network", prefix, len),
+            FrameError::InvalidUtf8 => write!(f, "Invalid UTF-8 text"),
+            FrameError::InvalidPacket => write!(f, "Invalid IPv4 packet"),
+            FrameError::Compression(reason) => write!(f, "Invalid compressed payload: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for FrameError {}
+
+impl From<FrameError> for io::Error {
+    fn from(e: FrameError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, e)
+    }
+}
+
+/// Why a gateway wouldn't lease an address to a node
+/* These go back to the node as the reason of an IPAssignFailureMessage. */
+#[derive(Clone, Debug, PartialEq)]
+pub enum LeaseError {
+    /// every address in the pool is leased
+    PoolExhausted,
+    /// another node already uses this ID
+    IdConflict(NodeId),
+    /// the address the node claims is leased to another node
+    AddressConflict { ipaddr: Ipv4Addr, holder: NodeId },
+    /// the node isn't allowed an address
+    Denied(NodeId),
+}
+
+impl fmt::Display for LeaseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LeaseError::PoolExhausted => write!(f, "Address pool exhausted"),
+            LeaseError::IdConflict(nodeid) => write!(f, "Node ID {} is already in use", nodeid),
+            LeaseError::AddressConflict { ipaddr, holder } => write!(f, "Address {} is leased to node {}", ipaddr, holder),
+            LeaseError::Denied(nodeid) => write!(f, "Node {} is denied an address", nodeid),
+        }
+    }
+}
+
+impl std::error::Error for LeaseError {}
@@ -3,14 +3,13 @@ use std::fs;
 use std::io::{BufRead, BufReader, Error, ErrorKind};
 use std::io;
 use crossbeam_channel;
-use crossbeam_channel::{Sender, Receiver};
 use hex;
 use std::thread;
 use std::time::Duration;
 use format_escape_default::format_escape_default;
 use std::path::PathBuf;
-use ratelimit_meter::{DirectRateLimiter, LeakyBucket};
 use crate::hardware::serial::SerialIO;
+use crate::hardware::radio::{Radio, RadioCapabilities, RadioFrame, LinkMetrics};
 use crate::settings::Settings;
 
 pub fn mkerror(msg: &str) -> Error {
@@ -27,13 +26,9 @@ pub struct LoStik {
     // serial messages coming from the radio
     readerlinesrx: crossbeam_channel::Receiver<String>,
 
-    // channels for receiving radio packets
-    rxsender: crossbeam_channel::Sender<Vec<u8>>,
-    rxreader: crossbeam_channel::Receiver<Vec<u8>>,
-
-    // channels for transmitting radio packets
-    pub txsender: crossbeam_channel::Sender<Vec<u8>>,
-    txreader: crossbeam_channel::Receiver<Vec<u8>>,
+    // channels for decoded radio packets
+    rxsender: crossbeam_channel::Sender<RadioFrame>,
+    rxreader: crossbeam_channel::Receiver<RadioFrame>,
 }
 
 /// Reads the lines from the radio and sends them down the channel to
@@ -64,111 +59,19 @@ pub fn assert_response(resp: String, expected: String) -> io::Result<()> {
     }
 }
 
-/// Loop for sending and receiving radio data
-/// Uses the Token Bucket algorithm to limit the transmission slot so
-/// we can ensure we have a healthy amount of time to receive
-pub fn radioloop(mut radio: LoStik) {
-    let duration = Duration::from_millis(radio.opt.txslot.clone());
-    let mut limiter = DirectRateLimiter::<LeakyBucket>::new(nonzero!(3u32), duration);
-
-    // flag if radio is transmitting or not
-    radio.rxstart();
-    let mut isrx = true;
-    let mut extratx: Option<Vec<u8>> = None;
-
-    info!("LoStik radio started");
-
-    // check if we're allowed to transmit
-    // if yes, put in transmit mode and send frames, if any
-    // strategy is to always transmit within allowed rate limit
-    // otherwise we ensure the radio is in receiving mode
-    loop {
-        // no extra data from last loop, let's pull from queue
-        if extratx.is_none() {
-            let next = radio.txreader.try_recv();
-
-            // nothing to transmit, put in receiving mode
-            if next.is_err() {
-                if !isrx {
-                    radio.rxstart();
-                    isrx = true;
-                }
-            }
-            // we have something to transmit, stop receiving and send
-            if limiter.check().is_ok() && next.is_ok() {
-                debug!("Something to transmit");
-                if isrx {
-                    radio.rxstop(); // we're okay to transmit, stop receiver
-                    isrx = false;
-                }
-                let send = next.clone();
-                radio.tx(&send.unwrap()); // grab the next frame and transmit
-
-                // keep transmitting until rate limited
-                while limiter.check().is_ok() {
-                    let next = radio.txreader.try_recv();
-                    if next.is_ok() {
-                        let send = next.clone();
-                        radio.tx(&send.unwrap());
-                    }
-                }
-
-                radio.rxstart(); // TODO not sure why but something blocks thread, so start right away
-                isrx = true;
-            }
-            // we've been rate limited, save to next loop
-            if limiter.check().is_err() && next.is_ok() {
-                debug!("Rate limiting transmission");
-                if next.is_ok() { // we were rate limited, save the extra frame
-                    extratx = Some(next.unwrap());
-                }
-                if !isrx {
-                    radio.rxstart(); // we're okay to receive again
-                    isrx = true;
-                }
-            }
-            // rate limited but nothing to send, start receiver
-            else {
-                if !isrx {
-                    radio.rxstart(); // we're okay to receive again
-                    isrx = true;
-                }
-            }
-        }
-        // we have extra data to transmit, check rate limiter
-        else {
-            if limiter.check().is_ok() {
-                debug!("Transmitting rate limited packet");
-                if isrx {
-                    radio.rxstop(); // we're okay to transmit, stop receiver
-                    isrx = false;
-                }
-                radio.tx(&extratx.unwrap());
-                extratx = None;
-            }
-        }
-        // check serial buffer for incoming radio packets
-        if isrx {
-            match radio.readerlinesrx.try_recv() {
-                Ok(msg) => {
-                    radio.onrx(msg);
-                    radio.rxstart();
-                },
-                _ => continue
-            }
-        }
-    }
-}
-
 impl LoStik {
     pub fn new(opt: Settings) -> LoStik {
+        let ser = SerialIO::new(opt.radioport.clone()).expect("Failed to initialize serial port");
+        LoStik::with_serial(opt, ser)
+    }
+
+    /// Drive a radio over an already open serial connection
+    pub fn with_serial(opt: Settings, ser: SerialIO) -> LoStik {
         // set up channels for serial command IO
         let (readerlinestx, readerlinesrx) = crossbeam_channel::unbounded();
-        // set up channels for radio packet IO
+        // set up channel for decoded radio packets
         let (rxsender, rxreader) = crossbeam_channel::unbounded();
-        let (txsender, txreader) = crossbeam_channel::unbounded();
 
-        let ser = SerialIO::new(opt.radioport.clone()).expect("Failed to initialize serial port");
         let ser2 = ser.clone();
         thread::spawn(move || serialloop(ser2, readerlinestx).expect("Serial IO crashed"));
 
@@ -177,21 +80,74 @@ impl LoStik {
             ser,
             readerlinesrx,
             rxsender,
-            rxreader,
-            txsender,
-            txreader
+            rxreader
         };
     }
 
-    pub fn run(&self) -> (Receiver<Vec<u8>>, Sender<Vec<u8>>) {
-        let ls2 = self.clone();
-        thread::spawn(move || radioloop(ls2));
+    fn oninit(&mut self) -> io::Result<()> {
+        let line = self.readerlinesrx.recv().unwrap();
+        if line == "invalid_param" {
+            Err(mkerror("Bad response from radio during initialization"))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// decode a received frame, reading its signal quality if
+    /// the radio can still be asked for it
+    fn onrx(&mut self, msg: String, measure: bool) -> io::Result<()> {
+        if msg.starts_with("radio_rx ") {
+            if let Ok(decoded) = hex::decode(&msg.as_bytes()[10..]) {
+                trace!("DECODED: {}", format_escape_default(&decoded));
+                let metrics = if measure { self.quality()? } else { LinkMetrics::default() };
+                self.rxsender.send((decoded, metrics)).unwrap();
+            } else {
+                return Err(mkerror("Error with hex decoding"));
+            }
+        }
+        // Might get radio_err here.  That's harmless.
+        Ok(())
+    }
+
+    /// ask the radio for the signal quality of the last received frame,
+    /// older firmware doesn't know `radio get rssi` and answers invalid_param
+    fn quality(&mut self) -> io::Result<LinkMetrics> {
+        self.ser.writeln(String::from("radio get snr"))?;
+        let snr = self.readerlinesrx.recv().unwrap().parse::<i8>().ok();
+        self.ser.writeln(String::from("radio get rssi"))?;
+        let rssi = self.readerlinesrx.recv().unwrap().parse::<i16>().ok();
+        trace!("Frame received with snr {:?} rssi {:?}", &snr, &rssi);
+        Ok(LinkMetrics { rssi, snr })
+    }
 
-        return (self.rxreader.clone(), self.txsender.clone());
+    /// turn on the red LED light
+    fn redledon(&mut self) {
+        self.ser.writeln(String::from("sys set pindig GPIO10 1"));
+        self.readerlinesrx.recv();
     }
 
+    /// turn off the red LED light
+    fn redledoff(&mut self) {
+        self.ser.writeln(String::from("sys set pindig GPIO10 0"));
+        self.readerlinesrx.recv();
+    }
+
+    /// turn on the blue LED light
+    fn blueledon(&mut self) {
+        self.ser.writeln(String::from("sys set pindig GPIO11 1"));
+        self.readerlinesrx.recv();
+    }
+
+    /// turn off the blue LED light
+    fn blueledoff(&mut self) {
+        self.ser.writeln(String::from("sys set pindig GPIO11 0"));
+        self.readerlinesrx.recv();
+    }
+}
+
+impl Radio for LoStik {
     /// apply radio settings using init file
-    pub fn init(&mut self, initfile: Option<PathBuf>) -> io::Result<()> {
+    fn init(&mut self, initfile: Option<PathBuf>) -> io::Result<()> {
         // First, send it an invalid command.  Then, consume everything it sends back
         self.ser.writeln(String::from("INVALIDCOMMAND"))?;
 
@@ -238,54 +194,16 @@ impl LoStik {
         Ok(())
     }
 
-    fn oninit(&mut self) -> io::Result<()> {
-        let line = self.readerlinesrx.recv().unwrap();
-        if line == "invalid_param" {
-            Err(mkerror("Bad response from radio during initialization"))
-        } else {
-            Ok(())
+    /// the RN2483/RN2903 accepts up to 255 bytes per transmission
+    fn capabilities(&self) -> RadioCapabilities {
+        RadioCapabilities {
+            maxpayload: 255,
+            rssi: true,
         }
     }
 
-    fn onrx(&mut self, msg: String) -> io::Result<()> {
-        if msg.starts_with("radio_rx ") {
-            if let Ok(decoded) = hex::decode(&msg.as_bytes()[10..]) {
-                trace!("DECODED: {}", format_escape_default(&decoded));
-                self.rxsender.send(decoded).unwrap();
-            } else {
-                return Err(mkerror("Error with hex decoding"));
-            }
-        }
-        // Might get radio_err here.  That's harmless.
-        Ok(())
-    }
-
-    /// turn on the red LED light
-    fn redledon(&mut self) {
-        self.ser.writeln(String::from("sys set pindig GPIO10 1"));
-        self.readerlinesrx.recv();
-    }
-
-    /// turn off the red LED light
-    fn redledoff(&mut self) {
-        self.ser.writeln(String::from("sys set pindig GPIO10 0"));
-        self.readerlinesrx.recv();
-    }
-
-    /// turn on the blue LED light
-    fn blueledon(&mut self) {
-        self.ser.writeln(String::from("sys set pindig GPIO11 1"));
-        self.readerlinesrx.recv();
-    }
-
-    /// turn off the blue LED light
-    fn blueledoff(&mut self) {
-        self.ser.writeln(String::from("sys set pindig GPIO11 0"));
-        self.readerlinesrx.recv();
-    }
-
     /// starts radio receiver
-    pub fn rxstart(&mut self) -> io::Result<()> {
+    fn rxstart(&mut self) -> io::Result<()> {
         // Enter read mode
 
         self.ser.writeln(String::from("radio rx 0"))?;
@@ -301,14 +219,14 @@ impl LoStik {
     }
 
     /// stops radio receiver so can transmit
-    pub fn rxstop(&mut self) -> io::Result<()> {
+    fn rxstop(&mut self) -> io::Result<()> {
         self.ser.writeln(String::from("radio rxstop"))?;
         let checkresp = self.readerlinesrx.recv().unwrap();
         if checkresp.starts_with("radio_rx ") {
             // We had a race.  A packet was coming in.  Decode and deal with it,
             // then look for the 'ok' from rxstop.  We can't try to read the quality in
             // this scenario.
-            self.onrx(checkresp)?;
+            self.onrx(checkresp, false)?;
             self.readerlinesrx.recv().unwrap();  // used to pop this into checkresp, but no need now.
         }
 
@@ -321,7 +239,7 @@ impl LoStik {
 
     /// transmits a frame, do not call this directly
     /// or you could have collisions
-    pub fn tx(&mut self, data: &[u8]) -> io::Result<()> {
+    fn tx(&mut self, data: &[u8]) -> io::Result<()> {
         self.redledon();
         // hex encode and send to radio device for transmission
         let txstr = format!("radio tx {}", hex::encode(data));
@@ -340,4 +258,115 @@ impl LoStik {
         Ok(())
     }
 
-}
\ No newline at end of file
+    /// check the serial buffer for incoming radio packets
+    fn rxpoll(&mut self) -> io::Result<bool> {
+        match self.readerlinesrx.try_recv() {
+            Ok(msg) => {
+                self.onrx(msg, true)?;
+                Ok(true)
+            },
+            _ => Ok(false)
+        }
+    }
+
+    fn recv(&mut self) -> Option<RadioFrame> {
+        self.rxreader.try_recv().ok()
+    }
+}
+
+#[cfg(test)]
+use crate::hardware::rn2xx3::Rn2xx3;
+
+#[cfg(test)]
+fn lostik_emulated() -> (LoStik, Rn2xx3) {
+    let (emulator, ser) = Rn2xx3::spawn();
+    let opt = Settings::new().expect("Error loading settings");
+    (LoStik::with_serial(opt, ser), emulator)
+}
+
+#[cfg(test)]
+fn lostik_poll(ls: &mut LoStik) -> Option<Vec<u8>> {
+    for _ in 0..100 {
+        if ls.rxpoll().unwrap() {
+            return ls.recv().map(|(data, _)| data);
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    None
+}
+
+#[test]
+fn lostik_init() {
+    let (mut ls, emulator) = lostik_emulated();
+    ls.init(None).expect("Radio failed to initialize");
+    let commands = emulator.commands();
+    assert_eq!(commands[0], "INVALIDCOMMAND");
+    assert!(commands.contains(&String::from("radio set sf sf12")));
+
+    // a bad setting in the init file fails initialization
+    let initfile = std::env::temp_dir().join("loramesh-lostik-init.txt");
+    fs::write(&initfile, "radio set sf sf13\n").unwrap();
+    assert!(ls.init(Some(initfile.clone())).is_err());
+    fs::remove_file(initfile).unwrap();
+}
+
+#[test]
+fn lostik_rx() {
+    let (mut ls, emulator) = lostik_emulated();
+    ls.rxstart().unwrap();
+    assert!(emulator.is_receiving());
+
+    emulator.receive(&[0xcau8, 0xfe]);
+    assert_eq!(lostik_poll(&mut ls), Some(vec![0xcau8, 0xfe]));
+
+    // frames carry the signal quality the module reported
+    ls.rxstart().unwrap();
+    emulator.quality(-12, -118);
+    emulator.receive(&[0x01u8]);
+    for _ in 0..100 {
+        if ls.rxpoll().unwrap() { break; }
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(ls.recv(), Some((vec![0x01u8], LinkMetrics { rssi: Some(-118), snr: Some(-12) })));
+
+    // the receive window closes after a frame, the radio loop restarts it
+    emulator.spurious_err();
+    ls.rxstart().unwrap();
+    assert!(emulator.is_receiving());
+
+    // watchdog timeouts end the window without a frame
+    emulator.timeout();
+    assert_eq!(lostik_poll(&mut ls), None);
+}
+
+#[test]
+fn lostik_rxstop_race() {
+    let (mut ls, emulator) = lostik_emulated();
+    ls.rxstart().unwrap();
+
+    // a frame sneaks in right as we stop receiving
+    emulator.race_rxstop(&[1u8, 2, 3]);
+    ls.rxstop().unwrap();
+    assert_eq!(ls.recv(), Some((vec![1u8, 2, 3], LinkMetrics::default())));
+    assert!(!emulator.is_receiving());
+
+    // the driver stayed in step with the module's responses
+    ls.tx(&[4u8]).unwrap();
+    ls.rxstart().unwrap();
+    assert!(emulator.is_receiving());
+}
+
+#[test]
+fn lostik_tx() {
+    let (mut ls, emulator) = lostik_emulated();
+    ls.tx(&[0x45u8, 0x00, 0x10]).unwrap();
+
+    // a lingering radio_err before the ok is tolerated
+    emulator.spurious_err();
+    ls.tx(&[0x01u8]).unwrap();
+    assert_eq!(emulator.transmitted(), vec![vec![0x45u8, 0x00, 0x10], vec![0x01u8]]);
+
+    // transmitting while receiving is refused by the module
+    ls.rxstart().unwrap();
+    assert!(ls.tx(&[0x02u8]).is_err());
+}
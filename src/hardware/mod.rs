@@ -1,4 +1,13 @@
 pub(crate) mod serial;
 
-pub(crate) mod lostik;
-pub(crate) use lostik::LoStik;
\ No newline at end of file
+pub mod radio;
+pub use radio::{Radio, RadioCapabilities, LinkMetrics};
+
+pub mod lostik;
+pub use lostik::LoStik;
+
+// simulated radios and tunnels for running the stack without hardware
+pub mod sim;
+
+#[cfg(test)]
+pub(crate) mod rn2xx3;
\ No newline at end of file
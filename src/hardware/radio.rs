@@ -0,0 +1,252 @@
+use log::*;
+use std::io;
+use std::thread;
+use std::time::Duration;
+use std::path::PathBuf;
+use crossbeam_channel::{Sender, Receiver};
+use ratelimit_meter::{DirectRateLimiter, LeakyBucket};
+use crate::stack::scheduler::{TdmaScheduler, FRAMES_PER_SLOT};
+use crate::stack::arq::LinkArq;
+use crate::stack::cipher::FrameCipher;
+use crate::stack::frame::{check_version, more_chunks, mark_slot_exceeded, PROTOCOL_VERSION};
+use crate::error::FrameError;
+use std::collections::HashSet;
+use std::num::NonZeroU32;
+
+/// Describes what a radio device is able to do
+#[derive(Clone, Debug)]
+pub struct RadioCapabilities {
+    /// Largest payload, in bytes, the radio can send in a single transmission
+    pub maxpayload: usize,
+    /// Whether the radio can report signal strength of received frames
+    pub rssi: bool,
+}
+
+/// SNR, in dB, at which LoRa frames are still decoded reliably
+const SNR_GOOD: f32 = 0.0;
+/// SNR, in dB, near the demodulation floor of the slowest spreading factor
+const SNR_FLOOR: f32 = -20.0;
+/// RSSI, in dBm, at which frames are still decoded reliably
+const RSSI_GOOD: f32 = -100.0;
+/// RSSI, in dBm, near the receiver sensitivity
+const RSSI_FLOOR: f32 = -135.0;
+
+/// Signal quality measured by the radio while receiving a frame
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct LinkMetrics {
+    /// received signal strength, in dBm
+    pub rssi: Option<i16>,
+    /// signal to noise ratio, in dB
+    pub snr: Option<i8>,
+}
+
+impl LinkMetrics {
+    /// Expected number of transmissions (ETX) needed for a frame to cross the link
+    /* Estimated from how far above the demodulation floor the frame was
+    received, preferring SNR over RSSI when the radio reports both. Links
+    without measurements are assumed perfect, costing a single transmission. */
+    pub fn etx(&self) -> f32 {
+        let delivery = match (self.snr, self.rssi) {
+            (Some(snr), _) => (snr as f32 - SNR_FLOOR) / (SNR_GOOD - SNR_FLOOR),
+            (None, Some(rssi)) => (rssi as f32 - RSSI_FLOOR) / (RSSI_GOOD - RSSI_FLOOR),
+            (None, None) => 1.0,
+        };
+        1.0 / delivery.clamp(0.1, 1.0)
+    }
+}
+
+/// A received frame with the signal quality it was received at
+pub type RadioFrame = (Vec<u8>, LinkMetrics);
+
+/// A half-duplex radio modem that can drive the mesh
+/* Implementations only need to provide the primitive operations of the
+device, the shared `radioloop` takes care of switching between receiving
+and transmitting, of transmit slots, of acknowledgements, of encryption
+and of rate limiting transmissions. */
+pub trait Radio: Clone + Send + 'static {
+    /// apply radio settings, optionally using an init file
+    fn init(&mut self, initfile: Option<PathBuf>) -> io::Result<()>;
+
+    /// report what this radio supports
+    fn capabilities(&self) -> RadioCapabilities;
+
+    /// starts radio receiver
+    fn rxstart(&mut self) -> io::Result<()>;
+
+    /// stops radio receiver so can transmit
+    fn rxstop(&mut self) -> io::Result<()>;
+
+    /// transmits a frame, do not call this directly
+    /// or you could have collisions
+    fn tx(&mut self, data: &[u8]) -> io::Result<()>;
+
+    /// check the device for radio events while receiving, returns
+    /// true if an event ended the receive window and the receiver
+    /// must be restarted
+    fn rxpoll(&mut self) -> io::Result<bool>;
+
+    /// pop the next frame received by the radio, if any, along
+    /// with the signal quality it was received at
+    fn recv(&mut self) -> Option<RadioFrame>;
+
+    /// Start the radio loop in its own thread, returns the channel
+    /// of received frames and the channel for frames to transmit
+    fn run(&self, txslot: Duration, scheduler: TdmaScheduler, arq: LinkArq, cipher: FrameCipher) -> (Receiver<RadioFrame>, Sender<Vec<u8>>) {
+        let (rxsender, rxreader) = crossbeam_channel::unbounded();
+        let (txsender, txreader) = crossbeam_channel::unbounded();
+
+        let radio = self.clone();
+        thread::spawn(move || radioloop(radio, txslot, scheduler, arq, cipher, rxsender, txreader));
+
+        (rxreader, txsender)
+    }
+}
+
+/// Loop for sending and receiving radio data
+/// Transmissions only happen inside this node's TDMA slot, and
+/// uses the Token Bucket algorithm to limit the transmission slot so
+/// we can ensure we have a healthy amount of time to receive
+pub fn radioloop<R: Radio>(mut radio: R, txslot: Duration, scheduler: TdmaScheduler, arq: LinkArq, cipher: FrameCipher, rxsender: Sender<RadioFrame>, txreader: Receiver<Vec<u8>>) {
+    let mut limiter = DirectRateLimiter::<LeakyBucket>::new(NonZeroU32::new(FRAMES_PER_SLOT).unwrap(), txslot);
+
+    // flag if radio is transmitting or not
+    if let Err(e) = radio.rxstart() {
+        error!("Failed to start radio receiver: {}", e);
+    }
+    let mut isrx = true;
+    let mut nexttx: Option<Vec<u8>> = None;
+    // the rest of a chunked frame goes before anything else
+    let mut midframe = false;
+    // the rate limiter already allowed the next transmission
+    let mut granted = false;
+    // protocol versions heard that we don't speak, each is warned about once
+    let mut foreign: HashSet<u8> = HashSet::new();
+
+    if scheduler.enabled() {
+        info!("Radio loop started, transmitting in TDMA slot {}", scheduler.slot());
+    } else {
+        info!("Radio loop started");
+    }
+
+    // check if we're allowed to transmit
+    // if yes, put in transmit mode and send frames, if any
+    // strategy is to always transmit within our slot and allowed rate limit
+    // otherwise we ensure the radio is in receiving mode
+    loop {
+        // pull from queue unless a frame is still waiting for its turn
+        if nexttx.is_none() {
+            nexttx = next_frame(&arq, &txreader, midframe);
+        }
+
+        // we have something to transmit, stop receiving and send
+        while nexttx.is_some() && scheduler.can_transmit() {
+            if !granted && limiter.check().is_err() {
+                trace!("Rate limiting transmission");
+                break;
+            }
+            if isrx {
+                txstart(&mut radio);
+                isrx = false;
+            }
+            let mut data = nexttx.take().unwrap();
+            // tell the receiver if the rest of the frame has to wait for our next slot
+            midframe = more_chunks(&data);
+            granted = midframe && limiter.check().is_ok();
+            if midframe && !granted {
+                trace!("Transmit slot exceeded, resuming frame in the next slot");
+                mark_slot_exceeded(&mut data);
+            }
+            transmit(&mut radio, &cipher, &data);
+            arq.sent(&data);
+            nexttx = next_frame(&arq, &txreader, midframe);
+        }
+
+        // out of slot, rate limited or nothing to send, start receiver
+        if !isrx {
+            rxrestart(&mut radio);
+            isrx = true;
+        }
+
+        // check the radio for incoming packets
+        match radio.rxpoll() {
+            Ok(true) => rxrestart(&mut radio),
+            Ok(false) => {},
+            Err(e) => error!("Error receiving from radio: {}", e),
+        }
+        while let Some((data, metrics)) = radio.recv() {
+            // the rest of the header can't be trusted to mean what we think
+            if let Err(FrameError::UnsupportedVersion(version)) = check_version(&data) {
+                if foreign.insert(version) {
+                    warn!("Dropping frames of protocol version {}, this node speaks version {}", &version, &PROTOCOL_VERSION);
+                } else {
+                    trace!("Dropping radio frame of protocol version {}", &version);
+                }
+                continue;
+            }
+            let data = match cipher.open(&data) {
+                Err(e) => {
+                    debug!("Dropping radio frame: {}", e);
+                    continue;
+                },
+                Ok(data) => data,
+            };
+            if !arq.received(&data) { continue; }
+            // nobody left to hand frames to, the node stopped
+            if rxsender.send((data, metrics)).is_err() {
+                info!("Radio loop stopped");
+                return;
+            }
+        }
+    }
+}
+
+/// Next frame to transmit: the rest of a chunked frame being sent, then
+/// acknowledgements and retransmissions, then new frames
+fn next_frame(arq: &LinkArq, txreader: &Receiver<Vec<u8>>, midframe: bool) -> Option<Vec<u8>> {
+    if midframe {
+        if let Ok(data) = txreader.try_recv() {
+            return Some(data);
+        }
+    }
+    arq.next().or_else(|| txreader.try_recv().ok())
+}
+
+/// stop the receiver so the radio can transmit
+fn txstart<R: Radio>(radio: &mut R) {
+    if let Err(e) = radio.rxstop() {
+        error!("Failed to stop radio receiver: {}", e);
+    }
+}
+
+/// put the radio back in receiving mode
+fn rxrestart<R: Radio>(radio: &mut R) {
+    if let Err(e) = radio.rxstart() {
+        error!("Failed to start radio receiver: {}", e);
+    }
+}
+
+/// seal and transmit a single frame, logging failures
+fn transmit<R: Radio>(radio: &mut R, cipher: &FrameCipher, data: &[u8]) {
+    match cipher.seal(data) {
+        Err(e) => error!("Failed to seal frame: {}", e),
+        Ok(sealed) => {
+            if let Err(e) = radio.tx(&sealed) {
+                error!("Failed to transmit frame: {}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn radio_link_etx() {
+    assert_eq!(LinkMetrics::default().etx(), 1.0);
+    assert_eq!(LinkMetrics { rssi: Some(-62), snr: Some(7) }.etx(), 1.0);
+
+    // half way down to the floor, every other frame gets through
+    assert_eq!(LinkMetrics { rssi: None, snr: Some(-10) }.etx(), 2.0);
+
+    // SNR wins over RSSI, and hopeless links are capped
+    assert_eq!(LinkMetrics { rssi: Some(-62), snr: Some(-30) }.etx(), 10.0);
+    assert!(LinkMetrics { rssi: Some(-130), snr: None }.etx() > 5.0);
+}
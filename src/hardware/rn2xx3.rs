@@ -0,0 +1,285 @@
+use log::*;
+use std::io;
+use std::io::{Read, Write};
+use std::thread;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::collections::VecDeque;
+use crossbeam_channel::{Sender, Receiver};
+use hex;
+use crate::hardware::serial::SerialIO;
+
+/// Reading end of an in-memory serial line
+struct PipeReader {
+    rx: Receiver<Vec<u8>>,
+    buf: VecDeque<u8>,
+}
+
+impl Read for PipeReader {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        if self.buf.is_empty() {
+            match self.rx.recv() {
+                Ok(data) => self.buf.extend(data),
+                Err(_) => return Ok(0), // EOF
+            }
+        }
+        let size = out.len().min(self.buf.len());
+        for (i, byte) in self.buf.drain(..size).enumerate() {
+            out[i] = byte;
+        }
+        Ok(size)
+    }
+}
+
+/// Writing end of an in-memory serial line
+struct PipeWriter {
+    tx: Sender<Vec<u8>>,
+}
+
+impl Write for PipeWriter {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.tx.send(Vec::from(data)).map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum RadioMode {
+    Idle,
+    Receiving,
+}
+
+struct EmulatorState {
+    mode: RadioMode,
+    /// lines sent to the driver
+    out: Sender<Vec<u8>>,
+    /// frames heard while not receiving, delivered on next `radio rx`
+    pending: VecDeque<Vec<u8>>,
+    /// frame that arrives right as the driver sends `radio rxstop`
+    race: Option<Vec<u8>>,
+    /// reply `radio_err` once before the next rx or tx acknowledgement
+    spuriouserr: bool,
+    /// signal quality of the last received frame
+    snr: i8,
+    rssi: i16,
+    /// every command received, in order
+    commands: Vec<String>,
+    /// every frame transmitted, in order
+    transmitted: Vec<Vec<u8>>,
+}
+
+impl EmulatorState {
+    fn reply(&mut self, line: &str) {
+        trace!("RN2xx3 emulator: {}", line);
+        let _ = self.out.send(format!("{}\r\n", line).into_bytes());
+    }
+
+    fn rx(&mut self, data: &[u8]) {
+        let line = format!("radio_rx  {}", hex::encode_upper(data));
+        self.reply(&line);
+    }
+
+    fn ack(&mut self) {
+        if self.spuriouserr {
+            self.spuriouserr = false;
+            self.reply("radio_err");
+        }
+        self.reply("ok");
+    }
+
+    /// answer a single command the way the module does
+    fn command(&mut self, line: &str) {
+        self.commands.push(String::from(line));
+        let args: Vec<&str> = line.split_whitespace().collect();
+        match args.as_slice() {
+            ["sys", "get", "ver"] => self.reply("RN2483 1.0.5 Oct 31 2018 15:06:52"),
+            ["sys", "get", "hweui"] => self.reply("0004A30B001A2B3C"),
+            ["sys", "set", "pindig", pin, state] => {
+                if pin.starts_with("GPIO") && (*state == "0" || *state == "1") {
+                    self.reply("ok");
+                } else {
+                    self.reply("invalid_param");
+                }
+            },
+            ["mac", "reset"] => self.reply("ok"),
+            ["mac", "pause"] => self.reply("4294967245"),
+            ["radio", "get", "snr"] => {
+                let snr = self.snr.to_string();
+                self.reply(&snr);
+            },
+            ["radio", "get", "rssi"] => {
+                let rssi = self.rssi.to_string();
+                self.reply(&rssi);
+            },
+            ["radio", "get", param] => {
+                let value = match *param {
+                    "mod" => "lora",
+                    "freq" => "868100000",
+                    "pwr" => "1",
+                    "sf" => "sf12",
+                    "bw" => "125",
+                    "cr" => "4/5",
+                    "wdt" => "15000",
+                    _ => "invalid_param",
+                };
+                self.reply(value);
+            },
+            ["radio", "set", param, value] => {
+                let valid = match *param {
+                    "pwr" => value.parse::<i8>().map(|p| (-3..=20).contains(&p)).unwrap_or(false),
+                    "sf" => ["sf7", "sf8", "sf9", "sf10", "sf11", "sf12"].contains(value),
+                    "bw" => ["125", "250", "500"].contains(value),
+                    "cr" => ["4/5", "4/6", "4/7", "4/8"].contains(value),
+                    "wdt" | "freq" => value.parse::<u32>().is_ok(),
+                    "mod" => ["lora", "fsk"].contains(value),
+                    _ => false,
+                };
+                self.reply(if valid { "ok" } else { "invalid_param" });
+            },
+            ["radio", "rx", "0"] => {
+                if self.mode != RadioMode::Idle {
+                    self.reply("busy");
+                    return;
+                }
+                self.ack();
+                match self.pending.pop_front() {
+                    Some(data) => self.rx(&data),
+                    None => self.mode = RadioMode::Receiving,
+                }
+            },
+            ["radio", "rxstop"] => {
+                if let Some(data) = self.race.take() {
+                    self.rx(&data);
+                }
+                self.mode = RadioMode::Idle;
+                self.reply("ok");
+            },
+            ["radio", "tx", data] => {
+                if self.mode != RadioMode::Idle {
+                    self.reply("busy");
+                    return;
+                }
+                match hex::decode(data) {
+                    Err(_) => self.reply("invalid_param"),
+                    Ok(bytes) => {
+                        if bytes.len() > 255 {
+                            self.reply("invalid_param");
+                            return;
+                        }
+                        self.transmitted.push(bytes);
+                        self.ack();
+                        self.reply("radio_tx_ok");
+                    }
+                }
+            },
+            _ => self.reply("invalid_param"),
+        }
+    }
+}
+
+/// Emulator of a Microchip RN2483/RN2903 module on the other end of a serial line
+/* Replies to the subset of the command set used by the LoStik driver,
+including the quirks the driver copes with: a stray `radio_err` before
+an acknowledgement, and a frame arriving just as the receiver stops. */
+#[derive(Clone)]
+pub struct Rn2xx3 {
+    state: Arc<Mutex<EmulatorState>>,
+}
+
+impl Rn2xx3 {
+    /// Start an emulated module, returns it along with the serial
+    /// connection a driver should use to talk to it
+    pub fn spawn() -> (Rn2xx3, SerialIO) {
+        let (drivertx, emulatorrx) = crossbeam_channel::unbounded::<Vec<u8>>();
+        let (emulatortx, driverrx) = crossbeam_channel::unbounded();
+
+        let emulator = Rn2xx3 {
+            state: Arc::new(Mutex::new(EmulatorState {
+                mode: RadioMode::Idle,
+                out: emulatortx,
+                pending: VecDeque::new(),
+                race: None,
+                spuriouserr: false,
+                snr: 7,
+                rssi: -62,
+                commands: Vec::new(),
+                transmitted: Vec::new(),
+            }))
+        };
+
+        let state = Arc::clone(&emulator.state);
+        thread::spawn(move || {
+            let mut line = Vec::new();
+            while let Ok(data) = emulatorrx.recv() {
+                for byte in data {
+                    if byte == b'\n' {
+                        let command = String::from_utf8_lossy(&line).trim().to_string();
+                        state.lock().unwrap().command(&command);
+                        line.clear();
+                    } else {
+                        line.push(byte);
+                    }
+                }
+            }
+        });
+
+        let ser = SerialIO::from_streams(
+            Box::new(PipeReader { rx: driverrx, buf: VecDeque::new() }),
+            Box::new(PipeWriter { tx: drivertx }),
+            PathBuf::from("rn2xx3-emulator"));
+        (emulator, ser)
+    }
+
+    /// A frame arrives over the air
+    pub fn receive(&self, data: &[u8]) {
+        let mut state = self.state.lock().unwrap();
+        if state.mode == RadioMode::Receiving {
+            state.mode = RadioMode::Idle;
+            state.rx(data);
+        } else {
+            state.pending.push_back(Vec::from(data));
+        }
+    }
+
+    /// The receive window times out without a frame
+    pub fn timeout(&self) {
+        let mut state = self.state.lock().unwrap();
+        if state.mode == RadioMode::Receiving {
+            state.mode = RadioMode::Idle;
+            state.reply("radio_err");
+        }
+    }
+
+    /// A frame arrives right as the receiver is stopped
+    pub fn race_rxstop(&self, data: &[u8]) {
+        self.state.lock().unwrap().race = Some(Vec::from(data));
+    }
+
+    /// Signal quality reported for the frames that follow
+    pub fn quality(&self, snr: i8, rssi: i16) {
+        let mut state = self.state.lock().unwrap();
+        state.snr = snr;
+        state.rssi = rssi;
+    }
+
+    /// Reply `radio_err` before the next rx or tx acknowledgement
+    pub fn spurious_err(&self) {
+        self.state.lock().unwrap().spuriouserr = true;
+    }
+
+    pub fn is_receiving(&self) -> bool {
+        self.state.lock().unwrap().mode == RadioMode::Receiving
+    }
+
+    pub fn commands(&self) -> Vec<String> {
+        self.state.lock().unwrap().commands.clone()
+    }
+
+    pub fn transmitted(&self) -> Vec<Vec<u8>> {
+        self.state.lock().unwrap().transmitted.clone()
+    }
+}
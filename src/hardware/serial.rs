@@ -1,6 +1,6 @@
 use std::io;
 use serialport::prelude::*;
-use std::io::{BufReader, BufRead, Write};
+use std::io::{BufReader, BufRead, Read, Write};
 use log::*;
 use std::sync::{Arc, Mutex};
 use std::time::Duration;
@@ -9,8 +9,8 @@ use std::path::PathBuf;
 #[derive(Clone)]
 pub struct SerialIO {
     // BufReader can't be cloned.  Sigh.
-    pub br: Arc<Mutex<BufReader<Box<dyn SerialPort>>>>,
-    pub swrite: Arc<Mutex<Box<dyn SerialPort>>>,
+    pub br: Arc<Mutex<BufReader<Box<dyn Read + Send>>>>,
+    pub swrite: Arc<Mutex<Box<dyn Write + Send>>>,
     pub portname: PathBuf
 }
 
@@ -29,9 +29,14 @@ impl SerialIO {
         let readport = serialport::open_with_settings(&portname, &settings)?;
         let writeport = readport.try_clone()?;
         
-        Ok(SerialIO {br: Arc::new(Mutex::new(BufReader::new(readport))),
-                    swrite: Arc::new(Mutex::new(writeport)),
-                    portname: portname})
+        Ok(SerialIO::from_streams(Box::new(readport), Box::new(writeport), portname))
+    }
+
+    /// Wrap an already open pair of streams, such as an emulated device
+    pub fn from_streams(reader: Box<dyn Read + Send>, writer: Box<dyn Write + Send>, portname: PathBuf) -> SerialIO {
+        SerialIO {br: Arc::new(Mutex::new(BufReader::new(reader))),
+                  swrite: Arc::new(Mutex::new(writer)),
+                  portname}
     }
 
     /// Read a line from the port.  Return it with EOL characters removed.
@@ -0,0 +1,612 @@
+use log::*;
+use std::io;
+use std::thread;
+use std::time::{Duration, Instant};
+use std::path::PathBuf;
+use std::collections::{HashMap, HashSet};
+use std::net::Ipv4Addr;
+use std::sync::{Arc, Mutex};
+use crossbeam_channel::{Receiver, Sender};
+use packet::ip::v4::Packet;
+use rand::{thread_rng, Rng};
+use crate::hardware::radio::{Radio, RadioCapabilities, RadioFrame, LinkMetrics};
+use crate::stack::{NodeId, Tunnel};
+
+/// Characteristics of a one-way link between two simulated radios
+#[derive(Clone, Debug)]
+pub struct LinkProfile {
+    /// probability, between 0 and 1, that a frame on this link is lost
+    pub loss: f64,
+    /// delay before the receiver starts hearing a transmission
+    pub latency: Duration,
+    /// signal to noise ratio, in dB, reported for frames on this link
+    pub snr: i8,
+}
+
+impl LinkProfile {
+    /// a perfect link, every frame arrives without delay
+    pub fn reliable() -> Self {
+        LinkProfile{ loss: 0f64, latency: Duration::from_millis(0), snr: 10 }
+    }
+
+    /// a link that drops frames with the given probability, its
+    /// SNR drops towards the demodulation floor to match
+    pub fn lossy(loss: f64) -> Self {
+        LinkProfile{ loss, latency: Duration::from_millis(0), snr: (-20f64 * loss) as i8 }
+    }
+}
+
+/// Time a transmission occupies the air, a fixed preamble plus a cost per byte
+#[derive(Clone, Debug)]
+pub struct AirtimeModel {
+    pub preamble: Duration,
+    pub perbyte: Duration,
+}
+
+impl AirtimeModel {
+    pub fn new(preamble: Duration, perbyte: Duration) -> Self {
+        AirtimeModel{ preamble, perbyte }
+    }
+
+    /// time on air of a frame of the given size
+    pub fn airtime(&self, len: usize) -> Duration {
+        self.preamble + self.perbyte * len as u32
+    }
+}
+
+/// A frame in flight towards a listening radio
+struct Reception {
+    data: Vec<u8>,
+    metrics: LinkMetrics,
+    start: Instant,
+    end: Instant,
+    collided: bool,
+}
+
+impl Reception {
+    fn overlaps(&self, start: &Instant, end: &Instant) -> bool {
+        self.start < *end && *start < self.end
+    }
+}
+
+struct AirState {
+    airtime: AirtimeModel,
+    /// one-way links, keyed by (transmitter, receiver)
+    links: HashMap<(NodeId, NodeId), LinkProfile>,
+    /// radios currently in receive mode
+    listening: HashMap<NodeId, bool>,
+    /// end of the current transmission of each radio
+    transmitting: HashMap<NodeId, Instant>,
+    /// frames being heard by each radio
+    receptions: HashMap<NodeId, Vec<Reception>>,
+    /// frames each radio has put on the air
+    transmissions: HashMap<NodeId, usize>,
+}
+
+/// A shared virtual "air" that simulated radios transmit into
+/* Every transmission is heard by the radios that have a link from the
+transmitter, after the link latency and for the airtime of the frame.
+Receptions that overlap at the same receiver collide and are lost, as
+are frames arriving while the receiver transmits or isn't listening. */
+#[derive(Clone)]
+pub struct VirtualAir {
+    state: Arc<Mutex<AirState>>,
+}
+
+impl VirtualAir {
+    pub fn new(airtime: AirtimeModel) -> Self {
+        VirtualAir {
+            state: Arc::new(Mutex::new(AirState {
+                airtime,
+                links: HashMap::new(),
+                listening: HashMap::new(),
+                transmitting: HashMap::new(),
+                receptions: HashMap::new(),
+                transmissions: HashMap::new(),
+            }))
+        }
+    }
+
+    /// Attach a new radio to the air with the given node ID
+    pub fn attach(&self, nodeid: NodeId) -> VirtualRadio {
+        let mut state = self.state.lock().unwrap();
+        state.listening.insert(nodeid, false);
+        state.receptions.insert(nodeid, Vec::new());
+        drop(state);
+
+        let (rxsender, rxreader) = crossbeam_channel::unbounded();
+        VirtualRadio {
+            nodeid,
+            air: self.clone(),
+            capabilities: RadioCapabilities { maxpayload: 255, rssi: true },
+            rxsender,
+            rxreader,
+        }
+    }
+
+    /// Let `dest` hear transmissions from `src`
+    pub fn link(&self, src: NodeId, dest: NodeId, profile: LinkProfile) {
+        self.state.lock().unwrap().links.insert((src, dest), profile);
+    }
+
+    /// Link two radios in both directions
+    pub fn connect(&self, a: NodeId, b: NodeId, profile: LinkProfile) {
+        self.link(a, b, profile.clone());
+        self.link(b, a, profile);
+    }
+
+    /// Remove the links between two radios in both directions
+    pub fn disconnect(&self, a: NodeId, b: NodeId) {
+        let mut state = self.state.lock().unwrap();
+        state.links.remove(&(a, b));
+        state.links.remove(&(b, a));
+    }
+
+    /// Check if a radio is in receive mode
+    pub fn listening(&self, nodeid: NodeId) -> bool {
+        self.state.lock().unwrap().listening.get(&nodeid).cloned().unwrap_or(false)
+    }
+
+    /// Count the frames a radio has put on the air
+    pub fn transmissions(&self, nodeid: NodeId) -> usize {
+        self.state.lock().unwrap().transmissions.get(&nodeid).cloned().unwrap_or(0)
+    }
+
+    /// Put a frame on the air, returns how long the transmission takes
+    fn transmit(&self, src: NodeId, data: &[u8]) -> Duration {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        let airtime = state.airtime.airtime(data.len());
+        state.transmitting.insert(src, now + airtime);
+        *state.transmissions.entry(src).or_insert(0) += 1;
+
+        // we can't hear anything while transmitting
+        if let Some(receptions) = state.receptions.get_mut(&src) {
+            receptions.iter_mut()
+                .filter(|r| r.overlaps(&now, &(now + airtime)))
+                .for_each(|r| r.collided = true);
+        }
+
+        let mut rng = thread_rng();
+        let links: Vec<(NodeId, LinkProfile)> = state.links.iter()
+            .filter(|((from, _), _)| *from == src)
+            .map(|((_, to), profile)| (*to, profile.clone()))
+            .collect();
+        for (dest, profile) in links {
+            if !state.listening.get(&dest).cloned().unwrap_or(false) {
+                continue;
+            }
+            if rng.gen::<f64>() < profile.loss {
+                trace!("Simulated loss of frame from {} to {}", &src, &dest);
+                continue;
+            }
+            let start = now + profile.latency;
+            let end = start + airtime;
+            let busy = state.transmitting.get(&dest).map(|until| *until > start).unwrap_or(false);
+            let receptions = state.receptions.get_mut(&dest).unwrap();
+            let mut collided = busy;
+            receptions.iter_mut()
+                .filter(|r| r.overlaps(&start, &end))
+                .for_each(|r| {
+                    r.collided = true;
+                    collided = true;
+                });
+            if collided {
+                debug!("Simulated collision at node {}", &dest);
+            }
+            let metrics = LinkMetrics { rssi: None, snr: Some(profile.snr) };
+            receptions.push(Reception { data: Vec::from(data), metrics, start, end, collided });
+        }
+
+        airtime
+    }
+
+    /// Take the frames a radio finished hearing
+    fn receive(&self, nodeid: NodeId) -> Vec<RadioFrame> {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        let receptions = state.receptions.get_mut(&nodeid).unwrap();
+        let (done, pending): (Vec<Reception>, Vec<Reception>) = receptions.drain(..).partition(|r| r.end <= now);
+        *receptions = pending;
+
+        done.into_iter().filter(|r| !r.collided).map(|r| (r.data, r.metrics)).collect()
+    }
+
+    /// Switch a radio between listening and idle, frames
+    /// still in flight are lost when it stops listening
+    fn listen(&self, nodeid: NodeId, listening: bool) {
+        let mut state = self.state.lock().unwrap();
+        state.listening.insert(nodeid, listening);
+        if !listening {
+            let now = Instant::now();
+            state.receptions.get_mut(&nodeid).unwrap().retain(|r| r.end <= now);
+        }
+    }
+}
+
+/// A simulated radio attached to a `VirtualAir`
+#[derive(Clone)]
+pub struct VirtualRadio {
+    nodeid: NodeId,
+    air: VirtualAir,
+    capabilities: RadioCapabilities,
+
+    // channels for received radio packets
+    rxsender: crossbeam_channel::Sender<RadioFrame>,
+    rxreader: crossbeam_channel::Receiver<RadioFrame>,
+}
+
+impl VirtualRadio {
+    /// deliver frames that finished arriving
+    fn onrx(&mut self) -> bool {
+        let frames = self.air.receive(self.nodeid);
+        let received = !frames.is_empty();
+        for received in frames {
+            self.rxsender.send(received).unwrap();
+        }
+        received
+    }
+}
+
+impl Radio for VirtualRadio {
+    fn init(&mut self, _initfile: Option<PathBuf>) -> io::Result<()> {
+        debug!("Virtual radio {} initialized", &self.nodeid);
+        Ok(())
+    }
+
+    fn capabilities(&self) -> RadioCapabilities {
+        self.capabilities.clone()
+    }
+
+    fn rxstart(&mut self) -> io::Result<()> {
+        self.air.listen(self.nodeid, true);
+        Ok(())
+    }
+
+    fn rxstop(&mut self) -> io::Result<()> {
+        // like the real thing, a frame can finish right as we stop
+        self.onrx();
+        self.air.listen(self.nodeid, false);
+        Ok(())
+    }
+
+    fn tx(&mut self, data: &[u8]) -> io::Result<()> {
+        let airtime = self.air.transmit(self.nodeid, data);
+        thread::sleep(airtime);
+        Ok(())
+    }
+
+    /// the simulated receiver stays open after a reception
+    fn rxpoll(&mut self) -> io::Result<bool> {
+        // poll about as often as a real device can be
+        if !self.onrx() {
+            thread::sleep(Duration::from_millis(1));
+        }
+        Ok(false)
+    }
+
+    fn recv(&mut self) -> Option<RadioFrame> {
+        self.rxreader.try_recv().ok()
+    }
+}
+
+/// Addresses and routes installed on a simulated tunnel
+struct TunnelState {
+    addresses: HashSet<Ipv4Addr>,
+    /// routes into the mesh, the address they go via by destination
+    routes: HashMap<Ipv4Addr, Ipv4Addr>,
+}
+
+/// A simulated tunnel interface, standing in for the host's network stack
+/* Packets given to `inject` reach the node as if a local program sent
+them, and the packets the node hands to the host are kept for `delivered`.
+Clones share the interface, so a test keeps one to watch the node that
+owns the other. */
+#[derive(Clone)]
+pub struct VirtualTunnel {
+    tunip: Ipv4Addr,
+    state: Arc<Mutex<TunnelState>>,
+
+    // packets from the host to the mesh
+    inboundsender: Sender<Packet<Vec<u8>>>,
+    inboundreader: Receiver<Packet<Vec<u8>>>,
+    // packets from the mesh to the host
+    outboundsender: Sender<Packet<Vec<u8>>>,
+    outboundreader: Receiver<Packet<Vec<u8>>>,
+}
+
+impl VirtualTunnel {
+    pub fn new(tunip: Ipv4Addr) -> Self {
+        let (inboundsender, inboundreader) = crossbeam_channel::unbounded();
+        let (outboundsender, outboundreader) = crossbeam_channel::unbounded();
+        VirtualTunnel {
+            tunip,
+            state: Arc::new(Mutex::new(TunnelState { addresses: HashSet::new(), routes: HashMap::new() })),
+            inboundsender,
+            inboundreader,
+            outboundsender,
+            outboundreader,
+        }
+    }
+
+    /// Send a packet into the mesh from the host
+    pub fn inject(&self, packet: Packet<Vec<u8>>) {
+        self.inboundsender.send(packet).unwrap();
+    }
+
+    /// Wait for the next packet the mesh delivered to the host
+    pub fn delivered(&self, timeout: Duration) -> Option<Packet<Vec<u8>>> {
+        self.outboundreader.recv_timeout(timeout).ok()
+    }
+
+    /// Addresses currently assigned to the interface
+    pub fn addresses(&self) -> HashSet<Ipv4Addr> {
+        self.state.lock().unwrap().addresses.clone()
+    }
+}
+
+impl Tunnel for VirtualTunnel {
+    fn run(&self) -> Receiver<Packet<Vec<u8>>> {
+        self.inboundreader.clone()
+    }
+
+    fn send(&mut self, packet: Packet<Vec<u8>>) {
+        self.outboundsender.send(packet).unwrap();
+    }
+
+    fn tunip(&self) -> Ipv4Addr {
+        self.tunip
+    }
+
+    fn assignipaddr(&mut self, ipaddr: &Ipv4Addr) {
+        self.state.lock().unwrap().addresses.insert(*ipaddr);
+    }
+
+    fn unassignipaddr(&mut self, ipaddr: &Ipv4Addr) {
+        self.state.lock().unwrap().addresses.remove(ipaddr);
+    }
+
+    fn routeipaddr(&mut self, dest: &Ipv4Addr, via: &Ipv4Addr) {
+        self.state.lock().unwrap().routes.insert(*dest, *via);
+    }
+
+    fn unrouteipaddr(&mut self, dest: &Ipv4Addr) {
+        self.state.lock().unwrap().routes.remove(dest);
+    }
+
+    fn routes(&self) -> HashMap<Ipv4Addr, Ipv4Addr> {
+        self.state.lock().unwrap().routes.clone()
+    }
+}
+
+#[cfg(test)]
+use crate::stack::{Frame, ToFromFrame, IPPacketMessage, MessageType, RouteFailureMessage, Reassembly, recombine_chunks};
+#[cfg(test)]
+use crate::stack::frame::{TransmissionState, PROTOCOL_VERSION};
+#[cfg(test)]
+use crate::stack::scheduler::TdmaScheduler;
+#[cfg(test)]
+use crate::stack::arq::LinkArq;
+#[cfg(test)]
+use crate::stack::cipher::FrameCipher;
+
+#[cfg(test)]
+fn sim_air() -> VirtualAir {
+    VirtualAir::new(AirtimeModel::new(Duration::from_millis(20), Duration::from_micros(100)))
+}
+
+#[cfg(test)]
+fn sim_listen(radio: &mut VirtualRadio) {
+    radio.rxstart().unwrap();
+}
+
+/// wait for a radio loop to start receiving
+#[cfg(test)]
+fn sim_started(air: &VirtualAir, nodeid: NodeId) {
+    for _ in 0..100 {
+        if air.listening(nodeid) { return; }
+        thread::sleep(Duration::from_millis(10));
+    }
+    panic!("Radio loop of node {} never started", &nodeid);
+}
+
+#[cfg(test)]
+fn sim_wait(radio: &mut VirtualRadio) -> Vec<Vec<u8>> {
+    thread::sleep(Duration::from_millis(60));
+    radio.rxpoll().unwrap();
+    let mut frames = Vec::new();
+    while let Some((data, _)) = radio.recv() {
+        frames.push(data);
+    }
+    frames
+}
+
+#[test]
+fn sim_topology() {
+    let air = sim_air();
+    let mut a = air.attach(1);
+    let mut b = air.attach(2);
+    let mut c = air.attach(3);
+    air.connect(1, 2, LinkProfile::reliable());
+    air.connect(2, 3, LinkProfile::reliable());
+    sim_listen(&mut b);
+    sim_listen(&mut c);
+
+    // only direct neighbours hear a transmission
+    a.tx(&[1u8, 2, 3]).unwrap();
+    assert_eq!(sim_wait(&mut b), vec![vec![1u8, 2, 3]]);
+    assert!(sim_wait(&mut c).is_empty());
+
+    // a lossy link drops everything
+    air.link(2, 3, LinkProfile::lossy(1f64));
+    b.tx(&[4u8]).unwrap();
+    assert!(sim_wait(&mut c).is_empty());
+
+    // and a radio that isn't listening hears nothing
+    air.connect(1, 3, LinkProfile::reliable());
+    c.rxstop().unwrap();
+    a.tx(&[5u8]).unwrap();
+    assert!(sim_wait(&mut c).is_empty());
+}
+
+#[test]
+fn sim_collision() {
+    let air = sim_air();
+    let a = air.attach(1);
+    let b = air.attach(2);
+    let mut c = air.attach(3);
+    air.connect(1, 3, LinkProfile::reliable());
+    air.connect(2, 3, LinkProfile::reliable());
+    sim_listen(&mut c);
+
+    // two hidden terminals transmitting at once collide at the receiver
+    let threads: Vec<_> = vec![a, b].into_iter().map(|mut radio| {
+        thread::spawn(move || radio.tx(&[9u8; 20]).unwrap())
+    }).collect();
+    threads.into_iter().for_each(|t| t.join().unwrap());
+    assert!(sim_wait(&mut c).is_empty());
+}
+
+#[test]
+fn sim_radioloop_chunks() {
+    let air = sim_air();
+    let a = air.attach(1);
+    let b = air.attach(2);
+    air.connect(1, 2, LinkProfile::reliable());
+
+    let (_arx, atx) = a.run(Duration::from_millis(100), TdmaScheduler::disabled(1), LinkArq::disabled(1), FrameCipher::plaintext());
+    let (brx, _btx) = b.run(Duration::from_millis(100), TdmaScheduler::disabled(2), LinkArq::disabled(2), FrameCipher::plaintext());
+    sim_started(&air, 2);
+
+    // send a chunked IP packet across the simulated air
+    let raw = vec![0x45u8, 0x00, 0x00, 0x42, 0x47, 0x07, 0x40, 0x00, 0x40, 0x11, 0x6e, 0xcc, 0xc0, 0xa8, 0x01, 0x89, 0xc0, 0xa8, 0x01, 0xfe, 0xba, 0x2f, 0x00, 0x35, 0x00, 0x2e, 0x1d, 0xf8, 0xbc, 0x81, 0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x03, 0x61, 0x70, 0x69, 0x0c, 0x73, 0x74, 0x65, 0x61, 0x6d, 0x70, 0x6f, 0x77, 0x65, 0x72, 0x65, 0x64, 0x03, 0x63, 0x6f, 0x6d, 0x00, 0x00, 0x1c, 0x00, 0x01];
+    let msg = IPPacketMessage::new(Packet::new(raw.clone()).unwrap());
+    let mut frame = msg.to_frame(7u8, 1, vec![2]);
+    for chunk in frame.chunked(&30usize).unwrap() {
+        atx.send(chunk).unwrap();
+    }
+
+    let mut chunks = Vec::new();
+    while chunks.len() < 3 {
+        let (data, _) = brx.recv_timeout(Duration::from_secs(5)).expect("Chunk lost on simulated air");
+        chunks.push(Frame::from_bytes(&data).unwrap());
+    }
+    let header = chunks[2].header();
+    let mut recombined = recombine_chunks(chunks, header).unwrap();
+    let msg2 = IPPacketMessage::from_frame(&mut recombined).unwrap();
+    assert_eq!(msg2.packet().as_ref(), &raw[..]);
+}
+
+#[test]
+fn sim_radioloop_version() {
+    let air = sim_air();
+    let mut a = air.attach(1);
+    let b = air.attach(2);
+    air.connect(1, 2, LinkProfile::reliable());
+    let (brx, _btx) = b.run(Duration::from_millis(100), TdmaScheduler::disabled(2), LinkArq::disabled(2), FrameCipher::plaintext());
+    sim_started(&air, 2);
+
+    // frames of a protocol version we don't speak never reach the node
+    let mut frame = RouteFailureMessage::new(3).to_frame(7, 1, vec![2]);
+    let mut newer = frame.to_bytes();
+    newer[0] = PROTOCOL_VERSION + 1;
+    a.tx(&newer).unwrap();
+    a.tx(&frame.to_bytes()).unwrap();
+    let (data, _) = brx.recv_timeout(Duration::from_secs(5)).expect("Frame lost on simulated air");
+    assert_eq!(data, frame.to_bytes());
+    assert!(brx.recv_timeout(Duration::from_millis(200)).is_err());
+}
+
+#[test]
+fn sim_radioloop_arq() {
+    let air = sim_air();
+    let a = air.attach(1);
+    let b = air.attach(2);
+    air.connect(1, 2, LinkProfile::reliable());
+
+    let arq = LinkArq::new(1, Duration::from_millis(100), 3);
+    let (_arx, atx) = a.run(Duration::from_millis(100), TdmaScheduler::disabled(1), arq.clone(), FrameCipher::plaintext());
+    sim_started(&air, 1);
+
+    // the first transmission is lost, nobody is listening yet
+    let raw = vec![0x45u8, 0x00, 0x00, 0x14, 0x00, 0x00, 0x40, 0x00, 0x40, 0x11, 0x00, 0x00, 0xac, 0x10, 0x00, 0x01, 0xac, 0x10, 0x00, 0x02];
+    let msg = IPPacketMessage::new(Packet::new(raw.clone()).unwrap());
+    atx.send(msg.to_frame(7u8, 1, vec![1, 2]).to_bytes()).unwrap();
+    thread::sleep(Duration::from_millis(50));
+    let (brx, _btx) = b.run(Duration::from_millis(100), TdmaScheduler::disabled(2), LinkArq::new(2, Duration::from_millis(100), 3), FrameCipher::plaintext());
+
+    // the retransmission gets through, once
+    let (data, _) = brx.recv_timeout(Duration::from_secs(5)).expect("Frame never retransmitted");
+    let mut frame = Frame::from_bytes(&data).unwrap();
+    assert_eq!(IPPacketMessage::from_frame(&mut frame).unwrap().packet().as_ref(), &raw[..]);
+    assert!(brx.recv_timeout(Duration::from_millis(500)).is_err());
+    assert!(arq.failures().is_empty());
+}
+
+#[test]
+fn sim_radioloop_sealed() {
+    let air = sim_air();
+    let a = air.attach(1);
+    let b = air.attach(2);
+    let c = air.attach(3);
+    air.connect(1, 2, LinkProfile::reliable());
+    air.connect(1, 3, LinkProfile::reliable());
+
+    let key = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+    let (_arx, atx) = a.run(Duration::from_millis(100), TdmaScheduler::disabled(1), LinkArq::disabled(1), FrameCipher::new(1, key).unwrap());
+    let (brx, _btx) = b.run(Duration::from_millis(100), TdmaScheduler::disabled(2), LinkArq::disabled(2), FrameCipher::new(2, key).unwrap());
+    let (crx, _ctx) = c.run(Duration::from_millis(100), TdmaScheduler::disabled(3), LinkArq::disabled(3), FrameCipher::plaintext());
+    sim_started(&air, 2);
+    sim_started(&air, 3);
+
+    // only the node sharing our key gets the frame, decrypted
+    let raw = vec![0x45u8, 0x00, 0x00, 0x14, 0x00, 0x00, 0x40, 0x00, 0x40, 0x11, 0x00, 0x00, 0xac, 0x10, 0x00, 0x01, 0xac, 0x10, 0x00, 0x02];
+    let msg = IPPacketMessage::new(Packet::new(raw.clone()).unwrap());
+    atx.send(msg.to_frame(7u8, 1, vec![1, 2]).to_bytes()).unwrap();
+    let (data, _) = brx.recv_timeout(Duration::from_secs(5)).expect("Sealed frame lost");
+    let mut frame = Frame::from_bytes(&data).unwrap();
+    assert_eq!(IPPacketMessage::from_frame(&mut frame).unwrap().packet().as_ref(), &raw[..]);
+
+    // a node without it only sees ciphertext
+    let (data, _) = crx.recv_timeout(Duration::from_secs(5)).expect("Sealed frame lost");
+    assert!(!data.ends_with(&raw));
+}
+
+#[test]
+fn sim_radioloop_slot_exceeded() {
+    let air = sim_air();
+    let a = air.attach(1);
+    let b = air.attach(2);
+    air.connect(1, 2, LinkProfile::reliable());
+
+    // three frames per slot, then one every 500ms
+    let (_arx, atx) = a.run(Duration::from_millis(1500), TdmaScheduler::disabled(1), LinkArq::disabled(1), FrameCipher::plaintext());
+    let (brx, _btx) = b.run(Duration::from_millis(1500), TdmaScheduler::disabled(2), LinkArq::disabled(2), FrameCipher::plaintext());
+    sim_started(&air, 2);
+
+    let mut frame = Frame::new(TransmissionState::FinalChunk, 7u8, MessageType::IPPacket, 1, 0u8, Vec::new(), (0..150u8).collect());
+    for chunk in frame.chunked(&30usize).unwrap() {
+        atx.send(chunk).unwrap();
+    }
+    let other = Frame::new(TransmissionState::FinalChunk, 8u8, MessageType::Broadcast, 1, 1u8, vec![1], vec![0u8, 0, 0]).to_bytes();
+    atx.send(other.clone()).unwrap();
+
+    let mut chunks = Vec::new();
+    while chunks.len() < 5 {
+        let (data, _) = brx.recv_timeout(Duration::from_secs(5)).expect("Chunk lost on simulated air");
+        chunks.push(Frame::from_bytes(&data).unwrap());
+    }
+
+    // the last chunk that fit in the slot says the rest will follow
+    assert_eq!(chunks[0].txflag(), TransmissionState::MoreChunks);
+    assert_eq!(chunks[1].txflag(), TransmissionState::MoreChunks);
+    assert_eq!(chunks[2].txflag(), TransmissionState::SlotExceeded);
+    assert_eq!(chunks[4].txflag(), TransmissionState::FinalChunk);
+    let mut reassembly = Reassembly::new(Duration::from_secs(5));
+    let whole = chunks.into_iter().map(|chunk| reassembly.push(chunk).unwrap()).last().unwrap();
+    assert_eq!(whole.expect("Frame was not reassembled").payload(), (0..150u8).collect::<Vec<u8>>());
+
+    // other frames wait for the whole frame
+    let (data, _) = brx.recv_timeout(Duration::from_secs(5)).expect("Frame lost on simulated air");
+    assert_eq!(data, other);
+}
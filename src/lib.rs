@@ -0,0 +1,19 @@
+//! LoRa mesh network stack, shared by the `loramesh` binary and the
+//! fuzz targets in `fuzz/`
+
+#[macro_use]
+extern crate nonzero_ext;
+extern crate packet;
+extern crate rand;
+extern crate config;
+
+pub mod error;
+pub mod hardware;
+pub mod stack;
+pub mod node;
+pub mod settings;
+
+use crate::stack::*;
+
+pub const MESH_MAX_MESSAGE_LEN: usize = 200;
+pub const TUN_DEFAULT_PREFIX: &str = "loratun%d";
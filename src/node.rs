@@ -1,7 +1,7 @@
 use log::*;
 use std::time::Duration;
 use crate::stack::{NetworkTunnel, Frame};
-use crate::hardware::LoStik;
+use crate::hardware::Radio;
 use crate::stack::*;
 use std::net::Ipv4Addr;
 use packet::ip::v4::Packet;
@@ -10,64 +10,135 @@ use crossbeam_channel::Sender;
 use std::borrow::{BorrowMut};
 use hex;
 use crate::stack::tun::{ipassign, iproute};
+use crate::stack::capabilities::{FEATURE_IPHC, FEATURE_LZ4};
 use std::collections::HashMap;
-use crate::stack::frame::recombine_chunks;
 use std::thread::sleep;
+use std::sync::atomic::{AtomicBool, Ordering};
 use rand::{thread_rng, Rng};
 use rand::prelude::ThreadRng;
-use util::composite_key;
 use std::intrinsics::transmute;
 use crate::settings::Settings;
 use crossbeam_channel::internal::SelectHandle;
 
-pub struct MeshNode {
+/// Frame counters reserved each time the state is saved, the cipher
+/// may use them before the state has to be saved again
+const COUNTER_STEP: u64 = 1 << 20;
+
+pub struct MeshNode<R: Radio, T: Tunnel = NetworkTunnel> {
     /// The ID of this node
-    id: u8,
+    id: NodeId,
     /// IP address of this node's tunnel
     ipaddr: Option<Ipv4Addr>,
+    /// Network mesh addresses are taken from, our gateway's once we hear it
+    subnet: MeshSubnet,
     /// LoRa device for communication
-    radio: LoStik,
+    radio: R,
     /// Local network interface for IP
-    networktunnel: NetworkTunnel,
+    networktunnel: T,
     /// Router instance
     router: MeshRouter,
+    /// Transmit slot scheduler, shared with the radio loop
+    scheduler: TdmaScheduler,
+    /// Frame encryption, applied by the radio loop
+    cipher: FrameCipher,
+    /// Signs our gateway claims and checks those of others
+    gatewaykeys: GatewayKeys,
+    /// Per-hop acknowledgements, shared with the radio loop
+    arq: LinkArq,
+    /// Large transfers waiting for RTS/CTS confirmation
+    reservations: Reservations,
+    /// Packets waiting for on-demand route discovery
+    discovery: RouteDiscovery,
+    /// Chunks of frames still being received
+    reassembly: Reassembly,
+    /// Broadcast in our next slot rather than waiting, like after our address changed
+    announce: bool,
     /// Options
     opt: Settings
 }
 
-impl MeshNode {
+impl<R: Radio, T: Tunnel> MeshNode<R, T> {
+
+    pub fn new(id: NodeId, mut networktunnel: T, radio: R, mut opt: Settings) -> Self {
+        let cipher = FrameCipher::from_key(id, &opt.networkkey).expect("Invalid network key");
+        if !cipher.enabled() {
+            warn!("No network key set, frames are sent unencrypted");
+        }
+        let gatewaykeys = GatewayKeys::new(&opt.gatewaykey, &opt.trustedgateways).expect("Invalid gateway keys");
+        if opt.isgateway {
+            match gatewaykeys.public() {
+                None => warn!("No gateway key set, broadcasts and IP assignments are unsigned"),
+                Some(public) => info!("Signing as gateway with public key {}", &public),
+            }
+        } else if opt.trustedgateways.is_empty() {
+            warn!("No trusted gateways set, any node claiming to be a gateway is believed, signed or not");
+        }
+
+        // chunks can't be larger than what the radio can send
+        let capabilities = radio.capabilities();
+        let maxpayload = capabilities.maxpayload - cipher.overhead();
+        if opt.maxpacketsize > maxpayload {
+            warn!("Max packet size {} exceeds radio limit, using {}", &opt.maxpacketsize, &maxpayload);
+            opt.maxpacketsize = maxpayload;
+        }
 
-    pub fn new(id: u8, mut networktunnel: NetworkTunnel, radio: LoStik, opt: Settings) -> Self {
-        // If this node is a gateway, assign an IP address of 172.16.0.<id>.
-        // Otherwise, we will wait for DHCP from a network gateway and
-        // assign a default address.
+        // If this node is a gateway, assign itself the address of its ID in
+        // the mesh subnet. Otherwise, we will wait for DHCP from a network
+        // gateway and take its subnet.
+        let subnet = MeshSubnet::new(opt.meshprefix, opt.meshprefixlen).expect("Invalid mesh subnet");
         let mut ipaddr = None;
         if opt.isgateway {
-            ipaddr = Some(Ipv4Addr::new(172,16,0, id));
+            ipaddr = Some(subnet.ipaddr(id).expect("Node ID doesn't fit in the mesh subnet"));
             networktunnel.assignipaddr(&ipaddr.unwrap());
-            networktunnel.routeipaddr(&ipaddr.unwrap(), &networktunnel.tunip.unwrap());
+            networktunnel.routeipaddr(&ipaddr.unwrap(), &networktunnel.tunip());
             info!("Network gateway detected, added route to {}", ipaddr.unwrap().to_string());
         }
+        let leases = Leases::new(&subnet, opt.leasestart, opt.leaseend, Duration::from_millis(opt.leasetime), &opt.leasedeny).expect("Invalid lease pool");
         let router =
             MeshRouter::new(
                 id,
                 None,
+                leases,
                 opt.maxhops.clone(),
                 Duration::from_millis(opt.chunktimeout.clone()),
                 opt.isgateway.clone());
+        let scheduler =
+            TdmaScheduler::new(
+                id,
+                Duration::from_millis(opt.txslot),
+                opt.tdmaslots,
+                Duration::from_millis(opt.tdmaguard));
+        let arq = LinkArq::new(id, scheduler.turnaround(), opt.arqretries);
+        let reservations = Reservations::new(scheduler.turnaround(), opt.rtsretries.saturating_add(1));
+        let discovery = RouteDiscovery::new(Duration::from_millis(opt.discoverytimeout));
+        let reassembly = Reassembly::new(Duration::from_millis(opt.chunktimeout));
 
         MeshNode{
             id,
             ipaddr,
+            subnet,
             radio,
             networktunnel,
             router,
+            scheduler,
+            cipher,
+            gatewaykeys,
+            arq,
+            reservations,
+            discovery,
+            reassembly,
+            announce: false,
             opt,
         }
     }
 
     /// Main loop, discover network and send/receive packets
     pub fn run(&mut self) {
+        self.run_until(&AtomicBool::new(false));
+    }
+
+    /// Main loop, returns once `stop` is set
+    pub fn run_until(&mut self, stop: &AtomicBool) {
         // random number generator for frame IDs
         let mut rng = thread_rng();
 
@@ -76,19 +147,24 @@ impl MeshNode {
             self.router.handle_ip_assignment(&self.ipaddr.unwrap());
             self.router.handle_gateway_assignment(&self.ipaddr.unwrap());
         }
+        // take back what we saved before restarting
+        self.restore_state();
 
         // start i/o with local tunnel
         let tunreader = self.networktunnel.run();
         // start radio i/o
-        let (rxreader, txsender) = self.radio.run();
+        let (rxreader, txsender) = self.radio.run(Duration::from_millis(self.opt.txslot), self.scheduler.clone(), self.arq.clone(), self.cipher.clone());
         // rate limiters for different tasks
-        let mut broadcastlimiter = DirectRateLimiter::<LeakyBucket>::new(nonzero!(1u32), Duration::from_secs(rng.gen_range(40, 80)));
+        let third = self.opt.broadcastinterval / 3;
+        let broadcastperiod = Duration::from_millis(2 * third + rng.gen_range(0, 2 * third + 1));
+        let mut broadcastlimiter = DirectRateLimiter::<LeakyBucket>::new(nonzero!(1u32), broadcastperiod);
         let mut mstlimiter = DirectRateLimiter::<LeakyBucket>::new(nonzero!(1u32), Duration::from_secs(240));
+        let mut expirylimiter = DirectRateLimiter::<LeakyBucket>::new(nonzero!(1u32), Duration::from_millis((self.opt.nodetimeout / 4).max(1000)));
 
-        // hashmap for storing incomplete chunks
-        let mut rxchunks: HashMap<String, Vec<Frame>> = HashMap::new();
+        while !stop.load(Ordering::Relaxed) {
+            // nothing came in, don't spin while waiting for it
+            let mut idle = true;
 
-        loop {
             // handle packets coming from tunnel
             // pull the next packet from the receiver, process it, and determine if we
             // need to forward it to the radio
@@ -102,6 +178,7 @@ impl MeshNode {
                     // Otherwise - nothing to write, go on through.
                 },
                 Ok(data) => {
+                    idle = false;
                     // apply routing logic
                     // if it cannot be routed, drop it
                     self.handle_tun_ip(rng, data, &txsender);
@@ -121,171 +198,299 @@ impl MeshNode {
                     }
                     // Otherwise - nothing to write, go on through.
                 },
-                Ok(data) => {
+                Ok((data, metrics)) => {
+                    idle = false;
                     match Frame::from_bytes(&data) {
                         Err(e) => {
                             debug!("Dropping radio frame {}", e);
                         },
                         Ok(mut frame) => {
                             trace!("Received frame txflag {} frameid {} sender {} routes {}", &frame.txflag().to_u8(), &frame.frameid(), &frame.sender(), &frame.routeoffset());
-                            let sender = frame.sender();
-                            let frameid = frame.frameid();
-                            // if this is a chunked packet, save the chunk
-                            // in the hashmap and come back to it
-                            if frame.txflag().more_chunks() {
-                                match rxchunks.get_mut(&composite_key(&sender,&frameid)) {
-                                    None => {
-                                        let mut chunks = Vec::new();
-                                        chunks.push(frame);
-                                        rxchunks.insert(composite_key(&sender,&frameid), chunks);
-                                    },
-                                    Some(chunks) => {
-                                        chunks.push(frame);
-                                    }
-                                }
-                            } else {
-                                // do we need to recombine previous chunks?
-                                match rxchunks.remove(&composite_key(&sender,&frameid)) {
-                                    None => {},
-                                    Some(mut chunks) => {
-                                        trace!("Recombining {} chunks", &chunks.len()+1);
-                                        let header = frame.header();
-                                        chunks.push(frame); // push final frame
-                                        trace!("First chunk flag {}", &chunks[0].txflag().to_u8());
-                                        frame = recombine_chunks(chunks, header);
-                                    }
-                                }
-                                // TODO some things here depend if node is gateway
-                                match frame.msgtype() {
-                                    // received IP packet, handle it
-                                    MessageType::IPPacket => {
-                                        debug!("Recieved IP packet from {}", &frame.sender());
-                                        match IPPacketMessage::from_frame(&mut frame) {
-                                            Err(e) => { error!("Dropping invalid IPv4 packet message {}", e); },
-                                            Ok(msg) => {
-                                                let packet = msg.packet();
-                                                self.handle_radio_ip(packet, frame, &txsender);
+                            // hold on to chunks until the whole frame is here
+                            match self.reassembly.push(frame) {
+                                Err(e) => debug!("Dropping radio frame {}", e),
+                                Ok(None) => {},
+                                Ok(Some(mut frame)) => {
+                                    // TODO some things here depend if node is gateway
+                                    match frame.msgtype() {
+                                        // received IP packet, handle it
+                                        MessageType::IPPacket => {
+                                            debug!("Recieved IP packet from {}", &frame.sender());
+                                            match IPPacketMessage::from_frame(&mut frame) {
+                                                Err(e) => { error!("Dropping invalid IPv4 packet message {}", e); },
+                                                Ok(msg) => {
+                                                    let packet = msg.packet();
+                                                    self.handle_radio_ip(packet, frame, &txsender);
+                                                }
                                             }
-                                        }
-                                    },
-                                    // process another node's broadcast
-                                    MessageType::Broadcast => {
-                                        match BroadcastMessage::from_frame(frame.borrow_mut()) {
-                                            Err(e) => error!("Could not parse BroadcastMessage: {}", e),
-                                            Ok(broadcast) => {
-                                                debug!("Received broadcast from {} {:?}", &frame.sender(), broadcast.clone().ipaddr);
-                                                // we aren't a gateway, we should rebroadcast this
-                                                if !self.opt.isgateway && !frame.route().contains(&self.id) {
-                                                    frame.route_unshift(self.id.clone());
-                                                    txsender.send(frame.to_bytes());
+                                        },
+                                        // received IP packet with compressed headers, rebuild and handle it
+                                        MessageType::CompressedIPPacket => {
+                                            debug!("Recieved compressed IP packet from {}", &frame.sender());
+                                            match CompressedIPPacketMessage::from_frame_in(&mut frame, &self.subnet) {
+                                                Err(e) => { error!("Dropping invalid compressed IPv4 packet message {}", e); },
+                                                Ok(msg) => {
+                                                    let packet = msg.packet();
+                                                    self.handle_radio_ip(packet, frame, &txsender);
                                                 }
-                                                // we need an IP to operate properly
-                                                if self.ipaddr.is_some() {
-                                                    // add route to IP if new observation and we aren't a gateway
-                                                    if &frame.sender() != &self.id && !self.opt.isgateway {
-                                                        if broadcast.ipaddr.is_some() {
-                                                            let ip = broadcast.ipaddr.unwrap().clone();
-                                                            match self.router.node_observe_get(&frame.sender()) {
-                                                                Some(_) => {},
-                                                                None => {
+                                            }
+                                        },
+                                        // process another node's broadcast
+                                        MessageType::Broadcast => {
+                                            match BroadcastMessage::from_frame(frame.borrow_mut()) {
+                                                Err(e) => error!("Could not parse BroadcastMessage: {}", e),
+                                                // don't believe, relay or sync with gateways we don't trust
+                                                Ok(ref broadcast) if broadcast.isgateway && !self.gatewaykeys.accept(frame.sender(), MessageType::Broadcast, broadcast.serial, &broadcast.signed_bytes(frame.sender()), &broadcast.signature) => {
+                                                    warn!("Ignoring unsigned, wrongly signed or replayed gateway broadcast from {}", &frame.sender());
+                                                },
+                                                Ok(broadcast) => {
+                                                    debug!("Received broadcast from {} {:?}", &frame.sender(), broadcast.clone().ipaddr);
+                                                    // the last relay, or the sender itself, is who we heard
+                                                    let transmitter = frame.route().first().cloned();
+                                                    // align our transmit slots with the gateway
+                                                    if broadcast.isgateway && !self.opt.isgateway {
+                                                        if let Some(epoch) = broadcast.epoch {
+                                                            self.scheduler.sync(epoch);
+                                                        }
+                                                        if let Some(subnet) = broadcast.subnet {
+                                                            self.handle_subnet_assignment(subnet);
+                                                        }
+                                                    }
+                                                    // we aren't a gateway, we should rebroadcast this
+                                                    if !self.opt.isgateway && !frame.route().contains(&self.id) {
+                                                        frame.route_unshift(self.id.clone());
+                                                        // the advertised TDMA phase is stale by the time we relay it
+                                                        let mut relay = broadcast.as_ref().clone();
+                                                        if relay.epoch.is_some() {
+                                                            relay.epoch = Some(self.scheduler.next_phase());
+                                                        }
+                                                        txsender.send(relay.to_frame(frame.frameid(), frame.sender(), frame.route()).to_bytes());
+                                                    }
+                                                    // we need an IP to operate properly
+                                                    if self.ipaddr.is_some() {
+                                                        // add route to IP if new observation and we aren't a gateway
+                                                        if &frame.sender() != &self.id && !self.opt.isgateway {
+                                                            if broadcast.ipaddr.is_some() {
+                                                                let ip = broadcast.ipaddr.unwrap().clone();
+                                                                let known = self.router.node_ipaddr(&frame.sender());
+                                                                // only mesh addresses are routed into the mesh
+                                                                if !self.subnet.contains(&ip) {
+                                                                    debug!("Not routing IP {} of node {}, it's outside the mesh subnet {}", &ip, &frame.sender(), &self.subnet);
+                                                                } else if self.router.node_observe_get(&frame.sender()).is_none() || known != Some(ip) {
+                                                                    // the node was given another address, forget the old one
+                                                                    if let Some(old) = known.filter(|old| *old != ip) {
+                                                                        info!("Node {} moved from IP {}", &frame.sender(), &old.to_string());
+                                                                        self.networktunnel.unrouteipaddr(&old);
+                                                                    }
                                                                     info!("Broadcast received from node {}, routing IP {}", &frame.sender(), &ip.to_string());
                                                                     self.networktunnel.routeipaddr(&ip, &self.ipaddr.unwrap());
                                                                     // TODO should we put broadcast handler here and refactor gateway logic?
                                                                 }
                                                             }
-                                                        }
-                                                    };
-                                                    // let our router handle the broadcast and add route to IP if we are a gateway
-                                                    match self.router.handle_broadcast(broadcast, frame.route()) {
-                                                        Err(e) => {
-                                                            error!("Failed to assign IP to broadcast from {}", &frame.sender());
-                                                            // ip address assignment failed, notify the source
-                                                            let mut route: Vec<u8> = Vec::new();
-                                                            if frame.route().len() > 0 {
-                                                                route = frame.route().clone(); // this was multi-hop, send it back
-                                                            } else {
-                                                                route.push(frame.sender());
-                                                            }
-                                                            let bytes = e.to_frame(rng.gen_range(1u8, 244u8), self.id, route).to_bytes();
-                                                            txsender.send(bytes);
-                                                        },
-                                                        Ok(ip) => {
-                                                            match ip {
-                                                                None => (), // no response, we know this node already
-                                                                Some((ipaddr, isnew)) => {
-                                                                    info!("Sending IP {} to node {}", ipaddr.to_string(), frame.sender());
-
-                                                                    // tell the node of their new IP address
-                                                                    let mut route: Vec<u8> = Vec::new();
-                                                                    if frame.route().len() > 0 {
-                                                                        route = frame.route().clone(); // this was multi-hop, send it back
-                                                                    } else {
-                                                                        route.push(frame.sender());
-                                                                    }
-                                                                    let bits = IPAssignSuccessMessage::new(ipaddr).to_frame(rng.gen_range(1u8, 244u8), self.id, route).to_bytes();
-                                                                    txsender.send(bits);
+                                                        };
+                                                        // let our router handle the broadcast and add route to IP if we are a gateway
+                                                        match self.router.handle_broadcast(broadcast, frame.route()) {
+                                                            Err(e) => {
+                                                                error!("Failed to assign IP to broadcast from {}", &frame.sender());
+                                                                // ip address assignment failed, notify the source
+                                                                let mut route: Vec<NodeId> = Vec::new();
+                                                                if frame.route().len() > 0 {
+                                                                    route = frame.route().clone(); // this was multi-hop, send it back
+                                                                } else {
+                                                                    route.push(frame.sender());
+                                                                }
+                                                                let bytes = e.to_frame(rng.gen_range(1u8, 244u8), self.id, route).to_bytes();
+                                                                txsender.send(bytes);
+                                                            },
+                                                            Ok(ip) => {
+                                                                match ip {
+                                                                    None => (), // no response, we know this node already
+                                                                    Some((ipaddr, isnew)) => {
+                                                                        info!("Sending IP {} to node {}", ipaddr.to_string(), frame.sender());
+
+                                                                        // tell the node of their new IP address
+                                                                        let mut route: Vec<NodeId> = Vec::new();
+                                                                        if frame.route().len() > 0 {
+                                                                            route = frame.route().clone(); // this was multi-hop, send it back
+                                                                        } else {
+                                                                            route.push(frame.sender());
+                                                                        }
+                                                                        let mut assign = IPAssignSuccessMessage::new(frame.sender(), ipaddr);
+                                                                        assign.serial = self.gatewaykeys.next_serial();
+                                                                        assign.signature = self.gatewaykeys.sign(&assign.signed_bytes(self.id));
+                                                                        let bits = assign.to_frame(rng.gen_range(1u8, 244u8), self.id, route).to_bytes();
+                                                                        txsender.send(bits);
 
-                                                                    // since we are a gateway, we must route the IP locally
-                                                                    if isnew {
-                                                                        info!("Broadcast received from node {}, assigned new IP {}", &frame.sender(), &ipaddr.to_string());
-                                                                        self.networktunnel.routeipaddr(&ipaddr, &self.ipaddr.unwrap());
+                                                                        // since we are a gateway, we must route the IP locally
+                                                                        if isnew {
+                                                                            info!("Broadcast received from node {}, assigned new IP {}", &frame.sender(), &ipaddr.to_string());
+                                                                            self.networktunnel.routeipaddr(&ipaddr, &self.ipaddr.unwrap());
+                                                                            self.save_state();
+                                                                        }
                                                                     }
                                                                 }
                                                             }
                                                         }
                                                     }
+                                                    // learn how good our link to the transmitter is
+                                                    if let Some(neighbour) = transmitter {
+                                                        self.router.edge_metrics(neighbour, &metrics);
+                                                    }
+                                                }
+                                            }
+                                        },
+                                        // we were successfully assigned an IP
+                                        MessageType::IPAssignSuccess => {
+                                            match frame.route_shift() {
+                                                None => error!("Received invalid IP message with no destination"),
+                                                Some(nexthop) => {
+                                                    if nexthop == self.id { // is it for us? drop if not
+                                                        if frame.route().len() == 0 {
+                                                            match IPAssignSuccessMessage::from_frame(frame.borrow_mut()) {
+                                                                Err(e) => error!("Could not parse IPAssignSuccessMessage: {}", e),
+                                                                Ok(ref message) if message.nodeid != self.id || !self.gatewaykeys.accept(frame.sender(), MessageType::IPAssignSuccess, message.serial, &message.signed_bytes(frame.sender()), &message.signature) => {
+                                                                    warn!("Ignoring unsigned, wrongly signed or replayed IP assignment from {}", &frame.sender());
+                                                                },
+                                                                Ok(message) => {
+                                                                    info!("Received new IP address {} from gateway {}", &message.ipaddr.to_string(), &frame.sender());
+                                                                    self.handle_ip_assignment(message.ipaddr);
+                                                                }
+                                                            }
+                                                        }
+                                                        if frame.route().len() > 0 { // retransmit to next hop
+                                                            txsender.send(frame.to_bytes());
+                                                        }
+                                                    }
                                                 }
                                             }
-                                        }
-                                    },
-                                    // we were successfully assigned an IP
-                                    MessageType::IPAssignSuccess => {
-                                        match frame.route_shift() {
-                                            None => error!("Received invalid IP message with no destination"),
-                                            Some(nexthop) => {
-                                                if nexthop == self.id { // is it for us? drop if not
-                                                    if frame.route().len() == 0 {
-                                                        match IPAssignSuccessMessage::from_frame(frame.borrow_mut()) {
-                                                            Err(e) => error!("Could not parse IPAssignSuccessMessage: {}", e),
-                                                            Ok(message) => {
-                                                                info!("Received new IP address {} from gateway {}", &message.ipaddr.to_string(), &frame.sender());
-                                                                self.handle_ip_assignment(message.ipaddr);
+                                        },
+                                        // we sent a broadcast without IP, but got a failure
+                                        MessageType::IPAssignFailure => {
+                                            match frame.route_shift() {
+                                                None => error!("Received invalid IP message with no destination"),
+                                                Some(nexthop) => {
+                                                    if nexthop == self.id { // is it for us? drop if not
+                                                        if frame.route().len() == 0 {
+                                                            match IPAssignFailureMessage::from_frame(frame.borrow_mut()) {
+                                                                Err(e) => error!("Could not parse IPAssignFailureMessage: {}", e),
+                                                                Ok(message) => error!("Failed to be assigned IP: {}", message.reason)
                                                             }
                                                         }
+                                                        if frame.route().len() > 0 { // retransmit to next hop
+                                                            txsender.send(frame.to_bytes());
+                                                        }
+                                                    }
+                                                }
+                                            }
+                                        },
+                                        // another node is looking for a route, answer or relay it
+                                        MessageType::RouteDiscovery => {
+                                            match RouteDiscoveryMessage::from_frame(frame.borrow_mut()) {
+                                                Err(e) => error!("Could not parse RouteDiscoveryMessage: {}", e),
+                                                Ok(discovery) => {
+                                                    let origin = frame.sender();
+                                                    if origin != self.id && !frame.route().contains(&self.id) && !self.discovery.seen(origin, frame.frameid()) {
+                                                        self.router.handle_route_discovery(&frame.route());
+                                                        if Some(discovery.target) == self.ipaddr {
+                                                            // send the full path back along the reverse route
+                                                            let route = frame.route();
+                                                            let mut path = route.clone();
+                                                            path.reverse();
+                                                            path.push(self.id);
+                                                            info!("Answering route discovery from node {}", &origin);
+                                                            let success = RouteSuccessMessage::new(discovery.target, path);
+                                                            txsender.send(success.to_frame(rng.gen_range(1u8, 244u8), self.id, route).to_bytes());
+                                                        } else if frame.route().len() < self.opt.maxhops as usize {
+                                                            frame.route_unshift(self.id);
+                                                            txsender.send(frame.to_bytes());
+                                                        }
                                                     }
-                                                    if frame.route().len() > 0 { // retransmit to next hop
-                                                        txsender.send(frame.to_bytes());
+                                                }
+                                            }
+                                        },
+                                        // a route we, or a node we relay for, asked for was found
+                                        MessageType::RouteSuccess => {
+                                            match frame.route_shift() {
+                                                None => error!("Received invalid route message with no destination"),
+                                                Some(nexthop) => {
+                                                    if nexthop == self.id { // is it for us? drop if not
+                                                        match RouteSuccessMessage::from_frame(frame.borrow_mut()) {
+                                                            Err(e) => error!("Could not parse RouteSuccessMessage: {}", e),
+                                                            Ok(success) => {
+                                                                self.router.handle_route_success(&success.target, &success.path);
+                                                                if frame.route().len() == 0 {
+                                                                    info!("Discovered route to {} through {:?}", &success.target, &success.path);
+                                                                    for packet in self.discovery.resolve(&success.target) {
+                                                                        self.handle_tun_ip(rng, packet, &txsender);
+                                                                    }
+                                                                } else { // retransmit to next hop
+                                                                    txsender.send(frame.to_bytes());
+                                                                }
+                                                            }
+                                                        }
                                                     }
                                                 }
                                             }
-                                        }
-                                    },
-                                    // we sent a broadcast without IP, but got a failure
-                                    MessageType::IPAssignFailure => {
-                                        match frame.route_shift() {
-                                            None => error!("Received invalid IP message with no destination"),
-                                            Some(nexthop) => {
-                                                if nexthop == self.id { // is it for us? drop if not
-                                                    if frame.route().len() == 0 {
-                                                        match IPAssignFailureMessage::from_frame(frame.borrow_mut()) {
-                                                            Err(e) => error!("Could not parse IPAssignFailureMessage: {}", e),
-                                                            Ok(message) => error!("Failed to be assigned IP: {}", message.reason)
+                                        },
+                                        // a node on our route lost its next hop
+                                        MessageType::RouteFailure => {
+                                            match frame.route_shift() {
+                                                None => error!("Received invalid route message with no destination"),
+                                                Some(nexthop) => {
+                                                    if nexthop == self.id { // is it for us? drop if not
+                                                        match RouteFailureMessage::from_frame(frame.borrow_mut()) {
+                                                            Err(e) => error!("Could not parse RouteFailureMessage: {}", e),
+                                                            Ok(failure) => {
+                                                                // forget the broken link so the next packet takes another path
+                                                                info!("Node {} lost its link to node {}", &frame.sender(), &failure.failednodeid);
+                                                                self.router.edge_remove(frame.sender(), failure.failednodeid);
+                                                                if frame.route().len() > 0 { // retransmit to next hop
+                                                                    txsender.send(frame.to_bytes());
+                                                                }
+                                                            }
                                                         }
                                                     }
-                                                    if frame.route().len() > 0 { // retransmit to next hop
-                                                        txsender.send(frame.to_bytes());
+                                                }
+                                            }
+                                        },
+                                        // a neighbour wants to send us a large transfer
+                                        MessageType::TransmitRequest => {
+                                            match TransmitRequestMessage::from_frame(frame.borrow_mut()) {
+                                                Err(e) => error!("Could not parse TransmitRequestMessage: {}", e),
+                                                Ok(request) => {
+                                                    // only confirm if nobody else reserved the channel
+                                                    if request.dest == self.id && !self.scheduler.is_deferred() {
+                                                        trace!("Confirming transmit request from {}", &frame.sender());
+                                                        let confirm = TransmitConfirmMessage::new(frame.sender(), request.duration);
+                                                        txsender.send(confirm.to_frame(rng.gen_range(1u8, 244u8), self.id, vec![frame.sender()]).to_bytes());
+                                                    }
+                                                }
+                                            }
+                                        },
+                                        // either we may send our transfer, or we must stay quiet
+                                        MessageType::TransmitConfirm => {
+                                            match TransmitConfirmMessage::from_frame(frame.borrow_mut()) {
+                                                Err(e) => error!("Could not parse TransmitConfirmMessage: {}", e),
+                                                Ok(confirm) => {
+                                                    if confirm.requester == self.id {
+                                                        if let Some(chunks) = self.reservations.confirm(frame.sender()) {
+                                                            trace!("Node {} confirmed, sending {} chunks", &frame.sender(), &chunks.len());
+                                                            for chunk in chunks {
+                                                                txsender.send(chunk);
+                                                            }
+                                                        }
+                                                    } else {
+                                                        debug!("Deferring {}ms for transfer to {}", &confirm.duration, &frame.sender());
+                                                        self.scheduler.defer(Duration::from_millis(confirm.duration as u64));
                                                     }
                                                 }
                                             }
-                                        }
-                                    },
-                                    // handle route discovery
-                                    // TODO: refactor out old message architecture
-                                    MessageType::RouteDiscovery => {},
-                                    MessageType::RouteSuccess => {},
-                                    MessageType::RouteFailure => {},
-                                    MessageType::TransmitRequest => {},
-                                    MessageType::TransmitConfirm => {},
+                                        },
+                                        // acknowledgements are consumed by the radio loop
+                                        MessageType::Ack => {
+                                            trace!("Ignoring acknowledgement from {}", &frame.sender());
+                                        },
+                                    }
                                 }
                             }
                         }
@@ -293,11 +498,34 @@ impl MeshNode {
                 }
             }
 
+            // frames the next hop never acknowledged mean the link is broken
+            for data in self.arq.failures() {
+                self.handle_unacknowledged(data, &txsender);
+            }
+
+            // ask again or give up on reservations that weren't confirmed
+            self.check_reservations(&txsender);
+
+            // drop packets whose route could not be discovered
+            for (target, dropped) in self.discovery.expired() {
+                debug!("Route discovery for {} timed out, dropping {} packets", &target, &dropped);
+            }
+
             // now handle any protocol tasks
             // such as broadcasts or route discovery
-            if broadcastlimiter.check().is_ok() {
+            // broadcasts wait for our slot so they go out right away
+            if self.scheduler.can_transmit() && (self.announce || broadcastlimiter.check().is_ok()) {
                 debug!("Sending broadcast to nearby nodes");
-                self.broadcast();
+                self.broadcast(&txsender);
+            }
+
+            // forget nodes we haven't heard from in a while
+            if expirylimiter.check().is_ok() {
+                self.expire_nodes();
+                // and save before the cipher runs out of counters
+                if self.opt.isgateway || self.cipher.remaining() < COUNTER_STEP / 2 {
+                    self.save_state();
+                }
             }
 
             // clean up the mesh graph to optimize
@@ -306,19 +534,125 @@ impl MeshNode {
                 debug!("Applying minimum spanning tree to mesh router");
                 self.router.min_spanning_tree();
             }
+
+            if idle {
+                sleep(Duration::from_millis(1));
+            }
         }
     }
 
     /// Handle an IP assignment
     /// ensures a new local route is set up and node
-    /// accepts new IP
-    // TODO recover if new IP is different than old
+    /// accepts new IP, replacing the one it had
     fn handle_ip_assignment(&mut self, ipaddr: Ipv4Addr) {
-        if self.ipaddr.is_none() {
-            self.ipaddr = Some(ipaddr);
-            self.networktunnel.assignipaddr(&ipaddr);
-            self.networktunnel.routeipaddr(&ipaddr, &self.networktunnel.tunip.unwrap());
-            self.router.handle_ip_assignment(&ipaddr);
+        if self.ipaddr == Some(ipaddr) {
+            return;
+        }
+        self.networktunnel.assignipaddr(&ipaddr);
+        if let Some(old) = self.ipaddr {
+            info!("Gateway moved us from IP {} to {}", &old, &ipaddr);
+            // the routes to the rest of the mesh went through the old address
+            for (dest, via) in self.networktunnel.routes() {
+                if via == old {
+                    self.networktunnel.routeipaddr(&dest, &ipaddr);
+                }
+            }
+            self.networktunnel.unrouteipaddr(&old);
+            self.networktunnel.unassignipaddr(&old);
+        }
+        self.ipaddr = Some(ipaddr);
+        self.networktunnel.routeipaddr(&ipaddr, &self.networktunnel.tunip());
+        self.router.handle_ip_assignment(&ipaddr);
+        // let the mesh know rather than have it wait for our next broadcast
+        self.announce = true;
+    }
+
+    /// Take back the frame counter, and the leases, topology and claim serial
+    /// if we are a gateway, saved before we restarted and route the addresses of the
+    /// nodes we knew again
+    /* With encryption, the counters we'll use are saved before the radio
+    starts. A node that can't tell which counters it used already, or
+    can't save the ones it's going to use, refuses to start rather than
+    risk reusing nonces. */
+    fn restore_state(&mut self) {
+        let statefile = self.opt.statefile.clone();
+        let state = match GatewayState::load(&statefile) {
+            Err(e) if self.cipher.enabled() => panic!("Could not read the frame counter from {}: {}", &statefile.display(), e),
+            Err(e) => {
+                warn!("Could not load state from {}, starting afresh: {}", &statefile.display(), e);
+                None
+            },
+            Ok(None) => {
+                info!("No state saved in {}", &statefile.display());
+                None
+            },
+            Ok(state) => state,
+        };
+        if let Some(state) = state {
+            self.cipher.resume(state.counter);
+            if self.opt.isgateway {
+                self.gatewaykeys.resume(state.serial);
+                info!("Restoring {} leases and {} nodes from {}", state.leases.len(), state.nodes.len(), &statefile.display());
+                for (_, ipaddr) in self.router.restore(&state) {
+                    if self.subnet.contains(&ipaddr) {
+                        self.networktunnel.routeipaddr(&ipaddr, &self.ipaddr.unwrap());
+                    }
+                }
+            }
+        }
+        if self.cipher.enabled() && !self.save_state() {
+            panic!("Could not save the frame counter to {}", &statefile.display());
+        }
+    }
+
+    /// Save our frame counter, and our leases, topology and claim serial if
+    /// we are a gateway, so a restart doesn't lose them, returns whether it
+    /// was saved
+    fn save_state(&mut self) -> bool {
+        let mut state = if self.opt.isgateway { self.router.state() } else { GatewayState::default() };
+        state.counter = self.cipher.counter() + COUNTER_STEP;
+        // we sign far fewer claims than that between saves, so a restarted
+        // gateway's claims are newer than all it signed before, whatever its clock
+        if self.opt.isgateway {
+            state.serial = self.gatewaykeys.serial() + COUNTER_STEP;
+        }
+        match state.save(&self.opt.statefile) {
+            Err(e) => {
+                error!("Could not save state to {}: {}", &self.opt.statefile.display(), e);
+                false
+            },
+            Ok(()) => {
+                // only now may the cipher use the counters we saved
+                self.cipher.reserve(state.counter);
+                true
+            }
+        }
+    }
+
+    /// Handle the mesh subnet advertised by a gateway
+    /// so compressed headers and addresses agree with it
+    fn handle_subnet_assignment(&mut self, subnet: MeshSubnet) {
+        if subnet != self.subnet {
+            info!("Using mesh subnet {} from gateway", &subnet);
+            self.subnet = subnet;
+            if let Some(ipaddr) = self.ipaddr {
+                if !subnet.contains(&ipaddr) {
+                    warn!("IP address {} is outside the mesh subnet {}, the gateway will move us", &ipaddr, &subnet);
+                }
+            }
+        }
+    }
+
+    /// Remove expired nodes from the router and withdraw their routes
+    fn expire_nodes(&mut self) {
+        for (nodeid, ipaddr) in self.router.expire(Duration::from_millis(self.opt.nodetimeout)) {
+            match ipaddr {
+                None => info!("Node {} expired", &nodeid),
+                Some(ip) => {
+                    info!("Node {} expired, removing route to {}", &nodeid, &ip);
+                    self.networktunnel.unrouteipaddr(&ip);
+                }
+            }
         }
     }
 
@@ -341,15 +675,28 @@ impl MeshNode {
                 // then send it in chunks if necessary
                 match self.router.packet_route(&packet) {
                     None => {
-                        trace!("Dropping packet to: {}", packet.destination());
-                        drop(packet);
+                        // hold the packet and ask the mesh for a route
+                        let dest = packet.destination();
+                        if self.discovery.queue(packet) {
+                            debug!("No route to {}, starting route discovery", &dest);
+                            let discovery = RouteDiscoveryMessage::new(dest);
+                            txsender.send(discovery.to_frame(framerng.gen_range(1, 244) as u8, self.id, vec![self.id]).to_bytes());
+                        }
                     },
                     Some(route) => {
-                        let message = IPPacketMessage::new(packet);
-                        let chunks = message.to_frame(framerng.gen_range(1, 244) as u8, self.id.clone(), route).chunked(&self.opt.maxpacketsize);
-                        for chunk in chunks {
-                            trace!("Sending chunk");
-                            txsender.send(chunk);
+                        let nexthop = route.get(1).cloned();
+                        let frameid = framerng.gen_range(1, 244) as u8;
+                        // only use what the next hop understands
+                        let capabilities = nexthop.map(|hop| self.router.node_capabilities(&hop)).unwrap_or_default();
+                        let mut frame = if capabilities.supports(FEATURE_IPHC) && CompressedIPPacketMessage::compressible(&packet) {
+                            CompressedIPPacketMessage::new(packet).with_subnet(self.subnet).to_frame(frameid, self.id.clone(), route)
+                        } else {
+                            IPPacketMessage::new(packet).to_frame(frameid, self.id.clone(), route)
+                        };
+                        frame.set_compress(capabilities.supports(FEATURE_LZ4));
+                        match frame.chunked(&self.opt.maxpacketsize) {
+                            Ok(chunks) => self.send_chunks(nexthop, chunks, txsender),
+                            Err(e) => error!("Dropping IP packet: {}", e),
                         }
                     }
                 }
@@ -385,42 +732,461 @@ impl MeshNode {
             // there wasn't a next hop, something's wrong
             None => error!("Received an IP packet from {} with no route", &frame.sender()),
             Some(nexthop) => {
-                if nexthop == self.id { panic!("Tried to transmit packet with local node destination"); }
+                // a route that leads back to us would only loop
+                if nexthop == self.id {
+                    warn!("Dropping IP packet from {}, its route sends it back through us", &frame.sender());
+                    return;
+                }
+
+                // only the node on the route relays it
+                if frame.route().first() != Some(&self.id) {
+                    trace!("Not relaying IP packet from {}, we aren't on its route", &frame.sender());
+                    return;
+                }
 
                 // we can still forward it to another node id
-                if frame.route().len() > 0 {
-                    // chunk it
-                    let chunks = frame.chunked(&self.opt.maxpacketsize);
+                match frame.route().get(1).cloned() {
+                    Some(nexthop) if self.router.node_known(&nexthop) => {
+                        // down-convert it for a next hop that lacks our features
+                        let capabilities = self.router.node_capabilities(&nexthop);
+                        if frame.msgtype() == MessageType::CompressedIPPacket && !capabilities.supports(FEATURE_IPHC) {
+                            trace!("Node {} doesn't understand compressed IP headers, relaying the whole packet", &nexthop);
+                            frame = IPPacketMessage::new(packet).to_frame(frame.frameid(), frame.sender(), frame.route());
+                        }
+                        frame.set_compress(capabilities.supports(FEATURE_LZ4));
+
+                        // chunk it
+                        match frame.chunked(&self.opt.maxpacketsize) {
+                            Ok(chunks) => self.send_chunks(Some(nexthop), chunks, txsender),
+                            Err(e) => error!("Dropping IP packet: {}", e),
+                        }
+                    },
+                    Some(nexthop) => {
+                        error!("Dropping IP packet from {} to {}: next hop {} unreachable", &packet.source(), &packet.destination(), &nexthop);
+                        self.route_failure(frame.sender(), nexthop, txsender);
+                    },
+                    None => {
+                        error!("Dropping IP packet from {} to {}: no route available", &packet.source(), &packet.destination());
+                    }
+                }
+            }
+        }
+    }
+
+    /// Repair routes after the next hop never acknowledged a frame
+    fn handle_unacknowledged(&mut self, data: Vec<u8>, txsender: &Sender<Vec<u8>>) {
+        let mut frame = match Frame::from_bytes(&data) {
+            Err(_) => return,
+            Ok(frame) => frame,
+        };
+        if let Some(nexthop) = frame.nexthop() {
+            let removed = self.router.edge_remove(self.id, nexthop);
+            if removed {
+                warn!("Node {} never acknowledged frame {} from {}", &nexthop, &frame.frameid(), &frame.sender());
+            }
+            // the sender keeps using the link until it hears it broke, and our
+            // first failure may not reach it, so answer every packet it sends
+            // that way, but only once for all the chunks of a frame
+            if frame.msgtype().is_ip_packet() && frame.sender() != self.id && (removed || frame.chunk() == 0) {
+                self.route_failure(frame.sender(), nexthop, txsender);
+            }
+        }
+    }
+
+    /// Tell the sender of a packet that its route through us is broken
+    fn route_failure(&mut self, sender: NodeId, failednodeid: NodeId, txsender: &Sender<Vec<u8>>) {
+        self.router.edge_remove(self.id, failednodeid);
+
+        // send it back along the best path we know, or directly
+        let route = match self.router.node_route(self.id, sender) {
+            Some(path) if path.len() > 1 => path[1..].to_vec(),
+            _ => vec![sender],
+        };
+        let failure = RouteFailureMessage::new(failednodeid);
+        txsender.send(failure.to_frame(thread_rng().gen_range(1u8, 244u8), self.id, route).to_bytes());
+    }
+
+    /// Transmit the chunks of a frame, large transfers are held
+    /// back until the next hop confirms our transmit request
+    fn send_chunks(&mut self, nexthop: Option<NodeId>, chunks: Vec<Vec<u8>>, txsender: &Sender<Vec<u8>>) {
+        let threshold = self.opt.rtsthreshold;
+        match nexthop {
+            Some(dest) if threshold > 0 && chunks.len() >= threshold => {
+                let frames = chunks.len();
+                if self.reservations.queue(dest, chunks) {
+                    self.request_transmit(dest, frames, txsender);
+                }
+            },
+            _ => {
+                for chunk in chunks {
+                    trace!("Sending chunk");
+                    txsender.send(chunk);
+                }
+            }
+        }
+    }
+
+    /// Ask the next hop to reserve the channel for our transfer
+    fn request_transmit(&mut self, dest: NodeId, frames: usize, txsender: &Sender<Vec<u8>>) {
+        let duration = self.scheduler.reservation(frames).as_millis() as u32;
+        trace!("Requesting {}ms to transmit {} chunks to {}", &duration, &frames, &dest);
+        let request = TransmitRequestMessage::new(dest, duration);
+        txsender.send(request.to_frame(thread_rng().gen_range(1u8, 244u8), self.id, vec![dest]).to_bytes());
+    }
+
+    /// Handle transfers whose transmit request went unanswered
+    fn check_reservations(&mut self, txsender: &Sender<Vec<u8>>) {
+        for unconfirmed in self.reservations.expired() {
+            match unconfirmed {
+                Unconfirmed::Retry { dest, frames } => self.request_transmit(dest, frames, txsender),
+                Unconfirmed::Abandon { dest, chunks } => {
+                    debug!("Sending {} chunks to {} without reservation", &chunks.len(), &dest);
                     for chunk in chunks {
                         txsender.send(chunk);
                     }
-                } else {
-                    error!("Dropping IP packet from {} to {}: no route available", &packet.source(), &packet.destination());
                 }
             }
         }
     }
 
     /// Send a broadcast packet to nearby nodes
-    fn broadcast(&mut self) {
+    fn broadcast(&mut self, txsender: &Sender<Vec<u8>>) {
         // prepare broadcast
-        if self.radio.txsender.is_empty() {
+        if txsender.is_empty() {
             let mut ipOffset = 0;
             if self.ipaddr.is_some() {
                 ipOffset = 4;
             }
-            let msg = BroadcastMessage {
+            // gateways keep the mesh's transmit slots aligned
+            let mut epoch = None;
+            if self.opt.isgateway && self.scheduler.enabled() {
+                epoch = Some(self.scheduler.next_phase());
+            }
+            let mut msg = BroadcastMessage {
                 header: None,
                 isgateway: self.opt.isgateway.clone(),
                 ipOffset,
-                ipaddr: self.ipaddr
+                ipaddr: self.ipaddr,
+                epoch,
+                capabilities: Some(Capabilities::ours()),
+                subnet: if self.opt.isgateway { Some(self.subnet) } else { None },
+                serial: None,
+                signature: None
             };
-            let mut route: Vec<u8> = Vec::new();
+            if self.opt.isgateway {
+                msg.serial = self.gatewaykeys.next_serial();
+                msg.signature = self.gatewaykeys.sign(&msg.signed_bytes(self.id));
+            }
+            let mut route: Vec<NodeId> = Vec::new();
             route.push(self.id.clone());
             let mut frame = msg.to_frame(1u8, self.id, route);
             // dump
-            self.radio.txsender.send(frame.to_bytes());
+            txsender.send(frame.to_bytes());
+            self.announce = false;
+        }
+    }
+
+}
+#[cfg(test)]
+use crate::hardware::sim::{AirtimeModel, LinkProfile, VirtualAir, VirtualTunnel};
+#[cfg(test)]
+use std::sync::{Arc, Mutex, MutexGuard};
+#[cfg(test)]
+use std::sync::atomic::AtomicUsize;
+#[cfg(test)]
+use std::thread::{self, JoinHandle};
+#[cfg(test)]
+use std::time::Instant;
+
+/// a node running over the simulated air, stopped when dropped
+#[cfg(test)]
+struct SimNode {
+    tunnel: VirtualTunnel,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+    statefile: std::path::PathBuf,
+}
+
+#[cfg(test)]
+impl Drop for SimNode {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() && !thread::panicking() {
+                panic!("Simulated node crashed");
+            }
         }
+        let _ = std::fs::remove_file(&self.statefile);
     }
+}
+
+/// fresh air to simulate a mesh on, one at a time, the guard keeps other
+/// simulations from starving this one of CPU and skewing its timing
+#[cfg(test)]
+fn sim_air() -> (MutexGuard<'static, ()>, VirtualAir) {
+    static SIMULATION: Mutex<()> = Mutex::new(());
+    let guard = SIMULATION.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    (guard, VirtualAir::new(AirtimeModel::new(Duration::from_millis(5), Duration::from_micros(20))))
+}
 
-}
\ No newline at end of file
+#[cfg(test)]
+fn sim_settings(nodeid: NodeId, isgateway: bool) -> Settings {
+    static STATEFILES: AtomicUsize = AtomicUsize::new(0);
+    let mut opt = Settings::new().unwrap();
+    opt.nodeid = nodeid;
+    opt.isgateway = isgateway;
+    opt.networkkey = None;
+    opt.gatewaykey = None;
+    opt.trustedgateways = Vec::new();
+    opt.statefile = std::env::temp_dir().join(format!("loramesh-sim-{}-{}.json", std::process::id(), STATEFILES.fetch_add(1, Ordering::Relaxed)));
+    opt.txslot = 100;
+    opt.tdmaslots = 0;
+    opt.arqretries = 1;
+    opt.chunktimeout = 2000;
+    opt.discoverytimeout = 1500;
+    opt.broadcastinterval = 300;
+    opt
+}
+
+/// start a node on the air, once its radio is listening
+#[cfg(test)]
+fn sim_node(air: &VirtualAir, opt: Settings) -> SimNode {
+    let nodeid = opt.nodeid;
+    let statefile = opt.statefile.clone();
+    let tunnel = VirtualTunnel::new(opt.tunaddr);
+    let mut node = MeshNode::new(nodeid, tunnel.clone(), air.attach(nodeid), opt);
+    let stop = Arc::new(AtomicBool::new(false));
+    let flag = Arc::clone(&stop);
+    let thread = thread::spawn(move || node.run_until(&flag));
+    for _ in 0..100 {
+        if air.listening(nodeid) { break; }
+        sleep(Duration::from_millis(10));
+    }
+    SimNode { tunnel, stop, thread: Some(thread), statefile }
+}
+
+/// wait until a condition holds, false if it never did
+#[cfg(test)]
+fn sim_until<F: FnMut() -> bool>(timeout: Duration, mut condition: F) -> bool {
+    let deadline = Instant::now() + timeout;
+    while Instant::now() < deadline {
+        if condition() { return true; }
+        sleep(Duration::from_millis(20));
+    }
+    false
+}
+
+/// the only address a node was given
+#[cfg(test)]
+fn sim_address(node: &SimNode) -> Option<Ipv4Addr> {
+    let addresses = node.tunnel.addresses();
+    if addresses.len() == 1 { addresses.into_iter().next() } else { None }
+}
+
+/// an empty UDP packet
+#[cfg(test)]
+fn sim_packet(source: Ipv4Addr, destination: Ipv4Addr) -> Packet<Vec<u8>> {
+    let mut raw = vec![0x45u8, 0x00, 0x00, 0x14, 0x00, 0x00, 0x40, 0x00, 0x40, 0x11, 0x00, 0x00];
+    raw.extend_from_slice(&source.octets());
+    raw.extend_from_slice(&destination.octets());
+    Packet::new(raw).unwrap()
+}
+
+/// keep sending a packet from one node until another delivers it
+#[cfg(test)]
+fn sim_deliver(from: &SimNode, to: &SimNode, timeout: Duration) -> bool {
+    let (source, destination) = (sim_address(from).unwrap(), sim_address(to).unwrap());
+    let deadline = Instant::now() + timeout;
+    while Instant::now() < deadline {
+        from.tunnel.inject(sim_packet(source, destination));
+        if let Some(packet) = to.tunnel.delivered(Duration::from_millis(500)) {
+            if packet.source() == source && packet.destination() == destination {
+                return true;
+            }
+        }
+    }
+    false
+}
+
+#[test]
+fn node_sim_multihop() {
+    let (_simulation, air) = sim_air();
+    air.connect(1, 2, LinkProfile::reliable());
+    air.connect(2, 3, LinkProfile::reliable());
+    let gateway = sim_node(&air, sim_settings(1, true));
+    let relay = sim_node(&air, sim_settings(2, false));
+    let node = sim_node(&air, sim_settings(3, false));
+
+    // the gateway routes its own address, and leases the others theirs
+    let gatewayip = Ipv4Addr::new(172,16,0,1);
+    assert_eq!(sim_address(&gateway), Some(gatewayip));
+    assert_eq!(gateway.tunnel.routes().get(&gatewayip), Some(&Ipv4Addr::new(10,107,1,3)));
+    assert!(sim_until(Duration::from_secs(10), || sim_address(&relay).is_some() && sim_address(&node).is_some()));
+    assert_ne!(sim_address(&relay), sim_address(&node));
+    assert!(sim_until(Duration::from_secs(5), || gateway.tunnel.routes().contains_key(&sim_address(&node).unwrap())));
+
+    // packets cross the relay both ways
+    assert!(sim_deliver(&node, &gateway, Duration::from_secs(10)));
+    assert!(sim_deliver(&gateway, &node, Duration::from_secs(10)));
+}
+
+#[test]
+fn node_sim_discovery() {
+    let (_simulation, air) = sim_air();
+    air.connect(1, 2, LinkProfile::reliable());
+    air.connect(2, 3, LinkProfile::reliable());
+    let mut opt = sim_settings(1, true);
+    opt.broadcastinterval = 600000;
+    let gateway = sim_node(&air, opt);
+
+    // nodes started after the gateway's first broadcast never hear of it
+    assert!(sim_until(Duration::from_secs(10), || air.transmissions(1) > 0));
+    let relay = sim_node(&air, sim_settings(2, false));
+    let node = sim_node(&air, sim_settings(3, false));
+    assert!(sim_until(Duration::from_secs(10), || sim_address(&relay).is_some() && sim_address(&node).is_some()));
+    assert!(!node.tunnel.routes().contains_key(&sim_address(&gateway).unwrap()));
+
+    // so they have to ask the mesh for a route to it
+    assert!(sim_deliver(&node, &gateway, Duration::from_secs(10)));
+}
+
+#[test]
+fn node_sim_route_failure() {
+    let (_simulation, air) = sim_air();
+    air.connect(1, 2, LinkProfile::reliable());
+    air.connect(2, 3, LinkProfile::reliable());
+    // a detour that is much worse than going through node 2, and slow
+    // enough that both relays repeating a frame don't collide
+    let poor = LinkProfile { loss: 0f64, latency: Duration::from_millis(50), snr: -18 };
+    air.connect(1, 4, poor.clone());
+    air.connect(3, 4, poor);
+    let gateway = sim_node(&air, sim_settings(1, true));
+    let _relay = sim_node(&air, sim_settings(2, false));
+    let _detour = sim_node(&air, sim_settings(4, false));
+    let node = sim_node(&air, sim_settings(3, false));
+    assert!(sim_until(Duration::from_secs(10), || sim_address(&node).is_some()));
+    assert!(sim_deliver(&node, &gateway, Duration::from_secs(10)));
+
+    // node 2 tells us it lost the gateway, and we take the detour
+    air.disconnect(1, 2);
+    assert!(sim_deliver(&node, &gateway, Duration::from_secs(15)));
+}
+
+#[test]
+fn node_sim_route_loop() {
+    let (_simulation, air) = sim_air();
+    let mut peer = air.attach(9);
+    air.connect(1, 2, LinkProfile::reliable());
+    air.connect(9, 2, LinkProfile::reliable());
+    let node = sim_node(&air, sim_settings(2, false));
+
+    // a packet claiming we already relayed it is dropped
+    let packet = sim_packet(Ipv4Addr::new(172,16,0,9), Ipv4Addr::new(172,16,0,3));
+    peer.tx(&IPPacketMessage::new(packet).to_frame(7, 9, vec![2, 3]).to_bytes()).unwrap();
+    sleep(Duration::from_millis(100));
+
+    // and the node carries on
+    let _gateway = sim_node(&air, sim_settings(1, true));
+    assert!(sim_until(Duration::from_secs(10), || sim_address(&node).is_some()));
+}
+
+#[test]
+fn node_sim_readdress() {
+    let (_simulation, air) = sim_air();
+    air.connect(1, 2, LinkProfile::reliable());
+    let gateway = sim_node(&air, sim_settings(1, true));
+    let node = sim_node(&air, sim_settings(2, false));
+    assert!(sim_until(Duration::from_secs(10), || sim_address(&node).is_some()));
+    let old = sim_address(&node).unwrap();
+    drop(gateway);
+    air.disconnect(1, 2);
+
+    // a gateway with another subnet moves the node into it
+    air.connect(5, 2, LinkProfile::reliable());
+    let mut opt = sim_settings(5, true);
+    opt.meshprefix = Ipv4Addr::new(10,99,0,0);
+    let gateway = sim_node(&air, opt);
+    assert!(sim_until(Duration::from_secs(10), || sim_address(&node).map(|ip| ip != old).unwrap_or(false)));
+    let ipaddr = sim_address(&node).unwrap();
+    assert_eq!(ipaddr.octets()[..2], [10, 99]);
+    assert!(!node.tunnel.routes().contains_key(&old));
+    assert_eq!(node.tunnel.routes().get(&ipaddr), Some(&Ipv4Addr::new(10,107,1,3)));
+
+    // and the routes to the rest of the mesh go through the new address,
+    // the gateway's once its broadcast is heard, which may come after the lease
+    let gatewayip = Ipv4Addr::new(10,99,0,5);
+    assert!(sim_until(Duration::from_secs(10), || node.tunnel.routes().get(&gatewayip) == Some(&ipaddr)));
+    let routes = node.tunnel.routes();
+    assert!(routes.iter().all(|(dest, via)| *dest == ipaddr || *via == ipaddr));
+    assert!(sim_deliver(&node, &gateway, Duration::from_secs(10)));
+}
+
+#[cfg(test)]
+const SIM_NETWORKKEY: &str = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+
+#[test]
+fn node_sim_counter_resumes() {
+    let (_simulation, air) = sim_air();
+    let mut opt = sim_settings(2, false);
+    opt.networkkey = Some(SIM_NETWORKKEY.to_string());
+    let saved = GatewayState { counter: 5 * COUNTER_STEP, ..Default::default() };
+    saved.save(&opt.statefile).unwrap();
+    let statefile = opt.statefile.clone();
+
+    // the node saves a mark past the one it resumed from before sending
+    let _node = sim_node(&air, opt);
+    let state = GatewayState::load(&statefile).unwrap().unwrap();
+    assert!(state.counter >= 6 * COUNTER_STEP);
+}
+
+#[test]
+fn node_sim_signed_gateway() {
+    let (_simulation, air) = sim_air();
+    air.connect(1, 2, LinkProfile::reliable());
+    air.connect(2, 3, LinkProfile::reliable());
+    let secret = "9d61b19deffd5a60ba844af492ec2cc44449c5697b326919703bac031cae7f60";
+    let public = "d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a";
+    let mut opts = vec![sim_settings(1, true), sim_settings(2, false), sim_settings(3, false)];
+    for opt in opts.iter_mut() {
+        opt.networkkey = Some(SIM_NETWORKKEY.to_string());
+        opt.trustedgateways = vec![public.to_string()];
+    }
+    opts[0].gatewaykey = Some(secret.to_string());
+    // a gateway restarted with its clock behind the serials it signed with
+    let resumed = u64::MAX / 2;
+    GatewayState { serial: resumed, ..Default::default() }.save(&opts[0].statefile).unwrap();
+    let statefile = opts[0].statefile.clone();
+    let mut opts = opts.into_iter();
+    let _gateway = sim_node(&air, opts.next().unwrap());
+    let relay = sim_node(&air, opts.next().unwrap());
+    let node = sim_node(&air, opts.next().unwrap());
+
+    // its claims carry on from the saved serial and are believed, relayed or not
+    assert!(GatewayState::load(&statefile).unwrap().unwrap().serial > resumed + COUNTER_STEP);
+    assert!(sim_until(Duration::from_secs(10), || sim_address(&relay).is_some() && sim_address(&node).is_some()));
+}
+
+#[test]
+fn node_sim_counter_unreadable() {
+    let (_simulation, air) = sim_air();
+    let mut opt = sim_settings(2, false);
+    opt.networkkey = Some(SIM_NETWORKKEY.to_string());
+    std::fs::write(&opt.statefile, b"not json").unwrap();
+    let statefile = opt.statefile.clone();
+
+    // refuses to start rather than reuse counters
+    let mut node = MeshNode::new(2, VirtualTunnel::new(opt.tunaddr), air.attach(2), opt);
+    let stop = AtomicBool::new(true);
+    assert!(thread::spawn(move || node.run_until(&stop)).join().is_err());
+    let _ = std::fs::remove_file(&statefile);
+
+    // which nodes without a key shrug off
+    let mut opt = sim_settings(3, false);
+    std::fs::write(&opt.statefile, b"not json").unwrap();
+    opt.broadcastinterval = 600000;
+    let statefile = opt.statefile.clone();
+    let mut node = MeshNode::new(3, VirtualTunnel::new(opt.tunaddr), air.attach(3), opt);
+    let stop = AtomicBool::new(true);
+    assert!(thread::spawn(move || node.run_until(&stop)).join().is_ok());
+    let _ = std::fs::remove_file(&statefile);
+}
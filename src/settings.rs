@@ -1,14 +1,17 @@
 use config::{ConfigError, Config, File, Environment};
 use std::path::PathBuf;
+use std::net::Ipv4Addr;
 use serde::Deserialize;
+use crate::stack::{MeshSubnet, NodeId};
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct Settings {
     /// The ID of this LoRa node
     /* This sets the ID of the node, similar to a MAC address. This must be
-    between 1 and 255 otherwise the node will enter local test mode. It is recommended
-    you set the gateway as 1. */
-    pub nodeid: u8,
+    between 1 and 65535 otherwise the node will enter local test mode. It is recommended
+    you set the gateway as 1, a gateway's ID can't be all ones in the host bits of
+    the mesh subnet since that's the broadcast address. */
+    pub nodeid: NodeId,
 
     /// Activate debug mode
     // short and long flags (-d, --debug) will be deduced from the field's name
@@ -19,12 +22,61 @@ pub struct Settings {
     DHCP server and will assign IP addresses to other nodes in the mesh. */
     pub isgateway: bool,
 
+    /// Network the mesh addresses are taken from
+    /* A node's address is this prefix with its ID in the host bits. Only the
+    gateway's setting matters, the other nodes use the subnet it broadcasts.
+    It must be within a private network or the shared address space. */
+    pub meshprefix: Ipv4Addr,
+
+    /// Prefix length of the mesh network [8..30]
+    /* Node IDs that don't fit in the host bits can't be given an address. */
+    pub meshprefixlen: u8,
+
+    /// First address the gateway leases, the subnet's first host by default
+    pub leasestart: Option<Ipv4Addr>,
+
+    /// Last address the gateway leases, the subnet's last host by default
+    pub leaseend: Option<Ipv4Addr>,
+
+    /// Time (ms) a leased address is held for a node that stopped broadcasting it
+    /* Every broadcast carrying the address renews the lease, and it's released
+    as soon as the node expires. */
+    pub leasetime: u64,
+
+    /// IDs of the nodes the gateway won't lease an address to
+    pub leasedeny: Vec<NodeId>,
+
+    /// File gateways keep their leases and topology in across restarts, and every
+    /// node the mark its frame counter may run up to
+    /* It's replaced as a whole each time it's saved, so a crash can't leave
+    it half written. */
+    pub statefile: PathBuf,
+
+    /// Address of the local tunnel interface, mesh routes go through it
+    pub tunaddr: Ipv4Addr,
+
     /// Local device port for radio
     pub radioport: PathBuf,
 
     /// Radio initialization command file
     pub radiocfg: Option<PathBuf>,
 
+    /// Key shared by the mesh to encrypt frames, 32 bytes hex encoded
+    /* When set, frames are encrypted and authenticated with ChaCha20-Poly1305
+    and frames from nodes without the key are dropped. */
+    pub networkkey: Option<String>,
+
+    /// Gateway's Ed25519 secret key, 32 bytes hex encoded
+    /* Gateways sign their broadcasts and IP assignments with it, the public
+    key to give to the other nodes is logged at startup. */
+    pub gatewaykey: Option<String>,
+
+    /// Ed25519 public keys of the gateways to believe, 32 bytes hex encoded
+    /* When empty, any node claiming to be a gateway is believed, whether it
+    signed its claims or not, so a gatewaykey only protects nodes that list
+    its public key here. */
+    pub trustedgateways: Vec<String>,
+
     /// Maximum frame size sent to radio [10..250] (valid only for ping and kiss)
     pub maxpacketsize: usize,
 
@@ -33,9 +85,42 @@ pub struct Settings {
     /* The smaller the transmission slot, the more frequently transmissions will occur */
     pub txslot: u64,
 
+    /// Number of TDMA slots in a superframe, 0 disables slotted transmission
+    /* Each node transmits only in slot `nodeid % tdmaslots`, each slot lasting
+    `txslot` milliseconds. Nodes align their slots with the gateway. */
+    pub tdmaslots: u8,
+
+    /// Guard time (ms) left unused at the end of each TDMA slot
+    pub tdmaguard: u64,
+
+    /// Number of chunks from which a transfer is reserved with RTS/CTS, 0 disables
+    /* Neighbours of the receiver that overhear its confirmation stay quiet while
+    the chunks are sent, which avoids hidden terminal collisions. */
+    pub rtsthreshold: usize,
+
+    /// Times a transmit request is sent again if the receiver doesn't confirm it
+    /* A transfer that runs out of them is sent without a reservation. */
+    pub rtsretries: u8,
+
+    /// Times a frame is sent again if the next hop doesn't acknowledge it, 0 disables acknowledgements
+    /* Broadcasts and route discoveries are never acknowledged. A frame that
+    runs out of retries is reported as a route failure. */
+    pub arqretries: u8,
+
     /// Timeout (ms) to drop incomplete packet chunks
     pub chunktimeout: u64,
 
+    /// Timeout (ms) to wait for an on-demand route discovery before dropping packets
+    pub discoverytimeout: u64,
+
+    /// Time (ms) after which nodes that haven't been heard from are forgotten
+    pub nodetimeout: u64,
+
+    /// Average time (ms) between broadcasts
+    /* Each node picks its period within a third of this, so nodes that
+    started together don't keep broadcasting at the same time. */
+    pub broadcastinterval: u64,
+
     /// Maximum number of hops a packet should travel
     pub maxhops: u8,
 }
@@ -46,11 +131,30 @@ impl Settings {
         settings.set_default("nodeid", 0);
         settings.set_default("debug", false);
         settings.set_default("isgateway", false);
+        settings.set_default("meshprefix", "172.16.0.0");
+        settings.set_default("meshprefixlen", 16);
+        settings.set_default::<Option<&str>>("leasestart", None);
+        settings.set_default::<Option<&str>>("leaseend", None);
+        settings.set_default("leasetime", 3600000);
+        settings.set_default("leasedeny", Vec::<i64>::new());
+        settings.set_default("statefile", "/var/lib/loramesh/state.json");
+        settings.set_default("tunaddr", "10.107.1.3");
         settings.set_default("radioport", "/dev/ttyUSB0");
         settings.set_default::<Option<&str>>("radiocfg", None);
+        settings.set_default::<Option<&str>>("networkkey", None);
+        settings.set_default::<Option<&str>>("gatewaykey", None);
+        settings.set_default("trustedgateways", Vec::<String>::new());
         settings.set_default("maxpacketsize", 200);
         settings.set_default("txslot", 1000);
+        settings.set_default("tdmaslots", 0);
+        settings.set_default("tdmaguard", 100);
+        settings.set_default("rtsthreshold", 2);
+        settings.set_default("rtsretries", 2);
+        settings.set_default("arqretries", 3);
         settings.set_default("chunktimeout", 10000);
+        settings.set_default("discoverytimeout", 30000);
+        settings.set_default("nodetimeout", 600000);
+        settings.set_default("broadcastinterval", 60000);
         settings.set_default("maxhops", 2);
 
 
@@ -60,7 +164,19 @@ impl Settings {
         // Add in settings from the environment (with a prefix of APP)
         settings.merge(config::Environment::with_prefix("LOMESH")).unwrap();
 
-        settings.try_into()
+        let settings: Settings = settings.try_into()?;
+        settings.validate()?;
+        Ok(settings)
+    }
+
+    /// Check settings that depend on each other
+    fn validate(&self) -> Result<(), ConfigError> {
+        let subnet = MeshSubnet::new(self.meshprefix, self.meshprefixlen).map_err(|e| ConfigError::Message(e.to_string()))?;
+        // gateways take the address of their ID, the others are leased one
+        if self.isgateway && subnet.ipaddr(self.nodeid).is_none() {
+            return Err(ConfigError::Message(format!("Gateway node ID {} has no address in mesh subnet {}, it must be between 1 and {}", self.nodeid, subnet, (u32::MAX >> subnet.prefixlen()) - 1)));
+        }
+        Ok(())
     }
 }
 
@@ -71,8 +187,41 @@ fn settings_load() {
 
     assert_eq!(&opt.nodeid, &0);
     assert_eq!(&opt.isgateway, &false);
+    assert_eq!(&opt.meshprefix, &Ipv4Addr::new(172,16,0,0));
+    assert_eq!(&opt.meshprefixlen, &16);
+    assert_eq!(&opt.leasestart, &None);
+    assert_eq!(&opt.leasetime, &3600000);
+    assert!(opt.leasedeny.is_empty());
+    assert_eq!(&opt.statefile.to_str().unwrap(), &"/var/lib/loramesh/state.json");
+    assert_eq!(&opt.tunaddr, &Ipv4Addr::new(10,107,1,3));
     assert_eq!(&opt.radioport.to_str().unwrap(), &"/dev/ttyUSB0");
     assert_eq!(&opt.maxpacketsize, &200usize);
     assert_eq!(&opt.maxhops, &2);
     assert_eq!(&opt.radiocfg, &None);
-}
\ No newline at end of file
+    assert_eq!(&opt.networkkey, &None);
+    assert_eq!(&opt.gatewaykey, &None);
+    assert!(opt.trustedgateways.is_empty());
+    assert_eq!(&opt.tdmaslots, &0);
+    assert_eq!(&opt.broadcastinterval, &60000);
+}
+#[cfg(test)]
+#[test]
+fn settings_validate() {
+    let mut opt: Settings = Settings::new().expect("Error loading settings");
+    opt.isgateway = true;
+    opt.nodeid = 1;
+    assert!(opt.validate().is_ok());
+
+    // a gateway can't be the network or broadcast address of its subnet
+    opt.nodeid = 0;
+    assert!(opt.validate().is_err());
+    opt.nodeid = 65535;
+    assert!(opt.validate().is_err());
+    opt.meshprefix = Ipv4Addr::new(10,0,0,0);
+    opt.meshprefixlen = 8;
+    assert!(opt.validate().is_ok());
+
+    // and the subnet itself must be usable
+    opt.meshprefix = Ipv4Addr::new(8,0,0,0);
+    assert!(opt.validate().is_err());
+}
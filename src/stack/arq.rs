@@ -0,0 +1,296 @@
+use log::*;
+use std::collections::VecDeque;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::time::{Duration, Instant};
+use std::sync::{Arc, Mutex};
+use rand::{thread_rng, Rng};
+use crate::stack::{Frame, NodeId, ToFromFrame, AckMessage};
+
+/// Identifies a frame across hops: original sender, frame ID and chunk index
+pub type FrameKey = (NodeId, u8, u8);
+
+/// A frame sent to the next hop that wasn't acknowledged yet
+struct Unacked {
+    key: FrameKey,
+    /// the hop that has to acknowledge it, relays of the frame share its key
+    nexthop: NodeId,
+    data: Vec<u8>,
+    attempts: u8,
+    /// when to stop waiting, none while queued for retransmission
+    deadline: Option<Instant>,
+}
+
+struct ArqState {
+    /// acknowledgements and retransmissions waiting for the radio
+    outgoing: VecDeque<Vec<u8>>,
+    unacked: Vec<Unacked>,
+    /// frames we acknowledged recently and a digest of their payload, to drop retransmitted copies
+    seen: VecDeque<(FrameKey, u64, Instant)>,
+    /// frames the next hop never acknowledged
+    failed: Vec<Vec<u8>>,
+}
+
+/// Per-hop acknowledgements and retransmission of frames (ARQ)
+/* The radio loop hands every frame it transmits and receives to this.
+Frames whose message type asks for it are held until the next hop
+acknowledges them, and are sent again with an exponential backoff until
+they are, or the retries run out. The node collects frames that were
+never acknowledged to repair its routes. */
+#[derive(Clone)]
+pub struct LinkArq {
+    nodeid: NodeId,
+    timeout: Duration,
+    maxretries: u8,
+    state: Arc<Mutex<ArqState>>,
+}
+
+impl LinkArq {
+    pub fn new(nodeid: NodeId, timeout: Duration, maxretries: u8) -> Self {
+        LinkArq {
+            nodeid,
+            timeout,
+            maxretries,
+            state: Arc::new(Mutex::new(ArqState {
+                outgoing: VecDeque::new(),
+                unacked: Vec::new(),
+                seen: VecDeque::new(),
+                failed: Vec::new(),
+            })),
+        }
+    }
+
+    /// Frames are neither acknowledged nor retransmitted
+    pub fn disabled(nodeid: NodeId) -> Self {
+        LinkArq::new(nodeid, Duration::from_millis(0), 0)
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.maxretries > 0
+    }
+
+    /// Time to wait for an acknowledgement after the given number of
+    /// retransmissions, doubling each time with some jitter so senders
+    /// that collided don't collide again
+    fn backoff(&self, attempts: u8) -> Duration {
+        let jitter = thread_rng().gen_range(0, self.timeout.as_millis() as u64 / 2 + 1);
+        self.timeout * 2u32.pow(attempts.min(8) as u32) + Duration::from_millis(jitter)
+    }
+
+    /// How long a retransmitted copy of a frame may still arrive
+    fn window(&self) -> Duration {
+        self.timeout * 2u32.pow(self.maxretries.min(8) as u32 + 1)
+    }
+
+    /// Next frame the radio loop should send ahead of new frames,
+    /// acknowledgements first, then retransmissions that are due
+    pub fn next(&self) -> Option<Vec<u8>> {
+        if !self.enabled() { return None; }
+        let mut guard = self.state.lock().unwrap();
+        let state = &mut *guard;
+        let now = Instant::now();
+
+        let mut i = 0;
+        while i < state.unacked.len() {
+            let unacked = &mut state.unacked[i];
+            match unacked.deadline {
+                Some(deadline) if deadline <= now => {
+                    if unacked.attempts < self.maxretries {
+                        unacked.attempts += 1;
+                        unacked.deadline = None;
+                        debug!("Frame {:?} not acknowledged, retransmitting", &unacked.key);
+                        state.outgoing.push_back(unacked.data.clone());
+                    } else {
+                        warn!("Frame {:?} never acknowledged", &unacked.key);
+                        let unacked = state.unacked.remove(i);
+                        state.failed.push(unacked.data);
+                        continue;
+                    }
+                },
+                _ => {},
+            }
+            i += 1;
+        }
+
+        state.outgoing.pop_front()
+    }
+
+    /// Record a frame the radio just transmitted, frames that need an
+    /// acknowledgement are held until it arrives
+    pub fn sent(&self, data: &[u8]) {
+        if !self.enabled() { return; }
+        let mut frame = match Frame::from_bytes(&data.to_vec()) {
+            Err(_) => return,
+            Ok(frame) => frame,
+        };
+        let nexthop = match frame.nexthop() {
+            Some(nexthop) if frame.acknowledged() => nexthop,
+            _ => return,
+        };
+
+        let key = frame.key();
+        let mut state = self.state.lock().unwrap();
+        match state.unacked.iter_mut().find(|unacked| unacked.key == key && unacked.nexthop == nexthop) {
+            Some(unacked) => unacked.deadline = Some(Instant::now() + self.backoff(unacked.attempts)),
+            None => state.unacked.push(Unacked {
+                key,
+                nexthop,
+                data: Vec::from(data),
+                attempts: 0,
+                deadline: Some(Instant::now() + self.backoff(0)),
+            }),
+        }
+    }
+
+    /// Process a frame heard by the radio, returns false if it was meant
+    /// for the link layer only or is a copy of a frame we already got
+    pub fn received(&self, data: &[u8]) -> bool {
+        if !self.enabled() { return true; }
+        let mut frame = match Frame::from_bytes(&data.to_vec()) {
+            Err(_) => return true, // not ours to judge
+            Ok(frame) => frame,
+        };
+
+        // only the hop we sent a frame to can acknowledge it, acks
+        // overheard from hops up or down the route are for someone else
+        if frame.is_ack() {
+            if let Ok(ack) = AckMessage::from_frame(&mut frame) {
+                let mut state = self.state.lock().unwrap();
+                let key = ack.key();
+                let acker = frame.sender();
+                if state.unacked.iter().any(|unacked| unacked.key == key && unacked.nexthop == acker) {
+                    trace!("Frame {:?} acknowledged by {}", &key, &acker);
+                    state.unacked.retain(|unacked| unacked.key != key || unacked.nexthop != acker);
+                    // a retransmission may be waiting for the radio already
+                    state.outgoing.retain(|queued| match Frame::from_bytes(queued) {
+                        Ok(mut queued) => queued.is_ack() || queued.key() != key || queued.nexthop() != Some(acker),
+                        Err(_) => true,
+                    });
+                }
+            }
+            return false;
+        }
+        if !frame.acknowledged() || frame.nexthop() != Some(self.nodeid) {
+            return true;
+        }
+
+        // acknowledge every copy, our previous ack may have been lost
+        let key = frame.key();
+        let ack = AckMessage::new(key.0, key.1, key.2).to_frame(key.1, self.nodeid, Vec::new()).to_bytes();
+        let mut state = self.state.lock().unwrap();
+        state.outgoing.push_front(ack);
+
+        let now = Instant::now();
+        let window = self.window();
+        while state.seen.front().map(|(_, _, at)| now.duration_since(*at) > window).unwrap_or(false) {
+            state.seen.pop_front();
+        }
+        // frame IDs are reused, only the same payload makes it a copy
+        let digest = digest(&mut frame);
+        if state.seen.iter().any(|(seen, seendigest, _)| *seen == key && *seendigest == digest) {
+            debug!("Dropping retransmitted copy of frame {:?}", &key);
+            return false;
+        }
+        state.seen.push_back((key, digest, now));
+        true
+    }
+
+    /// Take the frames that were never acknowledged
+    pub fn failures(&self) -> Vec<Vec<u8>> {
+        self.state.lock().unwrap().failed.drain(..).collect()
+    }
+}
+
+/// Digest of a frame's payload, which retransmissions repeat unchanged
+fn digest(frame: &mut Frame) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    frame.payload().hash(&mut hasher);
+    hasher.finish()
+}
+
+#[cfg(test)]
+use crate::stack::{IPAssignSuccessMessage, BroadcastMessage};
+#[cfg(test)]
+use std::net::Ipv4Addr;
+#[cfg(test)]
+use std::thread;
+
+#[cfg(test)]
+#[test]
+fn arq_lifecycle() {
+    let sender = LinkArq::new(1, Duration::from_millis(5), 2);
+    let receiver = LinkArq::new(2, Duration::from_millis(5), 2);
+    let assign = IPAssignSuccessMessage::new(2, Ipv4Addr::new(172,16,0,2)).to_frame(9, 1, vec![2]).to_bytes();
+
+    // the receiver acknowledges, and drops the retransmitted copy
+    sender.sent(&assign);
+    assert!(receiver.received(&assign));
+    let ack = receiver.next().expect("Frame was not acknowledged");
+    assert!(!receiver.received(&assign));
+    assert_eq!(receiver.next(), Some(ack.clone()));
+
+    // the ack stops retransmissions
+    assert!(!sender.received(&ack));
+    assert_eq!(sender.next(), None);
+
+    // unacknowledged frames are retried, then reported
+    sender.sent(&assign);
+    for _ in 0..2 {
+        thread::sleep(Duration::from_millis(25));
+        assert_eq!(sender.next(), Some(assign.clone()));
+        sender.sent(&assign);
+    }
+    thread::sleep(Duration::from_millis(25));
+    assert_eq!(sender.next(), None);
+    assert_eq!(sender.failures(), vec![assign]);
+
+    // broadcasts are never acknowledged
+    let broadcast = BroadcastMessage { header: None, isgateway: false, ipOffset: 0, ipaddr: None, epoch: None, capabilities: None, subnet: None, serial: None, signature: None };
+    let bytes = broadcast.to_frame(1u8, 1, vec![1]).to_bytes();
+    sender.sent(&bytes);
+    assert!(receiver.received(&bytes));
+    assert_eq!(receiver.next(), None);
+    assert_eq!(sender.next(), None);
+}
+
+#[cfg(test)]
+#[test]
+fn arq_frameid_reuse() {
+    let receiver = LinkArq::new(2, Duration::from_millis(5), 2);
+    let first = IPAssignSuccessMessage::new(2, Ipv4Addr::new(172,16,0,2)).to_frame(9, 1, vec![2]).to_bytes();
+    let second = IPAssignSuccessMessage::new(2, Ipv4Addr::new(172,16,0,3)).to_frame(9, 1, vec![2]).to_bytes();
+
+    // another frame that happens to get the same ID isn't a copy
+    assert!(receiver.received(&first));
+    assert!(receiver.received(&second));
+    assert!(!receiver.received(&second));
+    assert!(!receiver.received(&first));
+}
+
+#[cfg(test)]
+#[test]
+fn arq_overheard_ack() {
+    // 1 sends to 2, which relays to 3, which relays to 4, all under the same key
+    let upstream = LinkArq::new(1, Duration::from_millis(5), 2);
+    let relay = LinkArq::new(2, Duration::from_millis(5), 2);
+    let downstream = LinkArq::new(3, Duration::from_millis(5), 2);
+    let assign = IPAssignSuccessMessage::new(4, Ipv4Addr::new(172,16,0,4));
+    let first = assign.to_frame(9, 1, vec![2, 3, 4]).to_bytes();
+    let last = assign.to_frame(9, 1, vec![4]).to_bytes();
+    upstream.sent(&first);
+    downstream.sent(&last);
+
+    // 3 overhears 2 acknowledging the first hop, 4 hasn't acknowledged anything
+    assert!(relay.received(&first));
+    let ack = relay.next().expect("Frame was not acknowledged");
+    assert!(!upstream.received(&ack));
+    assert!(!downstream.received(&ack));
+    assert_eq!(upstream.next(), None);
+    thread::sleep(Duration::from_millis(25));
+    assert_eq!(downstream.next(), Some(last.clone()));
+
+    // until it does
+    let ack = AckMessage::new(1, 9, 0).to_frame(9, 4, Vec::new()).to_bytes();
+    assert!(!downstream.received(&ack));
+    assert_eq!(downstream.next(), None);
+}
@@ -0,0 +1,74 @@
+use crate::error::FrameError;
+use crate::stack::frame::PROTOCOL_VERSION;
+
+/// Feature: LZ4 compressed frame payloads are understood
+pub const FEATURE_LZ4: u8 = 0x01;
+/// Feature: IPv4 packets with compressed headers are understood
+pub const FEATURE_IPHC: u8 = 0x02;
+
+/// Bytes capabilities take up in a broadcast
+pub const CAPABILITIES_LEN: usize = 3;
+
+/// Protocol versions and optional features a node understands
+/* Nodes advertise these in their broadcasts. Senders only use a feature
+on a frame to a neighbour that advertised it, and fall back to the plain
+encoding otherwise, so nodes can be upgraded one at a time. Neighbours we
+haven't heard capabilities from are assumed to understand none. */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Capabilities {
+    pub minversion: u8,
+    pub maxversion: u8,
+    pub features: u8,
+}
+
+impl Capabilities {
+    /// What this node understands
+    pub fn ours() -> Self {
+        Capabilities { minversion: PROTOCOL_VERSION, maxversion: PROTOCOL_VERSION, features: FEATURE_LZ4 | FEATURE_IPHC }
+    }
+
+    /// Whether frames of a protocol version are understood
+    pub fn speaks(&self, version: u8) -> bool {
+        self.minversion <= version && version <= self.maxversion
+    }
+
+    /// Whether an optional feature is understood
+    pub fn supports(&self, feature: u8) -> bool {
+        self.features & feature == feature
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        vec![self.minversion, self.maxversion, self.features]
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, FrameError> {
+        match bytes {
+            [minversion, maxversion, features, ..] => Ok(Capabilities { minversion: *minversion, maxversion: *maxversion, features: *features }),
+            _ => Err(FrameError::Truncated("capabilities")),
+        }
+    }
+}
+
+impl Default for Capabilities {
+    /// A neighbour that didn't say, only the plain encoding of our version is safe
+    fn default() -> Self {
+        Capabilities { minversion: PROTOCOL_VERSION, maxversion: PROTOCOL_VERSION, features: 0u8 }
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn capabilities_negotiation() {
+    let ours = Capabilities::ours();
+    assert!(ours.speaks(PROTOCOL_VERSION));
+    assert!(!ours.speaks(PROTOCOL_VERSION + 1));
+    assert!(ours.supports(FEATURE_LZ4 | FEATURE_IPHC));
+    assert_eq!(Capabilities::from_bytes(&ours.to_bytes()).unwrap(), ours);
+    assert!(Capabilities::from_bytes(&[1u8, 1u8]).is_err());
+
+    // unknown neighbours get the plain encoding
+    let unknown = Capabilities::default();
+    assert!(unknown.speaks(PROTOCOL_VERSION));
+    assert!(!unknown.supports(FEATURE_LZ4));
+    assert!(!unknown.supports(FEATURE_IPHC));
+}
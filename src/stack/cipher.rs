@@ -0,0 +1,235 @@
+use log::*;
+use std::io::{Error, ErrorKind};
+use std::convert::TryInto;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use crate::stack::frame::{header_len, NodeId};
+use crate::stack::replay::ReplayWindows;
+
+/// Bytes of the transmitter ID and counter sent with each frame
+pub const ENVELOPE_LEN: usize = 10;
+/// Bytes of the authentication tag sent with each frame
+pub const TAG_LEN: usize = 16;
+
+/// Nonce unique to a transmission, the transmitter's ID and counter
+fn nonce(transmitter: NodeId, counter: u64) -> [u8; 12] {
+    let mut nonce = [0u8; 12];
+    nonce[0..2].copy_from_slice(&transmitter.to_be_bytes());
+    nonce[4..].copy_from_slice(&counter.to_be_bytes());
+    nonce
+}
+
+/// Encrypts frames going on air with a key shared by the whole mesh
+/* Frames are sealed with ChaCha20-Poly1305 right before the radio sends
+them and opened as soon as they are received. The header stays readable
+and is authenticated as associated data, so each relay re-seals the frame
+after updating its route. Frames that fail authentication never leave
+the radio loop. Without a key, frames are sent as plaintext.
+
+Each transmission carries our node ID and a counter, authenticated along
+with the header, which make up the nonce and let receivers reject replayed
+frames. The counter starts from the clock in microseconds and the node
+resumes it from the high-water mark it saved before it restarted, only
+sealing frames below the mark it saved last, so nonces are never reused
+with the same key even if the clock goes back. */
+#[derive(Clone)]
+pub struct FrameCipher {
+    nodeid: NodeId,
+    aead: Option<ChaCha20Poly1305>,
+    counter: Arc<AtomicU64>,
+    /// counters from here on weren't saved yet and can't be used
+    limit: Arc<AtomicU64>,
+    replay: Arc<Mutex<ReplayWindows>>,
+}
+
+impl FrameCipher {
+    /// A cipher for this node using the 256 bit key, hex encoded
+    pub fn new(nodeid: NodeId, hexkey: &str) -> std::io::Result<Self> {
+        let key = hex::decode(hexkey.trim()).map_err(|_| Error::new(ErrorKind::InvalidInput, "Network key is not valid hex"))?;
+        if key.len() != 32 {
+            return Err(Error::new(ErrorKind::InvalidInput, "Network key must be 32 bytes"));
+        }
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map_err(|_| Error::new(ErrorKind::Other, "Clock is before 1970"))?;
+        Ok(FrameCipher {
+            nodeid,
+            aead: Some(ChaCha20Poly1305::new(Key::from_slice(&key))),
+            counter: Arc::new(AtomicU64::new(now.as_micros() as u64)),
+            limit: Arc::new(AtomicU64::new(u64::MAX)),
+            replay: Arc::new(Mutex::new(ReplayWindows::new())),
+        })
+    }
+
+    /// Frames are sent and accepted in the clear
+    pub fn plaintext() -> Self {
+        FrameCipher {
+            nodeid: 0,
+            aead: None,
+            counter: Arc::new(AtomicU64::new(0)),
+            limit: Arc::new(AtomicU64::new(u64::MAX)),
+            replay: Arc::new(Mutex::new(ReplayWindows::new())),
+        }
+    }
+
+    /// Use the network key from the settings, if one is set
+    pub fn from_key(nodeid: NodeId, hexkey: &Option<String>) -> std::io::Result<Self> {
+        match hexkey {
+            None => Ok(FrameCipher::plaintext()),
+            Some(key) => FrameCipher::new(nodeid, key),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.aead.is_some()
+    }
+
+    /// Bytes added to every frame
+    pub fn overhead(&self) -> usize {
+        if self.enabled() { ENVELOPE_LEN + TAG_LEN } else { 0 }
+    }
+
+    /// Counter the next frame will be sealed with
+    pub fn counter(&self) -> u64 {
+        self.counter.load(Ordering::SeqCst)
+    }
+
+    /// Counters the cipher may still use before the limit
+    pub fn remaining(&self) -> u64 {
+        self.limit.load(Ordering::SeqCst).saturating_sub(self.counter())
+    }
+
+    /// Continue from a counter saved before we restarted, if it's ahead of ours
+    pub fn resume(&self, counter: u64) {
+        self.counter.fetch_max(counter, Ordering::SeqCst);
+    }
+
+    /// Only seal frames with counters below `limit`, once it's saved
+    pub fn reserve(&self, limit: u64) {
+        self.limit.store(limit, Ordering::SeqCst);
+    }
+
+    /// Encrypt the payload of a frame, authenticating its header
+    pub fn seal(&self, frame: &[u8]) -> std::io::Result<Vec<u8>> {
+        let aead = match &self.aead {
+            None => return Ok(Vec::from(frame)),
+            Some(aead) => aead,
+        };
+        let (header, payload) = frame.split_at(header_len(frame).ok_or(ErrorKind::InvalidData)?);
+
+        let counter = self.counter.fetch_add(1, Ordering::SeqCst);
+        if counter >= self.limit.load(Ordering::SeqCst) {
+            return Err(Error::new(ErrorKind::Other, "Frame counter ran past the saved high-water mark"));
+        }
+        let mut bytes = Vec::from(header);
+        bytes.extend_from_slice(&self.nodeid.to_be_bytes());
+        bytes.extend_from_slice(&counter.to_be_bytes());
+
+        let sealed = aead.encrypt(Nonce::from_slice(&nonce(self.nodeid, counter)), Payload { msg: payload, aad: &bytes })
+            .map_err(|_| Error::new(ErrorKind::Other, "Failed to encrypt frame"))?;
+        bytes.extend(sealed);
+        Ok(bytes)
+    }
+
+    /// Decrypt and authenticate a received frame, frames that
+    /// were already received or are too old are rejected
+    pub fn open(&self, data: &[u8]) -> std::io::Result<Vec<u8>> {
+        let aead = match &self.aead {
+            None => return Ok(Vec::from(data)),
+            Some(aead) => aead,
+        };
+        let headerlen = header_len(data).ok_or(ErrorKind::InvalidData)?;
+        if data.len() < headerlen + ENVELOPE_LEN + TAG_LEN {
+            return Err(Error::new(ErrorKind::InvalidData, "Frame too short to be sealed"));
+        }
+        let (envelope, ciphertext) = data.split_at(headerlen + ENVELOPE_LEN);
+        let (header, transmitter) = envelope.split_at(headerlen);
+        let counter = u64::from_be_bytes(transmitter[2..].try_into().unwrap());
+        let transmitter = NodeId::from_be_bytes(transmitter[..2].try_into().unwrap());
+        if transmitter == self.nodeid {
+            return Err(Error::new(ErrorKind::InvalidData, "Frame claims to be our own transmission"));
+        }
+
+        let payload = aead.decrypt(Nonce::from_slice(&nonce(transmitter, counter)), Payload { msg: ciphertext, aad: envelope })
+            .map_err(|_| Error::new(ErrorKind::InvalidData, "Frame failed authentication"))?;
+        self.replay.lock().unwrap().check(transmitter, counter)?;
+        trace!("Opened frame {} from node {} with {} byte payload", &counter, &transmitter, &payload.len());
+
+        let mut bytes = Vec::from(header);
+        bytes.extend(payload);
+        Ok(bytes)
+    }
+}
+
+#[cfg(test)]
+use crate::stack::{ToFromFrame, IPAssignSuccessMessage};
+#[cfg(test)]
+use std::net::Ipv4Addr;
+
+#[cfg(test)]
+#[test]
+fn cipher_seal_open() {
+    let key = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+    let cipher = FrameCipher::new(1, key).unwrap();
+    let receiver = FrameCipher::new(2, key).unwrap();
+    let frame = IPAssignSuccessMessage::new(2, Ipv4Addr::new(172,16,0,2)).to_frame(9, 1, vec![2]).to_bytes();
+
+    // the payload is hidden, the header isn't
+    let sealed = cipher.seal(&frame).unwrap();
+    assert_eq!(sealed.len(), frame.len() + cipher.overhead());
+    assert_eq!(&sealed[..9], &frame[..9]);
+    assert!(!sealed.ends_with(&frame[9..]));
+
+    // tampering with the header, counter or payload, or another key, fails authentication
+    let mut rerouted = sealed.clone();
+    rerouted[8] = 3u8;
+    assert!(receiver.open(&rerouted).is_err());
+    let mut recounted = sealed.clone();
+    recounted[17] ^= 1;
+    assert!(receiver.open(&recounted).is_err());
+    let mut forged = sealed.clone();
+    let last = forged.len() - 1;
+    forged[last] ^= 1;
+    assert!(receiver.open(&forged).is_err());
+    let other = FrameCipher::new(2, &key.replace("00", "ff")).unwrap();
+    assert!(other.open(&sealed).is_err());
+
+    // the genuine frame opens, once
+    assert_eq!(receiver.open(&sealed).unwrap(), frame);
+    assert!(receiver.open(&sealed).is_err());
+    assert_eq!(receiver.open(&cipher.seal(&frame).unwrap()).unwrap(), frame);
+
+    // our own frames reflected back aren't accepted
+    assert!(cipher.open(&cipher.seal(&frame).unwrap()).is_err());
+
+    // plaintext frames aren't accepted either
+    assert!(receiver.open(&frame).is_err());
+    assert!(FrameCipher::new(1, "abcd").is_err());
+    assert_eq!(FrameCipher::plaintext().open(&frame).unwrap(), frame);
+}
+
+#[cfg(test)]
+#[test]
+fn cipher_counter_limit() {
+    let key = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+    let cipher = FrameCipher::new(1, key).unwrap();
+    let frame = IPAssignSuccessMessage::new(2, Ipv4Addr::new(172,16,0,2)).to_frame(9, 1, vec![2]).to_bytes();
+
+    // a saved counter ahead of the clock is resumed, one behind it isn't
+    let start = cipher.counter();
+    cipher.resume(start - 100);
+    assert_eq!(cipher.counter(), start);
+    cipher.resume(start + 100);
+    assert_eq!(cipher.counter(), start + 100);
+
+    // counters past the reserved ones are never used
+    cipher.reserve(start + 102);
+    assert_eq!(cipher.remaining(), 2);
+    assert!(cipher.seal(&frame).is_ok());
+    assert!(cipher.seal(&frame).is_ok());
+    assert!(cipher.seal(&frame).is_err());
+    assert_eq!(cipher.remaining(), 0);
+    cipher.reserve(start + 200);
+    assert!(cipher.seal(&frame).is_ok());
+}
@@ -0,0 +1,40 @@
+use crate::error::FrameError;
+
+/// Largest payload a compressed frame may expand to, an IPv4 packet
+const MAX_DECOMPRESSED_LEN: usize = 65535;
+/// Most an LZ4 block can expand by
+const MAX_RATIO: usize = 255;
+
+/// LZ4 compress a payload, only if that makes it smaller
+pub fn compress(payload: &[u8]) -> Option<Vec<u8>> {
+    if payload.is_empty() { return None; }
+    let compressed = lz4_flex::block::compress(payload);
+    if compressed.len() < payload.len() {
+        Some(compressed)
+    } else {
+        None
+    }
+}
+
+/// Expand an LZ4 compressed payload
+pub fn decompress(data: &[u8]) -> Result<Vec<u8>, FrameError> {
+    let bound = (data.len() * MAX_RATIO).min(MAX_DECOMPRESSED_LEN);
+    lz4_flex::block::decompress(data, bound)
+        .map_err(|e| FrameError::Compression(e.to_string()))
+}
+
+#[cfg(test)]
+#[test]
+fn compression_roundtrip() {
+    let payload = vec![0u8; 120];
+    let compressed = compress(&payload).expect("Repetitive payload did not compress");
+    assert!(compressed.len() < payload.len());
+    assert_eq!(decompress(&compressed).unwrap(), payload);
+
+    // payloads that don't shrink are left alone
+    assert_eq!(compress(&[1u8, 2, 3, 4]), None);
+    assert_eq!(compress(&[]), None);
+
+    // garbage is rejected
+    assert!(decompress(&[0xf0u8, 1, 2]).is_err());
+}
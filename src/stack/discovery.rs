@@ -0,0 +1,110 @@
+use log::*;
+use std::net::Ipv4Addr;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use packet::ip::v4::Packet;
+use crate::stack::NodeId;
+
+/// Most packets held for a single destination while discovering its route
+const MAX_QUEUED_PACKETS: usize = 16;
+
+/// Packets waiting for a route to their destination
+struct PendingRoute {
+    started: Instant,
+    packets: Vec<Packet<Vec<u8>>>,
+}
+
+/// State of on-demand route discoveries
+/* When the router has no path to a destination, the packets are held here
+while a RouteDiscovery floods the mesh. They are released once the target
+answers with a RouteSuccess, or dropped when the discovery times out. */
+pub struct RouteDiscovery {
+    pending: HashMap<Ipv4Addr, PendingRoute>,
+    seen: HashMap<(NodeId, u8), Instant>,
+    timeout: Duration,
+}
+
+impl RouteDiscovery {
+    pub fn new(timeout: Duration) -> Self {
+        RouteDiscovery {
+            pending: HashMap::new(),
+            seen: HashMap::new(),
+            timeout,
+        }
+    }
+
+    /// Hold a packet until its destination is found, returns true
+    /// if a new discovery needs to be started for it
+    pub fn queue(&mut self, packet: Packet<Vec<u8>>) -> bool {
+        let dest = packet.destination();
+        match self.pending.get_mut(&dest) {
+            Some(pending) => {
+                if pending.packets.len() < MAX_QUEUED_PACKETS {
+                    pending.packets.push(packet);
+                } else {
+                    trace!("Discovery queue for {} is full, dropping packet", &dest);
+                }
+                false
+            },
+            None => {
+                self.pending.insert(dest, PendingRoute { started: Instant::now(), packets: vec![packet] });
+                true
+            }
+        }
+    }
+
+    /// A route to the target was found, release its packets
+    pub fn resolve(&mut self, target: &Ipv4Addr) -> Vec<Packet<Vec<u8>>> {
+        self.pending.remove(target).map(|p| p.packets).unwrap_or_default()
+    }
+
+    /// Check if a discovery request was already handled, remembering it if not
+    pub fn seen(&mut self, origin: NodeId, requestid: u8) -> bool {
+        let now = Instant::now();
+        let timeout = self.timeout;
+        self.seen.retain(|_, at| now.duration_since(*at) < timeout);
+        self.seen.insert((origin, requestid), now).is_some()
+    }
+
+    /// Give up on discoveries that weren't answered in time, returns
+    /// each destination with the number of packets dropped
+    pub fn expired(&mut self) -> Vec<(Ipv4Addr, usize)> {
+        let now = Instant::now();
+        let timeout = self.timeout;
+        let expired: Vec<Ipv4Addr> = self.pending.iter()
+            .filter(|(_, p)| now.duration_since(p.started) >= timeout)
+            .map(|(dest, _)| *dest)
+            .collect();
+
+        expired.into_iter()
+            .map(|dest| {
+                let dropped = self.pending.remove(&dest).unwrap().packets.len();
+                (dest, dropped)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn discovery_queue() {
+    let raw = hex::decode("45000023180440004011caa1ac100000ac100004e6ba0bb8000ff4914142433132330a").unwrap();
+    let dest = Ipv4Addr::new(172,16,0,4);
+    let mut discovery = RouteDiscovery::new(Duration::from_millis(0));
+
+    // the first packet starts a discovery, the next ones wait with it
+    assert!(discovery.queue(Packet::new(raw.clone()).unwrap()));
+    assert!(!discovery.queue(Packet::new(raw.clone()).unwrap()));
+    assert_eq!(discovery.resolve(&dest).len(), 2);
+    assert!(discovery.resolve(&dest).is_empty());
+
+    // unanswered discoveries drop their packets
+    discovery.queue(Packet::new(raw.clone()).unwrap());
+    assert_eq!(discovery.expired(), vec![(dest, 1)]);
+
+    // floods are only handled once
+    let mut discovery = RouteDiscovery::new(Duration::from_secs(60));
+    assert!(!discovery.seen(1, 7));
+    assert!(discovery.seen(1, 7));
+    assert!(!discovery.seen(2, 7));
+}
@@ -1,11 +1,42 @@
 use crate::stack::message::*;
 use enumn::N;
 use crate::stack::chunk::chunk_data;
-use std::io::ErrorKind;
+use crate::stack::compression::{compress, decompress};
+use crate::error::FrameError;
 use packet::ip::v4::Packet;
 
+/// Version of the frame format this node speaks, the first byte of every frame
+/* Bump it whenever the header or a message changes in a way older nodes
+would misread. Receivers drop frames of other versions rather than guess. */
+pub const PROTOCOL_VERSION: u8 = 2;
+
+/// Bytes of the header before the sender and route
+const HEADER_LEN: usize = 7;
+
+/// Identifies a node in the mesh
+pub type NodeId = u16;
+
+/// Set in the txflag byte when the payload is LZ4 compressed
+/* Frames asked to with `set_compress`, for a next hop that advertised
+FEATURE_LZ4, are compressed as they're turned into bytes, only if that
+makes them smaller, and expanded again as they're parsed. Everything else,
+broadcasts and control frames included, goes out plain. A chunked frame is
+compressed as a whole, so its chunks stay compressed until recombined. */
+pub const FLAG_COMPRESSED: u8 = 0x80;
+
+/// Set in the txflag byte when node IDs in the header take 2 bytes
+/* The sender and route are sent 1 byte per node ID when all of them fit,
+so meshes of up to 256 nodes and short routes between low IDs cost no
+more airtime than before node IDs grew to 16 bits. */
+pub const FLAG_WIDE_IDS: u8 = 0x40;
+
+/// Bits of the txflag byte that aren't the transmission state
+const FLAGS: u8 = FLAG_COMPRESSED | FLAG_WIDE_IDS;
+
 /// Defines continuity in current transmission
-#[derive(Clone, PartialEq, Debug, N)]
+/* SlotExceeded marks the last chunk a sender could fit in its transmit
+slot, the rest of the frame follows in its next slot. */
+#[derive(Clone, Copy, PartialEq, Debug, N)]
 pub enum TransmissionState {
     FinalChunk = 0,
     MoreChunks = 1,
@@ -14,7 +45,7 @@ pub enum TransmissionState {
 
 impl TransmissionState {
     /// convert txflag to byte
-    pub fn to_u8(&self) -> u8 {
+    pub fn to_u8(self) -> u8 {
         match self {
             TransmissionState::FinalChunk => 0u8,
             TransmissionState::MoreChunks => 1u8,
@@ -37,150 +68,225 @@ impl TransmissionState {
 pub struct FrameHeader {
     txflag: TransmissionState,
     frameid: u8,
+    chunk: u8,
+    chunks: u8,
     msgtype: MessageType,
-    sender: u8,
+    sender: NodeId,
     routeoffset: usize,
-    route: Vec<u8>,
+    route: Vec<NodeId>,
 }
 
 impl FrameHeader {
     /// constructor
-    pub fn new(txflag: TransmissionState, frameid: u8, msgtype: MessageType, sender: u8, route: Vec<u8>) -> Self {
-        FrameHeader{txflag, frameid, msgtype, sender, routeoffset: route.len(), route}
+    pub fn new(txflag: TransmissionState, frameid: u8, msgtype: MessageType, sender: NodeId, route: Vec<NodeId>) -> Self {
+        FrameHeader{txflag, frameid, chunk: 0, chunks: 1, msgtype, sender, routeoffset: route.len(), route}
     }
 
     /// convert a packet to bytes
     pub fn bytes(&mut self) -> Vec<u8> {
+        let wide = self.sender > 0xff || self.route.iter().any(|n| *n > 0xff);
         let mut bytes = Vec::new();
-        bytes.push(self.txflag.to_u8());
+        bytes.push(PROTOCOL_VERSION);
+        let txflag = self.txflag.to_u8();
+        bytes.push(if wide { txflag | FLAG_WIDE_IDS } else { txflag });
         bytes.push(self.frameid);
+        bytes.push(self.chunk);
+        bytes.push(self.chunks);
         bytes.push(self.msgtype.to_u8());
-        bytes.push(self.sender.clone());
         bytes.push(self.routeoffset.clone() as u8);
-        self.route.iter().for_each(|n| bytes.push(n.clone() as u8));
+        for n in std::iter::once(&self.sender).chain(self.route.iter()) {
+            if wide {
+                bytes.extend_from_slice(&n.to_be_bytes());
+            } else {
+                bytes.push(*n as u8);
+            }
+        }
 
         return bytes;
     }
 
-    pub fn sender(&mut self) -> u8 {
+    pub fn sender(&mut self) -> NodeId {
         return self.sender;
     }
 
-    pub fn route(&mut self) -> Vec<u8> {
+    pub fn route(&mut self) -> Vec<NodeId> {
         return self.route.clone();
     }
-
-    pub fn route_bytes(&mut self) -> Vec<u8> {
-        return self.route.clone().iter().map(|byte| byte.clone()).collect();
-    }
 }
 
 /// A simple packet indicating the sender, message type, and transmission state
 #[derive(Clone)]
 pub struct Frame {
-    txflag: u8, // indicates if chunked
+    txflag: TransmissionState, // indicates if chunked
     frameid: u8, // prevent collisions on chunking
-    msgtype: u8, // a flag for message type
-    sender: u8, // which node ID sent this frame?
+    chunk: u8, // index of this chunk within the frame
+    chunks: u8, // how many chunks the frame was split into
+    msgtype: MessageType, // a flag for message type
+    sender: NodeId, // which node ID sent this frame?
     routeoffset: u8, // size of array of route for frame
-    route: Vec<u8>, // a list of node IDs that frame should pass
+    route: Vec<NodeId>, // a list of node IDs that frame should pass
     payload: Vec<u8>, // payload data
+    compressed: bool, // payload is a compressed chunk awaiting recombination
+    compress: bool, // compress the payload on air, set once the next hop is known to understand LZ4
 }
 
 impl Frame {
     /// public construct for Frame
-    pub fn new(txflag: u8, frameid: u8, msgtype: u8, sender: u8, routeoffset: u8, route: Vec<u8>, payload: Vec<u8>) -> Self {
-        Frame {txflag, frameid, msgtype, sender, routeoffset, route, payload }
+    pub fn new(txflag: TransmissionState, frameid: u8, msgtype: MessageType, sender: NodeId, routeoffset: u8, route: Vec<NodeId>, payload: Vec<u8>) -> Self {
+        Frame {txflag, frameid, chunk: 0, chunks: 1, msgtype, sender, routeoffset, route, payload, compressed: false, compress: false }
     }
 
     /// construct a frame from a header and payload
     pub fn from_header(mut header: FrameHeader, payload: Vec<u8>) -> Self {
         Frame{
-            txflag: header.txflag.to_u8(),
+            txflag: header.txflag,
             frameid: header.frameid,
-            msgtype: header.msgtype.to_u8(),
+            chunk: header.chunk,
+            chunks: header.chunks,
+            msgtype: header.msgtype,
             sender: header.sender,
             routeoffset: header.routeoffset as u8,
-            route: header.route_bytes(),
-            payload
+            route: header.route(),
+            payload,
+            compressed: false,
+            compress: false
+        }
+    }
+
+    /// the payload as sent on air, and whether it was compressed
+    fn wire_payload(&self) -> (Vec<u8>, bool) {
+        if self.compressed {
+            return (self.payload.clone(), true);
+        }
+        if !self.compress {
+            return (self.payload.clone(), false);
+        }
+        match compress(&self.payload) {
+            Some(compressed) => (compressed, true),
+            None => (self.payload.clone(), false),
         }
     }
 
     /// convert a frame to bytes
     pub fn to_bytes(&mut self) -> Vec<u8> {
-        let mut bytes = Vec::new();
-        bytes.push(self.txflag);
-        bytes.push(self.frameid);
-        bytes.push(self.msgtype);
-        bytes.push(self.sender);
-        bytes.push(self.routeoffset);
-        self.route.iter().for_each(|n| bytes.push(n.clone()));
+        let (payload, compressed) = self.wire_payload();
+        let mut bytes = self.header().bytes();
+        if compressed {
+            bytes[1] |= FLAG_COMPRESSED;
+        }
 
         // push data, if any
-        self.payload.iter().for_each(|d| bytes.push(d.clone()));
+        bytes.extend(payload);
 
         return bytes;
     }
 
-    /// parse from raw bytes
-    pub fn from_bytes(bytes: &Vec<u8>) -> std::io::Result<Self> {
-        let txflag = bytes.get(0).ok_or(ErrorKind::InvalidData)?.clone();
-        let frameid = bytes.get(1).ok_or(ErrorKind::InvalidData)?.clone();
-        let msgtype = bytes.get(2).ok_or(ErrorKind::InvalidData)?.clone();
-        let sender = bytes.get(3).ok_or(ErrorKind::InvalidData)?.clone();
-        let routeoffset = bytes.get(4).ok_or(ErrorKind::InvalidData)?.clone();
-        let routes = bytes.get(5..(5+routeoffset as usize)).ok_or(ErrorKind::InvalidData)?;
-        let (_left, right) = bytes.split_at(5+routeoffset as usize);
+    /// parse from raw bytes, validating the whole header
+    pub fn from_bytes(bytes: &Vec<u8>) -> Result<Self, FrameError> {
+        check_version(bytes)?;
+        let header = bytes.get(0..HEADER_LEN).ok_or(FrameError::Truncated("header"))?;
+        let flags = header[1];
+        let txflag = TransmissionState::n(flags & !FLAGS)
+            .ok_or(FrameError::UnknownTransmissionState(flags & !FLAGS))?;
+        let frameid = header[2];
+        let chunk = header[3];
+        let chunks = header[4];
+        let msgtype = MessageType::n(header[5]).ok_or(FrameError::UnknownMessageType(header[5]))?;
+        let routeoffset = header[6];
+        let width = id_width(flags);
+        let sender = bytes.get(HEADER_LEN..(HEADER_LEN+width)).ok_or(FrameError::Truncated("sender"))?;
+        let sender = parse_ids(sender, width)[0];
+        let len = header_len(bytes).ok_or(FrameError::Truncated("route"))?;
+        let route = parse_ids(&bytes[(HEADER_LEN+width)..len], width);
+        let (_left, right) = bytes.split_at(len);
+
+        // only the last chunk of a frame says no more chunks follow
+        if chunk >= chunks || txflag.more_chunks() != (chunk + 1 < chunks) {
+            return Err(FrameError::InvalidChunk { chunk, chunks });
+        }
+
+        // a whole frame can be expanded now, chunks only once recombined
+        let mut compressed = flags & FLAG_COMPRESSED != 0;
+        let mut payload = Vec::from(right);
+        if compressed && chunks <= 1 {
+            payload = decompress(&payload)?;
+            compressed = false;
+        }
 
         Ok(Frame {
             txflag,
             frameid,
+            chunk,
+            chunks,
             msgtype,
             sender,
             routeoffset,
-            route: Vec::from(routes),
-            payload: Vec::from(right)
+            route,
+            payload,
+            compressed,
+            compress: false
         })
     }
 
+    /// whether to compress the payload on air, for next hops
+    /// that don't understand compressed frames
+    pub fn set_compress(&mut self, compress: bool) {
+        self.compress = compress;
+    }
+
     /// remove the next hop in the route, and return the hop ID
     /// this is useful for message passing
-    pub fn route_shift(&mut self) -> Option<u8> {
+    pub fn route_shift(&mut self) -> Option<NodeId> {
+        if self.route.is_empty() { return None; }
         self.routeoffset -= 1;
-        let shift = self.route.drain(0..1);
-        return shift.last();
+        Some(self.route.remove(0))
     }
 
     /// insert a hop at the beginning of the route
     /// useful for when a message is rebroadcasted
-    pub fn route_unshift(&mut self, nodeid: u8) {
+    pub fn route_unshift(&mut self, nodeid: NodeId) {
         self.route.insert(0, nodeid);
         self.routeoffset += 1;
     }
 
-    /// chunk a frame into multiple frames
-    pub fn chunked(&mut self, chunksize: &usize) -> Vec<Vec<u8>> {
-        let payloadchunks = chunk_data(self.payload.clone(), chunksize);
+    /// chunk a frame into multiple frames, fails if it
+    /// needs more chunks than the header can count
+    pub fn chunked(&mut self, chunksize: &usize) -> Result<Vec<Vec<u8>>, FrameError> {
+        let (payload, compressed) = self.wire_payload();
+        let payloadchunks = chunk_data(payload, chunksize);
+        if payloadchunks.len() > u8::MAX as usize {
+            return Err(FrameError::TooManyChunks(payloadchunks.len()));
+        }
 
         // add header data to each frame
         let mut chunks: Vec<Vec<u8>> = Vec::new();
         for (i, datachunk) in payloadchunks.iter().enumerate() {
-            let mut chunk = self.header().bytes().clone();
-            chunk.extend(datachunk.iter());
+            let mut header = self.header();
+            header.chunk = i as u8;
+            header.chunks = payloadchunks.len() as u8;
             // set tx flag
-            if i < (payloadchunks.len()-1) {
-                chunk[0] = 1 as u8;
+            header.txflag = if i < (payloadchunks.len()-1) {
+                TransmissionState::MoreChunks
+            } else {
+                TransmissionState::FinalChunk
+            };
+            let mut chunk = header.bytes();
+            chunk.extend(datachunk.iter());
+            if compressed {
+                chunk[1] |= FLAG_COMPRESSED;
             }
             chunks.push(chunk);
         }
 
-        return chunks;
+        return Ok(chunks);
     }
 
     pub fn header(&mut self) -> FrameHeader {
         return FrameHeader{
             txflag: self.txflag(),
             frameid: self.frameid(),
+            chunk: self.chunk,
+            chunks: self.chunks,
             msgtype: self.msgtype(),
             sender: self.sender(),
             routeoffset: self.route().len(),
@@ -189,18 +295,56 @@ impl Frame {
     }
 
     pub fn txflag(&mut self) -> TransmissionState {
-        return TransmissionState::n(self.txflag as u8).unwrap();
+        return self.txflag;
     }
 
     pub fn frameid(&mut self) -> u8 {
         return self.frameid as u8;
     }
 
+    pub fn chunk(&mut self) -> u8 {
+        return self.chunk;
+    }
+
+    pub fn chunks(&mut self) -> u8 {
+        return self.chunks;
+    }
+
+    /// identifies this frame across hops by its original
+    /// sender, frame ID and chunk index
+    pub fn key(&mut self) -> (NodeId, u8, u8) {
+        (self.sender, self.frameid, self.chunk)
+    }
+
+    /// whether each hop should acknowledge this frame
+    pub fn acknowledged(&mut self) -> bool {
+        self.msgtype.acknowledged()
+    }
+
+    pub fn is_ack(&mut self) -> bool {
+        self.msgtype == MessageType::Ack
+    }
+
+    /// the node a transmission of this frame is addressed to, only
+    /// known for frames that travel along a route
+    pub fn nexthop(&mut self) -> Option<NodeId> {
+        match self.msgtype {
+            // IP packets carry their whole path, starting with the transmitter
+            MessageType::IPPacket |
+            MessageType::CompressedIPPacket => self.route.get(1).cloned(),
+            MessageType::IPAssignSuccess |
+            MessageType::IPAssignFailure |
+            MessageType::RouteSuccess |
+            MessageType::RouteFailure => self.route.first().cloned(),
+            _ => None,
+        }
+    }
+
     pub fn msgtype(&mut self) -> MessageType {
-        return MessageType::n(self.msgtype).unwrap();
+        return self.msgtype;
     }
 
-    pub fn sender(&mut self) -> u8 {
+    pub fn sender(&mut self) -> NodeId {
         return self.sender;
     }
 
@@ -208,11 +352,7 @@ impl Frame {
         return self.routeoffset;
     }
 
-    pub fn route(&mut self) -> Vec<u8> {
-        return self.route.clone();
-    }
-
-    pub fn route_bytes(&mut self) -> Vec<u8> {
+    pub fn route(&mut self) -> Vec<NodeId> {
         return self.route.clone();
     }
 
@@ -221,24 +361,77 @@ impl Frame {
     }
 }
 
-/// take a list of received chunked frames and recombine their payload
-pub fn recombine_chunks(chunks: Vec<Frame>, header: FrameHeader) -> Frame {
+/// check raw frame bytes are in the version of the format we speak
+pub fn check_version(bytes: &[u8]) -> Result<(), FrameError> {
+    match bytes.first() {
+        None => Err(FrameError::Truncated("version")),
+        Some(&PROTOCOL_VERSION) => Ok(()),
+        Some(version) => Err(FrameError::UnsupportedVersion(*version)),
+    }
+}
+
+/// bytes each node ID in the header takes, from the txflag byte
+fn id_width(flags: u8) -> usize {
+    if flags & FLAG_WIDE_IDS != 0 { 2 } else { 1 }
+}
+
+/// node IDs packed at the given width
+fn parse_ids(bytes: &[u8], width: usize) -> Vec<NodeId> {
+    bytes.chunks(width).map(|id| match id {
+        [high, low] => NodeId::from_be_bytes([*high, *low]),
+        _ => id[0] as NodeId,
+    }).collect()
+}
+
+/// size of the header at the start of raw frame bytes, if complete
+pub fn header_len(bytes: &[u8]) -> Option<usize> {
+    let width = id_width(*bytes.get(1)?);
+    let len = HEADER_LEN + (1 + *bytes.get(HEADER_LEN - 1)? as usize) * width;
+    if bytes.len() < len { return None; }
+    Some(len)
+}
+
+/// whether raw frame bytes are a chunk with more of its frame to follow
+pub fn more_chunks(bytes: &[u8]) -> bool {
+    match bytes.get(1).and_then(|flag| TransmissionState::n(flag & !FLAGS)) {
+        Some(txflag) => txflag.more_chunks(),
+        None => false,
+    }
+}
+
+/// flag raw chunk bytes as the last one before the sender's transmit slot ends
+pub fn mark_slot_exceeded(bytes: &mut [u8]) {
+    if let Some(flag) = bytes.get_mut(1) {
+        *flag = (*flag & FLAGS) | TransmissionState::SlotExceeded.to_u8();
+    }
+}
+
+/// take a list of received chunked frames, in order, and recombine their
+/// payload, expanding it if it was compressed
+pub fn recombine_chunks(chunks: Vec<Frame>, mut header: FrameHeader) -> Result<Frame, FrameError> {
+    let compressed = chunks.first().map(|chunk| chunk.compressed).unwrap_or(false);
     let mut combinedbytes = Vec::new();
     for chunk in chunks {
         combinedbytes.extend(chunk.payload.iter());
     }
+    if compressed {
+        combinedbytes = decompress(&combinedbytes)?;
+    }
+    header.txflag = TransmissionState::FinalChunk;
+    header.chunk = 0;
+    header.chunks = 1;
 
-    Frame::from_header(
+    Ok(Frame::from_header(
         header,
         combinedbytes
-    )
+    ))
 }
 
 /// Instantiate a new frame for tx
 pub trait ToFromFrame {
-    fn from_frame(f: &mut Frame) -> std::io::Result<Box<Self>>;
+    fn from_frame(f: &mut Frame) -> Result<Box<Self>, FrameError>;
 
-    fn to_frame(&self, frameid: u8, sender: u8, route: Vec<u8>) -> Frame;
+    fn to_frame(&self, frameid: u8, sender: NodeId, route: Vec<NodeId>) -> Frame;
 }
 
 #[cfg(test)]
@@ -247,7 +440,7 @@ use hex;
 #[test]
 fn frame_chunking() {
     // check sizes during chunking
-    let sender = 3u8;
+    let sender: NodeId = 3;
     let raw = vec![0x45u8, 0x00, 0x00, 0x42, 0x47, 0x07, 0x40, 0x00, 0x40, 0x11, 0x6e, 0xcc, 0xc0, 0xa8, 0x01, 0x89, 0xc0, 0xa8, 0x01, 0xfe, 0xba, 0x2f, 0x00, 0x35, 0x00, 0x2e, 0x1d, 0xf8, 0xbc, 0x81, 0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x03, 0x61, 0x70, 0x69, 0x0c, 0x73, 0x74, 0x65, 0x61, 0x6d, 0x70, 0x6f, 0x77, 0x65, 0x72, 0x65, 0x64, 0x03, 0x63, 0x6f, 0x6d, 0x00, 0x00, 0x1c, 0x00, 0x01];
     let hex1 = hex::encode(&raw);
     let originalsize = raw.len();
@@ -257,16 +450,16 @@ fn frame_chunking() {
     assert_eq!(&hex1, &hex::encode(&raw));
 
     let msg = IPPacketMessage::new(packet);
-    let mut frame = msg.to_frame(1u8, sender, Vec::new());
+    let mut frame = msg.to_frame(1, sender, Vec::new());
 
     let chunksize = 45usize;
-    let framesize = chunksize.clone()+5usize;
-    let mut chunks = frame.chunked(&chunksize);
+    let framesize = chunksize.clone()+8usize;
+    let mut chunks = frame.chunked(&chunksize).unwrap();
 
     // ensure the sizes of the chunked packet are correct
     assert_eq!(&originalsize, &66usize);
     assert_eq!(&chunks[0].len(), &framesize);
-    assert_eq!(&chunks[1].len(), &26usize);
+    assert_eq!(&chunks[1].len(), &29usize);
 
     // check recombination
     let mut chunkedframes = Vec::new();
@@ -274,6 +467,11 @@ fn frame_chunking() {
         chunkedframes.push(Frame::from_bytes(&chunk).expect("Invalid chunked frame"));
     }
 
+    // chunks are numbered in order, and know how many there are
+    assert_eq!(chunkedframes[0].chunk(), 0u8);
+    assert_eq!(chunkedframes[1].chunk(), 1u8);
+    assert_eq!(chunkedframes[1].chunks(), 2u8);
+
     let mut rawchunks = &mut chunkedframes[0].clone().payload;
     rawchunks.extend(&mut chunkedframes[1].clone().payload.iter());
 
@@ -283,11 +481,182 @@ fn frame_chunking() {
     let packet2 = Packet::new(rawchunks.clone()).expect("Invalid manually recombined packet");
     let msg2 = IPPacketMessage::new(packet2);
 
-    let mut frame3 = recombine_chunks(chunkedframes, frame.header());
+    let mut frame3 = recombine_chunks(chunkedframes, frame.header()).expect("Invalid recombined frame");
     let msg3 = IPPacketMessage::from_frame(&mut frame3).expect("Invalid recombined IPPacketMessage");
     let packet3 = msg2.clone().packet();
     let raw3 = packet3.as_ref();
 
     assert_eq!(&raw3[0], &raw[0]);
     assert_eq!(&raw3[50], &raw[50]);
-}
\ No newline at end of file
+}
+#[cfg(test)]
+#[test]
+fn frame_compression() {
+    // repetitive payloads are compressed on air and expanded when parsed
+    let payload = vec![0u8; 120];
+    let mut frame = Frame::new(TransmissionState::FinalChunk, 7u8, MessageType::IPPacket, 1, 2u8, vec![1, 2], payload.clone());
+    frame.set_compress(true);
+    let bytes = frame.to_bytes();
+    assert!(bytes.len() < 10 + payload.len());
+    assert_eq!(bytes[1], FLAG_COMPRESSED);
+    let mut parsed = Frame::from_bytes(&bytes).unwrap();
+    assert_eq!(parsed.txflag(), TransmissionState::FinalChunk);
+    assert_eq!(parsed.payload(), payload);
+    parsed.set_compress(true);
+    assert_eq!(parsed.to_bytes(), bytes);
+
+    // chunks of a compressed frame are expanded once recombined
+    let big: Vec<u8> = (0..600u32).map(|i| (i % 7) as u8).collect();
+    let mut frame = Frame::new(TransmissionState::FinalChunk, 7u8, MessageType::IPPacket, 1, 2u8, vec![1, 2], big.clone());
+    frame.set_compress(true);
+    let chunks: Vec<Frame> = frame.chunked(&20usize).unwrap().iter().map(|chunk| Frame::from_bytes(chunk).unwrap()).collect();
+    assert!(chunks.len() < big.len() / 20);
+    let header = chunks.last().unwrap().clone().header();
+    assert_eq!(recombine_chunks(chunks, header).unwrap().payload(), big);
+
+    // frames are plain unless asked to be compressed, for next hops that don't understand it
+    let mut plain = Frame::new(TransmissionState::FinalChunk, 7u8, MessageType::IPPacket, 1, 2u8, vec![1, 2], payload.clone());
+    assert_eq!(plain.to_bytes().len(), 10 + payload.len());
+    assert_eq!(plain.chunked(&200usize).unwrap()[0], plain.to_bytes());
+
+    // payloads that wouldn't shrink are sent as they are
+    let mut frame = Frame::new(TransmissionState::FinalChunk, 7u8, MessageType::IPPacket, 1, 0u8, Vec::new(), vec![1u8, 2, 3, 4]);
+    frame.set_compress(true);
+    assert_eq!(frame.to_bytes(), vec![PROTOCOL_VERSION, 0u8, 7, 0, 1, MessageType::IPPacket as u8, 0, 1, 1, 2, 3, 4]);
+
+    // corrupt compressed payloads are rejected
+    let mut corrupt = bytes.clone();
+    corrupt.truncate(11);
+    assert!(Frame::from_bytes(&corrupt).is_err());
+}
+#[cfg(test)]
+#[test]
+fn frame_decode_errors() {
+    let bytes = Frame::new(TransmissionState::FinalChunk, 7u8, MessageType::Ack, 1, 1u8, vec![2], vec![1u8, 7, 0]).to_bytes();
+    assert!(Frame::from_bytes(&bytes).is_ok());
+
+    // garbage is rejected instead of panicking
+    assert_eq!(Frame::from_bytes(&Vec::new()).err(), Some(FrameError::Truncated("version")));
+    assert_eq!(Frame::from_bytes(&bytes[..5].to_vec()).err(), Some(FrameError::Truncated("header")));
+    let mut longroute = bytes.clone();
+    longroute[6] = 200u8;
+    assert_eq!(Frame::from_bytes(&longroute).err(), Some(FrameError::Truncated("route")));
+    let mut badflag = bytes.clone();
+    badflag[1] = 9u8;
+    assert_eq!(Frame::from_bytes(&badflag).err(), Some(FrameError::UnknownTransmissionState(9)));
+    let mut badtype = bytes.clone();
+    badtype[5] = 250u8;
+    assert_eq!(Frame::from_bytes(&badtype).err(), Some(FrameError::UnknownMessageType(250)));
+    let mut nochunks = bytes.clone();
+    nochunks[4] = 0u8;
+    assert_eq!(Frame::from_bytes(&nochunks).err(), Some(FrameError::InvalidChunk { chunk: 0, chunks: 0 }));
+    let mut notlast = bytes.clone();
+    notlast[1] = TransmissionState::MoreChunks.to_u8();
+    assert_eq!(Frame::from_bytes(&notlast).err(), Some(FrameError::InvalidChunk { chunk: 0, chunks: 1 }));
+
+    // frames of other protocol versions are refused before anything else is read
+    let mut newer = bytes.clone();
+    newer[0] = PROTOCOL_VERSION + 1;
+    newer[5] = 250u8;
+    assert_eq!(Frame::from_bytes(&newer).err(), Some(FrameError::UnsupportedVersion(PROTOCOL_VERSION + 1)));
+    assert_eq!(header_len(&bytes), Some(9));
+
+    // an empty route has nothing to shift
+    let mut frame = Frame::from_bytes(&bytes).unwrap();
+    assert_eq!(frame.route_shift(), Some(2));
+    assert_eq!(frame.route_shift(), None);
+}
+#[cfg(test)]
+#[test]
+fn frame_wide_ids() {
+    // node IDs that fit in a byte are sent as one
+    let mut narrow = Frame::new(TransmissionState::FinalChunk, 7u8, MessageType::Ack, 1, 2u8, vec![255, 3], vec![1u8, 7, 0]);
+    let bytes = narrow.to_bytes();
+    assert_eq!(&bytes[..10], &[PROTOCOL_VERSION, 0u8, 7, 0, 1, MessageType::Ack as u8, 2, 1, 255, 3]);
+    assert_eq!(header_len(&bytes), Some(10));
+
+    // any that doesn't widens the whole header
+    let mut wide = Frame::new(TransmissionState::FinalChunk, 7u8, MessageType::Ack, 1, 2u8, vec![256, 3], vec![1u8, 7, 0]);
+    let bytes = wide.to_bytes();
+    assert_eq!(&bytes[..13], &[PROTOCOL_VERSION, FLAG_WIDE_IDS, 7, 0, 1, MessageType::Ack as u8, 2, 0, 1, 1, 0, 0, 3]);
+    assert_eq!(header_len(&bytes), Some(13));
+    let mut parsed = Frame::from_bytes(&bytes).unwrap();
+    assert_eq!(parsed.sender(), 1);
+    assert_eq!(parsed.route(), vec![256, 3]);
+    assert_eq!(parsed.payload(), vec![1u8, 7, 0]);
+    assert_eq!(parsed.txflag(), TransmissionState::FinalChunk);
+
+    // and stays wide across chunks
+    let mut big = Frame::new(TransmissionState::FinalChunk, 7u8, MessageType::IPPacket, 65535, 1u8, vec![2], (0..60u8).collect());
+    big.set_compress(false);
+    let chunks = big.chunked(&30usize).unwrap();
+    assert_eq!(chunks.len(), 2);
+    assert!(chunks.iter().all(|chunk| chunk[1] & FLAG_WIDE_IDS != 0 && header_len(chunk) == Some(11)));
+    assert!(more_chunks(&chunks[0]));
+    let mut paused = chunks[0].clone();
+    mark_slot_exceeded(&mut paused);
+    assert_eq!(paused[1], FLAG_WIDE_IDS | TransmissionState::SlotExceeded.to_u8());
+    assert_eq!(Frame::from_bytes(&chunks[1]).unwrap().sender(), 65535);
+
+    // a wide sender cut short is truncated
+    assert_eq!(Frame::from_bytes(&bytes[..8].to_vec()).err(), Some(FrameError::Truncated("sender")));
+}
+
+#[test]
+fn frame_too_many_chunks() {
+    // 255 chunks is as many as the header can count
+    let mut frame = Frame::new(TransmissionState::FinalChunk, 7u8, MessageType::IPPacket, 1, 1u8, vec![2], vec![0u8; 255 * 10]);
+    frame.set_compress(false);
+    assert_eq!(frame.chunked(&10usize).unwrap().len(), 255);
+    let mut frame = Frame::new(TransmissionState::FinalChunk, 7u8, MessageType::IPPacket, 1, 1u8, vec![2], vec![0u8; 255 * 10 + 1]);
+    frame.set_compress(false);
+    assert_eq!(frame.chunked(&10usize).err(), Some(FrameError::TooManyChunks(256)));
+}
+
+#[cfg(test)]
+use crate::stack::Reassembly;
+#[cfg(test)]
+use proptest::prelude::*;
+#[cfg(test)]
+use std::time::Duration;
+
+#[cfg(test)]
+proptest! {
+    #[test]
+    fn frame_decode_arbitrary(bytes in proptest::collection::vec(any::<u8>(), 0..300)) {
+        // whatever arrives over the air decodes or errors, and decoded
+        // frames encode back into the same frame
+        if let Ok(mut frame) = Frame::from_bytes(&bytes) {
+            let mut again = Frame::from_bytes(&frame.to_bytes()).unwrap();
+            prop_assert_eq!(again.to_bytes(), frame.to_bytes());
+            prop_assert_eq!(again.payload(), frame.payload());
+        }
+    }
+
+    #[test]
+    fn frame_chunks_roundtrip(
+        payload in proptest::collection::vec(0u8..4, 0..600),
+        route in proptest::collection::vec(any::<NodeId>(), 0..8),
+        frameid in any::<u8>(),
+        sender in any::<NodeId>(),
+        maxsize in 10usize..200,
+        reversed in any::<bool>(),
+    ) {
+        let mut frame = Frame::new(TransmissionState::FinalChunk, frameid, MessageType::IPPacket, sender, route.len() as u8, route.clone(), payload.clone());
+        let mut chunks = frame.chunked(&maxsize).unwrap();
+        if reversed { chunks.reverse(); }
+
+        // the chunks of a frame put back together in any order make up the frame
+        let mut reassembly = Reassembly::new(Duration::from_secs(10));
+        let mut whole = None;
+        for chunk in &chunks {
+            whole = reassembly.push(Frame::from_bytes(chunk).unwrap()).unwrap().or(whole);
+        }
+        let mut whole = whole.expect("Frame was not reassembled");
+        prop_assert_eq!(whole.payload(), payload);
+        prop_assert_eq!(whole.route(), route);
+        prop_assert_eq!(whole.sender(), sender);
+        prop_assert_eq!(whole.frameid(), frameid);
+        prop_assert_eq!(whole.to_bytes(), frame.to_bytes());
+    }
+}
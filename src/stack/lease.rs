@@ -0,0 +1,263 @@
+use log::*;
+use std::io::{Error, ErrorKind};
+use std::net::Ipv4Addr;
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+use crate::error::LeaseError;
+use crate::stack::{MeshSubnet, NodeId};
+use crate::stack::state::{from_unix, to_unix, LeaseRecord};
+
+/// How recently a node must have broadcast its leased address for a second
+/// node asking for one with the same ID to be a conflict
+/* Longer than the slowest broadcast interval, so a live node is always seen
+within it, while a node that rebooted gets its address back soon after. */
+const CONFLICT_WINDOW: Duration = Duration::from_secs(90);
+
+/// An address held by a node
+#[derive(Clone, Debug)]
+struct Lease {
+    ipaddr: Ipv4Addr,
+    /// when the lease runs out unless renewed, never for reserved addresses
+    expires: Option<Instant>,
+    /// when the node last broadcast the address as its own
+    claimed: Option<Instant>,
+}
+
+impl Lease {
+    fn expired(&self, now: Instant) -> bool {
+        matches!(self.expires, Some(expires) if now >= expires)
+    }
+}
+
+/// Addresses the gateway leases to the other nodes
+/* Nodes without an address broadcast without one and are leased an address
+from the pool, their own in the mesh subnet when it's free so IPHC can leave
+it out. Every broadcast carrying the address renews the lease, and leases
+are released when their node expires or they run out. A node asking for an
+address while its ID was just heard with one is told its ID is taken, and a
+node claiming an address leased to another is told so too. */
+#[derive(Clone, Debug)]
+pub struct Leases {
+    subnet: MeshSubnet,
+    first: u32,
+    last: u32,
+    duration: Duration,
+    denied: HashSet<NodeId>,
+    leases: HashMap<NodeId, Lease>,
+}
+
+impl Leases {
+    /// A pool from `first` to `last` of the subnet, its whole host range by default
+    pub fn new(subnet: &MeshSubnet, first: Option<Ipv4Addr>, last: Option<Ipv4Addr>, duration: Duration, denied: &[NodeId]) -> std::io::Result<Self> {
+        let (hostfirst, hostlast) = subnet.hosts();
+        let first = first.unwrap_or(hostfirst);
+        let last = last.unwrap_or(hostlast);
+        if !subnet.contains(&first) || !subnet.contains(&last) || u32::from(first) > u32::from(last) {
+            return Err(Error::new(ErrorKind::InvalidInput, format!("Lease pool {} to {} isn't a range of mesh subnet {}", first, last, subnet)));
+        }
+        Ok(Leases {
+            subnet: *subnet,
+            first: u32::from(first),
+            last: u32::from(last),
+            duration,
+            denied: denied.iter().cloned().collect(),
+            leases: HashMap::new(),
+        })
+    }
+
+    /// Hold an address for a node for as long as we run, like our own
+    pub fn reserve(&mut self, nodeid: NodeId, ipaddr: Ipv4Addr) {
+        self.leases.insert(nodeid, Lease { ipaddr, expires: None, claimed: None });
+    }
+
+    /// Lease an address to a node that asked for one, returns
+    /// it and whether the node didn't hold it already
+    pub fn assign(&mut self, nodeid: NodeId) -> Result<(Ipv4Addr, bool), LeaseError> {
+        if nodeid == 0 || self.denied.contains(&nodeid) {
+            return Err(LeaseError::Denied(nodeid));
+        }
+        let now = Instant::now();
+        self.expire_at(now);
+
+        if let Some(lease) = self.leases.get_mut(&nodeid) {
+            // someone else with this ID is still using its address
+            let inuse = matches!(lease.claimed, Some(claimed) if now.duration_since(claimed) < CONFLICT_WINDOW);
+            if lease.expires.is_none() || inuse {
+                return Err(LeaseError::IdConflict(nodeid));
+            }
+            // the node lost our answer or restarted, it gets the same address
+            lease.expires = Some(now + self.duration);
+            return Ok((lease.ipaddr, false));
+        }
+
+        let ipaddr = self.free(nodeid).ok_or(LeaseError::PoolExhausted)?;
+        debug!("Leasing {} to node {}", &ipaddr, &nodeid);
+        self.leases.insert(nodeid, Lease { ipaddr, expires: Some(now + self.duration), claimed: None });
+        Ok((ipaddr, true))
+    }
+
+    /// Renew the lease of a node broadcasting its address, returns true if
+    /// the address wasn't leased to it, like after a gateway restart
+    /* Addresses outside the pool aren't ours to lease and are left alone. */
+    pub fn claim(&mut self, nodeid: NodeId, ipaddr: Ipv4Addr) -> Result<bool, LeaseError> {
+        if self.denied.contains(&nodeid) {
+            return Err(LeaseError::Denied(nodeid));
+        }
+        let now = Instant::now();
+        self.expire_at(now);
+
+        if let Some(lease) = self.leases.get_mut(&nodeid) {
+            if lease.ipaddr != ipaddr || lease.expires.is_none() {
+                return Err(LeaseError::IdConflict(nodeid));
+            }
+            lease.expires = Some(now + self.duration);
+            lease.claimed = Some(now);
+            return Ok(false);
+        }
+        if let Some(holder) = self.holder(&ipaddr) {
+            return Err(LeaseError::AddressConflict { ipaddr, holder });
+        }
+        if !self.in_pool(&ipaddr) {
+            return Ok(false);
+        }
+        debug!("Node {} claimed {}, leasing it", &nodeid, &ipaddr);
+        self.leases.insert(nodeid, Lease { ipaddr, expires: Some(now + self.duration), claimed: Some(now) });
+        Ok(true)
+    }
+
+    /// Give up the lease of a node, like when it expires
+    pub fn release(&mut self, nodeid: NodeId) -> Option<Ipv4Addr> {
+        match self.leases.get(&nodeid) {
+            Some(lease) if lease.expires.is_some() => self.leases.remove(&nodeid).map(|lease| lease.ipaddr),
+            _ => None,
+        }
+    }
+
+    /// Leases to save, reserved addresses are taken again at startup
+    pub fn records(&self) -> Vec<LeaseRecord> {
+        self.leases.iter()
+            .filter_map(|(nodeid, lease)| lease.expires.map(|expires| LeaseRecord { nodeid: *nodeid, ipaddr: lease.ipaddr, expires: to_unix(expires) }))
+            .collect()
+    }
+
+    /// Take back saved leases, skipping those that no longer fit the pool
+    pub fn restore(&mut self, records: &[LeaseRecord]) {
+        for record in records {
+            if !self.in_pool(&record.ipaddr) || self.holder(&record.ipaddr).is_some() || self.leases.contains_key(&record.nodeid) {
+                warn!("Dropping saved lease of {} to node {}", &record.ipaddr, &record.nodeid);
+                continue;
+            }
+            self.leases.insert(record.nodeid, Lease { ipaddr: record.ipaddr, expires: Some(from_unix(record.expires)), claimed: None });
+        }
+        self.expire_at(Instant::now());
+    }
+
+    /// Whether an address belongs to the subnet we lease from
+    pub fn contains(&self, ipaddr: &Ipv4Addr) -> bool {
+        self.subnet.contains(ipaddr)
+    }
+
+    /// Drop leases that ran out
+    fn expire_at(&mut self, now: Instant) {
+        let expired: Vec<NodeId> = self.leases.iter()
+            .filter(|(_, lease)| lease.expired(now))
+            .map(|(nodeid, _)| *nodeid)
+            .collect();
+        for nodeid in expired {
+            if let Some(lease) = self.leases.remove(&nodeid) {
+                debug!("Lease of {} to node {} ran out", &lease.ipaddr, &nodeid);
+            }
+        }
+    }
+
+    /// Node an address is leased to, if any
+    fn holder(&self, ipaddr: &Ipv4Addr) -> Option<NodeId> {
+        self.leases.iter().find(|(_, lease)| lease.ipaddr == *ipaddr).map(|(nodeid, _)| *nodeid)
+    }
+
+    fn in_pool(&self, ipaddr: &Ipv4Addr) -> bool {
+        (self.first..=self.last).contains(&u32::from(*ipaddr))
+    }
+
+    /// A free address for a node, its own in the subnet if we can
+    fn free(&self, nodeid: NodeId) -> Option<Ipv4Addr> {
+        let used: HashSet<u32> = self.leases.values().map(|lease| u32::from(lease.ipaddr)).collect();
+        let own = self.subnet.ipaddr(nodeid).map(u32::from);
+        own.into_iter().chain(self.first..=self.last)
+            .filter(|addr| self.in_pool(&Ipv4Addr::from(*addr)))
+            .find(|addr| !used.contains(addr))
+            .map(Ipv4Addr::from)
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn lease_assign() {
+    let subnet = MeshSubnet::new(Ipv4Addr::new(10,42,7,0), 24).unwrap();
+    let mut leases = Leases::new(&subnet, None, Some(Ipv4Addr::new(10,42,7,4)), Duration::from_secs(60), &[9]).unwrap();
+    leases.reserve(1, Ipv4Addr::new(10,42,7,1));
+
+    // nodes get their own address when it's free, the next free one otherwise
+    assert_eq!(leases.assign(3), Ok((Ipv4Addr::new(10,42,7,3), true)));
+    assert_eq!(leases.assign(300), Ok((Ipv4Addr::new(10,42,7,2), true)));
+    assert_eq!(leases.assign(3), Ok((Ipv4Addr::new(10,42,7,3), false)));
+    assert_eq!(leases.assign(4), Ok((Ipv4Addr::new(10,42,7,4), true)));
+    assert_eq!(leases.assign(5), Err(LeaseError::PoolExhausted));
+
+    // reserved and denied IDs get nothing
+    assert_eq!(leases.assign(1), Err(LeaseError::IdConflict(1)));
+    assert_eq!(leases.assign(9), Err(LeaseError::Denied(9)));
+    assert_eq!(leases.assign(0), Err(LeaseError::Denied(0)));
+
+    // released addresses go back to the pool
+    assert_eq!(leases.release(4), Some(Ipv4Addr::new(10,42,7,4)));
+    assert_eq!(leases.release(1), None);
+    assert_eq!(leases.assign(5), Ok((Ipv4Addr::new(10,42,7,4), true)));
+
+    assert!(Leases::new(&subnet, Some(Ipv4Addr::new(10,42,8,1)), None, Duration::from_secs(60), &[]).is_err());
+    assert!(Leases::new(&subnet, Some(Ipv4Addr::new(10,42,7,9)), Some(Ipv4Addr::new(10,42,7,8)), Duration::from_secs(60), &[]).is_err());
+}
+
+#[cfg(test)]
+#[test]
+fn lease_claim() {
+    let mut leases = Leases::new(&MeshSubnet::default(), None, None, Duration::from_secs(60), &[]).unwrap();
+    let (ipaddr, _) = leases.assign(2).unwrap();
+
+    // broadcasting the address renews it, after which the ID is taken
+    assert_eq!(leases.claim(2, ipaddr), Ok(false));
+    assert_eq!(leases.assign(2), Err(LeaseError::IdConflict(2)));
+    assert_eq!(leases.claim(2, Ipv4Addr::new(172,16,0,7)), Err(LeaseError::IdConflict(2)));
+
+    // nobody else can claim it, but free pool addresses are taken over
+    assert_eq!(leases.claim(3, ipaddr), Err(LeaseError::AddressConflict { ipaddr, holder: 2 }));
+    assert_eq!(leases.claim(3, Ipv4Addr::new(172,16,0,30)), Ok(true));
+    assert_eq!(leases.holder(&Ipv4Addr::new(172,16,0,30)), Some(3));
+    assert_eq!(leases.claim(4, Ipv4Addr::new(10,0,0,4)), Ok(false));
+    assert_eq!(leases.holder(&Ipv4Addr::new(10,0,0,4)), None);
+
+    // leases that run out are reclaimed
+    let mut short = Leases::new(&MeshSubnet::default(), None, None, Duration::from_millis(0), &[]).unwrap();
+    short.assign(2).unwrap();
+    assert_eq!(short.claim(5, Ipv4Addr::new(172,16,0,2)), Ok(true));
+}
+
+#[cfg(test)]
+#[test]
+fn lease_restore() {
+    let subnet = MeshSubnet::new(Ipv4Addr::new(10,42,7,0), 24).unwrap();
+    let mut leases = Leases::new(&subnet, None, None, Duration::from_secs(60), &[]).unwrap();
+    leases.reserve(1, Ipv4Addr::new(10,42,7,1));
+    leases.assign(2).unwrap();
+    leases.assign(3).unwrap();
+    let records = leases.records();
+    assert_eq!(records.len(), 2);
+
+    // a restarted gateway keeps them, but not those outside its pool
+    let mut restarted = Leases::new(&subnet, None, Some(Ipv4Addr::new(10,42,7,2)), Duration::from_secs(60), &[]).unwrap();
+    restarted.reserve(1, Ipv4Addr::new(10,42,7,1));
+    restarted.restore(&records);
+    assert_eq!(restarted.holder(&Ipv4Addr::new(10,42,7,2)), Some(2));
+    assert_eq!(restarted.holder(&Ipv4Addr::new(10,42,7,3)), None);
+    assert_eq!(restarted.assign(2), Ok((Ipv4Addr::new(10,42,7,2), false)));
+}
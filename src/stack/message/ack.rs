@@ -0,0 +1,70 @@
+use crate::error::FrameError;
+use crate::stack::{Frame, MessageType};
+use crate::stack::frame::{FrameHeader, NodeId, ToFromFrame, TransmissionState};
+use crate::stack::util::parse_nodeid;
+
+/// Acknowledge a frame received from the previous hop.
+/* Frames are identified by their original sender, frame ID and chunk
+index, which stay the same as the frame is relayed along its route. */
+pub struct AckMessage {
+    pub header: Option<FrameHeader>,
+    pub sender: NodeId, // original sender of the acknowledged frame
+    pub frameid: u8,
+    pub chunk: u8
+}
+
+impl AckMessage {
+    pub fn new(sender: NodeId, frameid: u8, chunk: u8) -> Self {
+        AckMessage{ header: None, sender, frameid, chunk }
+    }
+
+    /// the (sender, frameid, chunk) key of the acknowledged frame
+    pub fn key(&self) -> (NodeId, u8, u8) {
+        (self.sender, self.frameid, self.chunk)
+    }
+}
+
+impl ToFromFrame for AckMessage {
+    fn from_frame(f: &mut Frame) -> Result<Box<Self>, FrameError> {
+        let header = f.header();
+        let data = f.payload();
+        let key = data.get(0..4).ok_or(FrameError::Truncated("acknowledged frame"))?;
+
+        Ok(Box::new(AckMessage {
+            header: Some(header),
+            sender: parse_nodeid(&key[0..2]),
+            frameid: key[2],
+            chunk: key[3]
+        }))
+    }
+
+    fn to_frame(&self, frameid: u8, sender: NodeId, route: Vec<NodeId>) -> Frame {
+        let routeoffset = route.len() as u8;
+        let mut payload = Vec::from(&self.sender.to_be_bytes()[..]);
+        payload.extend_from_slice(&[self.frameid, self.chunk]);
+
+        Frame::new(
+            TransmissionState::FinalChunk,
+            frameid,
+            MessageType::Ack,
+            sender,
+            routeoffset,
+            route,
+            payload
+        )
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn ack_tofrom_frame() {
+    let bytes = AckMessage::new(1, 42, 3).to_frame(42, 2, Vec::new()).to_bytes();
+    let mut frame = Frame::from_bytes(&bytes).unwrap();
+    assert_eq!(frame.msgtype(), MessageType::Ack);
+    assert!(!frame.msgtype().acknowledged());
+    assert_eq!(AckMessage::from_frame(&mut frame).unwrap().key(), (1, 42, 3));
+
+    // truncated acks are rejected
+    let mut short = Frame::new(TransmissionState::FinalChunk, 42u8, MessageType::Ack, 2, 0u8, Vec::new(), vec![0u8, 1, 42]);
+    assert!(AckMessage::from_frame(&mut short).is_err());
+}
@@ -1,8 +1,24 @@
+use crate::error::FrameError;
 use std::net::Ipv4Addr;
+use std::convert::TryInto;
 use crate::stack::Frame;
-use crate::stack::frame::{FrameHeader, ToFromFrame};
+use crate::stack::frame::{FrameHeader, NodeId, ToFromFrame, TransmissionState};
 use crate::stack::util::{parse_bool, parse_ipv4, parse_byte};
 use crate::message::MessageType;
+use crate::stack::signing::SIGNATURE_LEN;
+use crate::stack::capabilities::{Capabilities, CAPABILITIES_LEN};
+use crate::stack::subnet::{MeshSubnet, SUBNET_LEN};
+
+/// Options byte: the TDMA phase follows
+const OPTION_EPOCH: u8 = 0b01;
+/// Options byte: the sender's protocol versions and features follow
+const OPTION_CAPABILITIES: u8 = 0b100;
+/// Options byte: a gateway signature follows
+const OPTION_SIGNED: u8 = 0b10;
+/// Options byte: the mesh subnet follows, advertised by gateways
+const OPTION_SUBNET: u8 = 0b1000;
+/// Options byte: the serial of a signed gateway claim follows
+const OPTION_SERIAL: u8 = 0b10000;
 
 /// Broadcast this node to nearby devices.
 #[derive(Clone)]
@@ -10,30 +26,105 @@ pub struct BroadcastMessage {
     pub header: Option<FrameHeader>,
     pub isgateway: bool,
     pub ipOffset: usize,
-    pub ipaddr: Option<Ipv4Addr>
+    pub ipaddr: Option<Ipv4Addr>,
+    /// phase (ms) of the sender's TDMA superframe, advertised by gateways,
+    /// unauthenticated since relays re-stamp it
+    pub epoch: Option<u32>,
+    /// protocol versions and features the sender understands
+    pub capabilities: Option<Capabilities>,
+    /// subnet node addresses come from, advertised by gateways
+    pub subnet: Option<MeshSubnet>,
+    /// serial of a signed gateway claim, newer claims have larger ones
+    pub serial: Option<u64>,
+    /// gateway's signature over `signed_bytes`
+    pub signature: Option<Vec<u8>>
+}
+
+impl BroadcastMessage {
+    /// The claim a gateway signs: that it is a gateway, its address, subnet
+    /// and the claim's serial
+    /* Relays re-stamp the epoch with their own phase, so it isn't signed.
+    Any node with the network key can relay a gateway's broadcast with
+    another epoch and shift the transmit slots of the nodes hearing it,
+    the signature only vouches for the gateway's address and subnet. */
+    pub fn signed_bytes(&self, sender: NodeId) -> Vec<u8> {
+        let mut data = vec![MessageType::Broadcast as u8];
+        data.extend_from_slice(&sender.to_be_bytes());
+        data.push(parse_byte(self.isgateway));
+        if let Some(ipaddr) = self.ipaddr {
+            data.extend_from_slice(&ipaddr.octets());
+        }
+        if let Some(subnet) = &self.subnet {
+            data.extend(subnet.to_bytes());
+        }
+        if let Some(serial) = self.serial {
+            data.extend_from_slice(&serial.to_be_bytes());
+        }
+        data
+    }
 }
 
 impl ToFromFrame for BroadcastMessage {
-    fn from_frame(f: &mut Frame) -> std::io::Result<Box<Self>> {
+    fn from_frame(f: &mut Frame) -> Result<Box<Self>, FrameError> {
         let header = f.header();
         let data = f.payload();
-        let isgateway = parse_bool(data[0]).unwrap();
-        let offset = data[1] as usize;
+        let isgateway = parse_bool(*data.get(0).ok_or(FrameError::Truncated("gateway flag"))?)?;
+        let offset = *data.get(1).ok_or(FrameError::Truncated("IP offset"))? as usize;
         let mut ipaddr: Option<Ipv4Addr> = None;
         if offset > 0 as usize {
-            let octets = &data[2..6];
+            let octets = data.get(2..6).ok_or(FrameError::Truncated("IP address"))?;
             ipaddr = Some(parse_ipv4(octets));
         }
+        // nodes route to the internet through the gateway's address
+        if isgateway && ipaddr.is_none() {
+            return Err(FrameError::Missing("gateway IP address"));
+        }
+        let options = data.get(2+offset).cloned().unwrap_or(0u8);
+        let mut next = 3+offset;
+        let mut epoch: Option<u32> = None;
+        if options & OPTION_EPOCH != 0 {
+            let bytes = data.get(next..(next+4)).ok_or(FrameError::Truncated("epoch"))?;
+            epoch = Some(u32::from_be_bytes(bytes.try_into().unwrap()));
+            next += 4;
+        }
+        let mut capabilities: Option<Capabilities> = None;
+        if options & OPTION_CAPABILITIES != 0 {
+            let bytes = data.get(next..(next+CAPABILITIES_LEN)).ok_or(FrameError::Truncated("capabilities"))?;
+            capabilities = Some(Capabilities::from_bytes(bytes)?);
+            next += CAPABILITIES_LEN;
+        }
+        let mut subnet: Option<MeshSubnet> = None;
+        if options & OPTION_SUBNET != 0 {
+            let bytes = data.get(next..(next+SUBNET_LEN)).ok_or(FrameError::Truncated("subnet"))?;
+            subnet = Some(MeshSubnet::from_bytes(bytes)?);
+            next += SUBNET_LEN;
+        }
+        let mut serial: Option<u64> = None;
+        if options & OPTION_SERIAL != 0 {
+            let bytes = data.get(next..(next+8)).ok_or(FrameError::Truncated("serial"))?;
+            serial = Some(u64::from_be_bytes(bytes.try_into().unwrap()));
+            next += 8;
+        }
+        let mut signature: Option<Vec<u8>> = None;
+        if options & OPTION_SIGNED != 0 {
+            let bytes = data.get(next..(next+SIGNATURE_LEN)).ok_or(FrameError::Truncated("signature"))?;
+            signature = Some(Vec::from(bytes));
+        }
 
         Ok(Box::new(BroadcastMessage {
             header: Some(header),
             isgateway,
             ipOffset: offset,
-            ipaddr
+            ipaddr,
+            epoch,
+            capabilities,
+            subnet,
+            serial,
+            signature
         }))
     }
 
-    fn to_frame(&self, frameid: u8, sender: u8, route: Vec<u8>) -> Frame {
+    fn to_frame(&self, frameid: u8, sender: NodeId, route: Vec<NodeId>) -> Frame {
         // write the payload
         let mut payload: Vec<u8> = Vec::new();
         payload.push(parse_byte(self.isgateway));
@@ -48,16 +139,38 @@ impl ToFromFrame for BroadcastMessage {
             payload.push(0usize as u8);
         }
 
-        // cast the route
-        let route: Vec<u8> = route.clone().iter().map(|i| i.clone() as u8).collect();
+        // write TDMA phase, capabilities, subnet, serial and signature if we have them
+        let mut options = 0u8;
+        if self.epoch.is_some() { options |= OPTION_EPOCH; }
+        if self.capabilities.is_some() { options |= OPTION_CAPABILITIES; }
+        if self.subnet.is_some() { options |= OPTION_SUBNET; }
+        if self.serial.is_some() { options |= OPTION_SERIAL; }
+        if self.signature.is_some() { options |= OPTION_SIGNED; }
+        payload.push(options);
+        if let Some(epoch) = self.epoch {
+            payload.extend_from_slice(&epoch.to_be_bytes());
+        }
+        if let Some(capabilities) = &self.capabilities {
+            payload.extend(capabilities.to_bytes());
+        }
+        if let Some(subnet) = &self.subnet {
+            payload.extend(subnet.to_bytes());
+        }
+        if let Some(serial) = self.serial {
+            payload.extend_from_slice(&serial.to_be_bytes());
+        }
+        if let Some(signature) = &self.signature {
+            payload.extend_from_slice(signature);
+        }
+
         let routeoffset = route.len() as u8;
 
         Frame::new(
-            0i8 as u8,
+            TransmissionState::FinalChunk,
             frameid,
-            MessageType::Broadcast as u8,
-            sender as u8,
-            routeoffset as u8,
+            MessageType::Broadcast,
+            sender,
+            routeoffset,
             route,
             payload
         )
@@ -67,19 +180,24 @@ impl ToFromFrame for BroadcastMessage {
 #[cfg(test)]
 #[test]
 fn broadcast_tofrom_frame() {
-    let id = 5u8;
+    let id: NodeId = 5;
     let isgateway = false;
     let msg = BroadcastMessage {
         header: None,
         isgateway,
         ipOffset: 4,
-        ipaddr: Some(Ipv4Addr::new(172,16,0,id.clone() as u8))
+        ipaddr: MeshSubnet::default().ipaddr(id),
+        epoch: Some(1234),
+        capabilities: None,
+        subnet: None,
+        serial: None,
+        signature: None
     };
-    let mut route: Vec<u8> = Vec::new();
+    let mut route: Vec<NodeId> = Vec::new();
     route.push(id.clone());
 
     // check tofrom frame
-    let mut frame = msg.to_frame(1u8, id, route);
+    let mut frame = msg.to_frame(1, id, route);
 
     assert_eq!(frame.sender(), id);
     assert_eq!(frame.payload().get(0).unwrap().clone() as i8, 0i8);
@@ -87,17 +205,17 @@ fn broadcast_tofrom_frame() {
     assert_eq!(frame.payload().get(2).unwrap().clone(), 172u8);
     assert_eq!(frame.payload().get(3).unwrap().clone(), 16u8);
     assert_eq!(frame.payload().get(4).unwrap().clone(), 0u8);
-    assert_eq!(frame.payload().get(5).unwrap().clone(), id);
+    assert_eq!(frame.payload().get(5).unwrap().clone(), id as u8);
 
     // ensure representation is same after hex encoding
     let bytes = frame.to_bytes();
 
-    assert_eq!(bytes.get(6).unwrap().clone() as i8, 0i8);
-    assert_eq!(bytes.get(7).unwrap().clone() as usize, 4);
-    assert_eq!(bytes.get(8).unwrap().clone(), 172u8);
-    assert_eq!(bytes.get(9).unwrap().clone(), 16u8);
-    assert_eq!(bytes.get(10).unwrap().clone(), 0u8);
-    assert_eq!(bytes.get(11).unwrap().clone(), id);
+    assert_eq!(bytes.get(9).unwrap().clone() as i8, 0i8);
+    assert_eq!(bytes.get(10).unwrap().clone() as usize, 4);
+    assert_eq!(bytes.get(11).unwrap().clone(), 172u8);
+    assert_eq!(bytes.get(12).unwrap().clone(), 16u8);
+    assert_eq!(bytes.get(13).unwrap().clone(), 0u8);
+    assert_eq!(bytes.get(14).unwrap().clone(), id as u8);
 
     let mut frame2 = Frame::from_bytes(&bytes).unwrap();
     let msg2 = BroadcastMessage::from_frame(&mut frame2).unwrap();
@@ -106,4 +224,64 @@ fn broadcast_tofrom_frame() {
     assert_eq!(msg2.header.unwrap().sender(), id);
     assert_eq!(msg2.isgateway, isgateway);
     assert_eq!(msg2.ipaddr.unwrap(), msg.ipaddr.unwrap());
+    assert_eq!(msg2.epoch, Some(1234));
+    assert!(msg2.signature.is_none());
+
+    // a signature can follow with or without the epoch
+    let mut signed = msg.clone();
+    signed.epoch = None;
+    signed.signature = Some(vec![7u8; SIGNATURE_LEN]);
+    let bytes = signed.to_frame(1, id, vec![id]).to_bytes();
+    let msg3 = BroadcastMessage::from_frame(&mut Frame::from_bytes(&bytes).unwrap()).unwrap();
+    assert_eq!(msg3.epoch, None);
+    assert_eq!(msg3.signature, signed.signature);
+    assert_eq!(msg3.signed_bytes(id), vec![MessageType::Broadcast as u8, 0, 5, 0, 172, 16, 0, 5]);
+
+    // capabilities sit between the epoch and the signature
+    let mut advertised = signed.clone();
+    advertised.epoch = Some(1234);
+    advertised.capabilities = Some(Capabilities::ours());
+    let bytes = advertised.to_frame(1, id, vec![id]).to_bytes();
+    let msg4 = BroadcastMessage::from_frame(&mut Frame::from_bytes(&bytes).unwrap()).unwrap();
+    assert_eq!(msg4.epoch, Some(1234));
+    assert_eq!(msg4.capabilities, Some(Capabilities::ours()));
+    assert_eq!(msg4.signature, signed.signature);
+
+    // gateways sign the subnet they advertise along with their address
+    let mut gateway = advertised.clone();
+    gateway.subnet = Some(MeshSubnet::new(Ipv4Addr::new(10,42,0,0), 16).unwrap());
+    let bytes = gateway.to_frame(1, id, vec![id]).to_bytes();
+    let msg5 = BroadcastMessage::from_frame(&mut Frame::from_bytes(&bytes).unwrap()).unwrap();
+    assert_eq!(msg5.capabilities, Some(Capabilities::ours()));
+    assert_eq!(msg5.subnet, gateway.subnet);
+    assert_eq!(msg5.signature, signed.signature);
+    assert_eq!(msg5.signed_bytes(id), vec![MessageType::Broadcast as u8, 0, 5, 0, 172, 16, 0, 5, 10, 42, 0, 0, 16]);
+
+    // and the serial, so an old broadcast can't be replayed, but not the epoch
+    let mut serial = gateway.clone();
+    serial.serial = Some(0x0102);
+    let bytes = serial.to_frame(1, id, vec![id]).to_bytes();
+    let mut msg6 = BroadcastMessage::from_frame(&mut Frame::from_bytes(&bytes).unwrap()).unwrap();
+    assert_eq!(msg6.subnet, gateway.subnet);
+    assert_eq!(msg6.serial, Some(0x0102));
+    assert_eq!(msg6.signature, signed.signature);
+    assert_eq!(msg6.signed_bytes(id), vec![MessageType::Broadcast as u8, 0, 5, 0, 172, 16, 0, 5, 10, 42, 0, 0, 16, 0, 0, 0, 0, 0, 0, 1, 2]);
+    msg6.epoch = Some(4321);
+    assert_eq!(msg6.signed_bytes(id), serial.signed_bytes(id));
+
+    // a signature that was cut short is rejected
+    let mut payload = signed.to_frame(1, id, vec![id]).payload();
+    payload.pop();
+    let mut short = Frame::new(TransmissionState::FinalChunk, 1, MessageType::Broadcast, id, 1, vec![id], payload);
+    assert!(BroadcastMessage::from_frame(&mut short).is_err());
+
+    // as are empty broadcasts and gateway flags that aren't booleans
+    let mut empty = Frame::new(TransmissionState::FinalChunk, 1, MessageType::Broadcast, id, 1, vec![id], Vec::new());
+    assert_eq!(BroadcastMessage::from_frame(&mut empty).err(), Some(FrameError::Truncated("gateway flag")));
+    let mut badflag = Frame::new(TransmissionState::FinalChunk, 1, MessageType::Broadcast, id, 1, vec![id], vec![2, 0]);
+    assert_eq!(BroadcastMessage::from_frame(&mut badflag).err(), Some(FrameError::InvalidBool(2)));
+
+    // and gateways that don't tell us their address
+    let mut anonymous = Frame::new(TransmissionState::FinalChunk, 1, MessageType::Broadcast, id, 1, vec![id], vec![1, 0, 0]);
+    assert_eq!(BroadcastMessage::from_frame(&mut anonymous).err(), Some(FrameError::Missing("gateway IP address")));
 }
\ No newline at end of file
@@ -1,51 +1,87 @@
 use crate::{MESH_MAX_MESSAGE_LEN};
 use enumn::N;
+use crate::error::FrameError;
 use std::net::Ipv4Addr;
+use std::convert::TryInto;
 use crate::stack::{Frame, MessageType};
-use crate::stack::frame::{FrameHeader, ToFromFrame};
-use crate::stack::util::{parse_ipv4};
+use crate::stack::frame::{FrameHeader, NodeId, ToFromFrame, TransmissionState};
+use crate::stack::util::{parse_ipv4, parse_nodeid};
+use crate::stack::signing::SIGNATURE_LEN;
 
 /// Notify node of their new IP address.
+/* The assignment names the node it is for, so a gateway's signature
+over it can't be replayed to hand the same address to another node, and
+carries a serial, so it can't be replayed to hand back an old address. */
 pub struct IPAssignSuccessMessage {
     pub header: Option<FrameHeader>,
-    pub ipaddr: Ipv4Addr
+    pub nodeid: NodeId,
+    pub ipaddr: Ipv4Addr,
+    /// serial of the signed claim, sent along with the signature
+    pub serial: Option<u64>,
+    /// gateway's signature over `signed_bytes`
+    pub signature: Option<Vec<u8>>
 }
 
 impl IPAssignSuccessMessage {
-    pub fn new(ipaddr: Ipv4Addr) -> Self {
-        return IPAssignSuccessMessage{ header: None, ipaddr}
+    pub fn new(nodeid: NodeId, ipaddr: Ipv4Addr) -> Self {
+        return IPAssignSuccessMessage{ header: None, nodeid, ipaddr, serial: None, signature: None}
+    }
+
+    /// The claim a gateway signs: who assigned which address to whom, and when
+    pub fn signed_bytes(&self, sender: NodeId) -> Vec<u8> {
+        let mut data = vec![MessageType::IPAssignSuccess as u8];
+        data.extend_from_slice(&sender.to_be_bytes());
+        data.extend_from_slice(&self.nodeid.to_be_bytes());
+        data.extend_from_slice(&self.ipaddr.octets());
+        if let Some(serial) = self.serial {
+            data.extend_from_slice(&serial.to_be_bytes());
+        }
+        data
     }
 }
 
 impl ToFromFrame for IPAssignSuccessMessage {
-    fn from_frame(f: &mut Frame) -> std::io::Result<Box<Self>> {
+    fn from_frame(f: &mut Frame) -> Result<Box<Self>, FrameError> {
         let header = f.header();
         let data = f.payload();
-        let octets = &data[0..data.len()];
+        let octets = data.get(0..4).ok_or(FrameError::Truncated("IP address"))?;
         let ipaddr = parse_ipv4(octets);
+        let nodeid = parse_nodeid(data.get(4..6).ok_or(FrameError::Truncated("node ID"))?);
+        let mut serial: Option<u64> = None;
+        let mut signature: Option<Vec<u8>> = None;
+        if let Some(signed) = data.get(6..(14 + SIGNATURE_LEN)) {
+            serial = Some(u64::from_be_bytes(signed[..8].try_into().unwrap()));
+            signature = Some(Vec::from(&signed[8..]));
+        }
 
         Ok(Box::new(IPAssignSuccessMessage {
             header: Some(header),
-            ipaddr
+            nodeid,
+            ipaddr,
+            serial,
+            signature
         }))
     }
 
-    fn to_frame(&self, frameid: u8, sender: u8, route: Vec<u8>) -> Frame {
-        // cast the route
-        let route: Vec<u8> = route.clone().iter().map(|i| i.clone() as u8).collect();
+    fn to_frame(&self, frameid: u8, sender: NodeId, route: Vec<NodeId>) -> Frame {
         let routeoffset = route.len() as u8;
 
         // write the payload
         let mut data: Vec<u8> = Vec::new();
         let octets = self.ipaddr.octets();
         octets.iter().for_each(|oct| data.push(oct.clone()));
+        data.extend_from_slice(&self.nodeid.to_be_bytes());
+        if let Some(signature) = &self.signature {
+            data.extend_from_slice(&self.serial.unwrap_or(0).to_be_bytes());
+            data.extend_from_slice(signature);
+        }
 
         Frame::new(
-            0i8 as u8,
+            TransmissionState::FinalChunk,
             frameid,
-            MessageType::IPAssignSuccess as u8,
-            sender as u8,
-            routeoffset as u8,
+            MessageType::IPAssignSuccess,
+            sender,
+            routeoffset,
             route,
             data
         )
@@ -65,9 +101,9 @@ impl IPAssignFailureMessage {
 }
 
 impl ToFromFrame for IPAssignFailureMessage {
-    fn from_frame(f: &mut Frame) -> std::io::Result<Box<Self>> {
+    fn from_frame(f: &mut Frame) -> Result<Box<Self>, FrameError> {
         let header = f.header();
-        let reason = String::from_utf8(f.payload()).expect("Could not parse UTF-8 message");
+        let reason = String::from_utf8(f.payload()).map_err(|_| FrameError::InvalidUtf8)?;
 
         Ok(Box::new(IPAssignFailureMessage {
             header: Some(header),
@@ -75,21 +111,47 @@ impl ToFromFrame for IPAssignFailureMessage {
         }))
     }
 
-    fn to_frame(&self, frameid: u8, sender: u8, route: Vec<u8>) -> Frame {
-        // cast the route
-        let route: Vec<u8> = route.clone().iter().map(|i| i.clone() as u8).collect();
+    fn to_frame(&self, frameid: u8, sender: NodeId, route: Vec<NodeId>) -> Frame {
         let routeoffset = route.len() as u8;
 
         let payload = &self.reason;
 
         Frame::new(
-            0u8,
+            TransmissionState::FinalChunk,
             frameid,
-            MessageType::IPAssignFailure as u8,
-            sender as u8,
+            MessageType::IPAssignFailure,
+            sender,
             routeoffset,
             route,
             payload.clone().into_bytes()
         )
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+#[test]
+fn ipassign_tofrom_frame() {
+    let mut msg = IPAssignSuccessMessage::new(2, Ipv4Addr::new(172,16,0,2));
+    let bytes = msg.to_frame(9u8, 1, vec![2]).to_bytes();
+    let assign = IPAssignSuccessMessage::from_frame(&mut Frame::from_bytes(&bytes).unwrap()).unwrap();
+    assert_eq!(assign.nodeid, 2);
+    assert_eq!(assign.ipaddr, msg.ipaddr);
+    assert!(assign.signature.is_none());
+
+    msg.serial = Some(0x0102);
+    msg.signature = Some(vec![7u8; SIGNATURE_LEN]);
+    let bytes = msg.to_frame(9u8, 1, vec![2]).to_bytes();
+    let assign = IPAssignSuccessMessage::from_frame(&mut Frame::from_bytes(&bytes).unwrap()).unwrap();
+    assert_eq!(assign.serial, msg.serial);
+    assert_eq!(assign.signature, msg.signature);
+    assert_eq!(assign.signed_bytes(1), vec![MessageType::IPAssignSuccess as u8, 0, 1, 0, 2, 172, 16, 0, 2, 0, 0, 0, 0, 0, 0, 1, 2]);
+
+    // truncated assignments are rejected
+    let mut short = Frame::new(TransmissionState::FinalChunk, 9u8, MessageType::IPAssignSuccess, 1, 1u8, vec![2], vec![172u8, 16]);
+    assert!(IPAssignSuccessMessage::from_frame(&mut short).is_err());
+
+    // failure reasons must be text
+    let mut failure = IPAssignFailureMessage::new(String::from("No addresses left")).to_frame(9u8, 1, vec![2]);
+    assert_eq!(IPAssignFailureMessage::from_frame(&mut failure).unwrap().reason, "No addresses left");
+    let mut garbled = Frame::new(TransmissionState::FinalChunk, 9u8, MessageType::IPAssignFailure, 1, 1u8, vec![2], vec![0xffu8, 0xfe]);
+    assert_eq!(IPAssignFailureMessage::from_frame(&mut garbled).err(), Some(FrameError::InvalidUtf8));
+}
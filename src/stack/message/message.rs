@@ -6,7 +6,7 @@ use crate::stack::frame::{FrameHeader, ToFromFrame};
 use crate::stack::util::{parse_ipv4};
 
 /// Defines the type of message in the protocol.
-#[derive(Clone, PartialEq, Debug, N)]
+#[derive(Clone, Copy, PartialEq, Debug, N)]
 pub enum MessageType {
     Broadcast = 1,
     IPAssignSuccess = 2,
@@ -17,10 +17,12 @@ pub enum MessageType {
     TransmitRequest = 7,
     TransmitConfirm = 8,
     IPPacket = 9,
+    Ack = 10,
+    CompressedIPPacket = 11,
 }
 
 impl MessageType {
-    pub fn to_u8(&self) -> u8 {
+    pub fn to_u8(self) -> u8 {
         match self {
             MessageType::Broadcast => 1 as u8,
             MessageType::IPAssignSuccess => 2 as u8,
@@ -31,24 +33,242 @@ impl MessageType {
             MessageType::TransmitRequest => 7 as u8,
             MessageType::TransmitConfirm => 8 as u8,
             MessageType::IPPacket => 9 as u8,
+            MessageType::Ack => 10 as u8,
+            MessageType::CompressedIPPacket => 11 as u8,
         }
     }
+
+    /// Whether each hop acknowledges frames of this type
+    /* Only frames sent along a route to a single next hop are acknowledged.
+    Floods like broadcasts and route discoveries have no single receiver to
+    answer, and RTS/CTS exchanges already retry on their own. */
+    pub fn acknowledged(&self) -> bool {
+        matches!(self,
+            MessageType::IPAssignSuccess | MessageType::IPAssignFailure
+            | MessageType::RouteSuccess | MessageType::RouteFailure
+            | MessageType::IPPacket | MessageType::CompressedIPPacket)
+    }
+
+    /// Whether frames of this type carry an IP packet along a route
+    pub fn is_ip_packet(&self) -> bool {
+        matches!(self, MessageType::IPPacket | MessageType::CompressedIPPacket)
+    }
+}
+#[cfg(test)]
+use crate::stack::*;
+#[cfg(test)]
+use crate::stack::signing::SIGNATURE_LEN;
+#[cfg(test)]
+use crate::stack::capabilities::Capabilities;
+#[cfg(test)]
+use packet::ip::v4::Packet;
+#[cfg(test)]
+use proptest::prelude::*;
+
+/// send a message over the air and back, checking nothing changed
+#[cfg(test)]
+fn roundtrip<M: ToFromFrame>(msg: &M, route: Vec<NodeId>) -> Result<Box<M>, TestCaseError> {
+    let bytes = msg.to_frame(5, 1, route.clone()).to_bytes();
+    let mut frame = Frame::from_bytes(&bytes).map_err(|e| TestCaseError::fail(e.to_string()))?;
+    let decoded = M::from_frame(&mut frame).map_err(|e| TestCaseError::fail(e.to_string()))?;
+    prop_assert_eq!(decoded.to_frame(5, 1, route).to_bytes(), bytes);
+    Ok(decoded)
+}
+
+/// decode a frame as every message type, none of which may panic
+#[cfg(test)]
+fn decode_all(frame: &Frame) {
+    let _ = BroadcastMessage::from_frame(&mut frame.clone());
+    let _ = IPAssignSuccessMessage::from_frame(&mut frame.clone());
+    let _ = IPAssignFailureMessage::from_frame(&mut frame.clone());
+    let _ = RouteDiscoveryMessage::from_frame(&mut frame.clone());
+    let _ = RouteSuccessMessage::from_frame(&mut frame.clone());
+    let _ = RouteFailureMessage::from_frame(&mut frame.clone());
+    let _ = TransmitRequestMessage::from_frame(&mut frame.clone());
+    let _ = TransmitConfirmMessage::from_frame(&mut frame.clone());
+    let _ = IPPacketMessage::from_frame(&mut frame.clone());
+    let _ = AckMessage::from_frame(&mut frame.clone());
+    let _ = CompressedIPPacketMessage::from_frame(&mut frame.clone());
 }
 
-/// A node is no longer reachable from the sender.
-pub struct RouteFailureMessage {
-    pub header: Option<FrameHeader>,
-    pub failednodeid: u8
+/// IPv4 packets without options, UDP or not, that may be fragmented or not
+#[cfg(test)]
+fn ipv4_packet() -> impl Strategy<Value = Packet<Vec<u8>>> {
+    (any::<u8>(), any::<[u8; 2]>(), any::<bool>(), any::<u8>(), prop_oneof![Just(17u8), any::<u8>()],
+        any::<[u8; 4]>(), any::<[u8; 4]>(), proptest::collection::vec(any::<u8>(), 8..120))
+        .prop_map(|(tos, id, df, ttl, protocol, src, dst, payload)| {
+            let length = (20 + payload.len()) as u16;
+            let mut bytes = vec![0x45u8, tos];
+            bytes.extend_from_slice(&length.to_be_bytes());
+            bytes.extend_from_slice(&id);
+            bytes.extend_from_slice(&[if df { 0x40u8 } else { 0u8 }, 0u8, ttl, protocol, 0u8, 0u8]);
+            bytes.extend_from_slice(&src);
+            bytes.extend_from_slice(&dst);
+            bytes.extend(payload);
+            Packet::new(bytes).unwrap()
+        })
 }
 
-/// Request destination node if okay to transmit.
-pub struct TransmitRequestMessage {
-    pub header: Option<FrameHeader>,
-    pub dest: u8 // the intended receiver
+#[cfg(test)]
+fn ipv4addr() -> impl Strategy<Value = Ipv4Addr> {
+    any::<[u8; 4]>().prop_map(Ipv4Addr::from)
+}
+
+#[cfg(test)]
+fn signature() -> impl Strategy<Value = Option<Vec<u8>>> {
+    proptest::option::of(proptest::collection::vec(any::<u8>(), SIGNATURE_LEN))
+}
+
+#[cfg(test)]
+proptest! {
+    #[test]
+    fn message_decode_arbitrary(
+        msgtype in 1u8..12,
+        route in proptest::collection::vec(any::<NodeId>(), 0..4),
+        payload in proptest::collection::vec(any::<u8>(), 0..200),
+    ) {
+        let frame = Frame::new(TransmissionState::FinalChunk, 5, MessageType::n(msgtype).unwrap(), 1, route.len() as u8, route, payload);
+        decode_all(&frame);
+    }
+
+    #[test]
+    fn message_decode_mutated(
+        packet in ipv4_packet(),
+        path in proptest::collection::vec(any::<NodeId>(), 0..8),
+        flips in proptest::collection::vec((any::<usize>(), any::<u8>()), 1..4),
+    ) {
+        // valid frames of every type with some bytes changed
+        let route = vec![1, 2];
+        let frames = vec![
+            BroadcastMessage { header: None, isgateway: true, ipOffset: 4, ipaddr: Some(Ipv4Addr::new(172,16,0,1)), epoch: Some(7), capabilities: Some(Capabilities::ours()), subnet: None, serial: Some(3), signature: None }.to_frame(5, 1, route.clone()),
+            IPAssignSuccessMessage::new(2, Ipv4Addr::new(172,16,0,2)).to_frame(5, 1, route.clone()),
+            IPAssignFailureMessage::new(String::from("No addresses left")).to_frame(5, 1, route.clone()),
+            RouteDiscoveryMessage::new(Ipv4Addr::new(172,16,0,2)).to_frame(5, 1, route.clone()),
+            RouteSuccessMessage::new(Ipv4Addr::new(172,16,0,2), path).to_frame(5, 1, route.clone()),
+            RouteFailureMessage::new(2).to_frame(5, 1, route.clone()),
+            TransmitRequestMessage::new(2, 300u32).to_frame(5, 1, route.clone()),
+            TransmitConfirmMessage::new(1, 300u32).to_frame(5, 1, route.clone()),
+            IPPacketMessage::new(packet.clone()).to_frame(5, 1, route.clone()),
+            AckMessage::new(1, 5, 0).to_frame(5, 1, route.clone()),
+            CompressedIPPacketMessage::new(packet).to_frame(5, 1, route.clone()),
+        ];
+        for mut frame in frames {
+            let mut bytes = frame.to_bytes();
+            for (index, byte) in &flips {
+                let len = bytes.len();
+                bytes[index % len] = *byte;
+            }
+            if let Ok(frame) = Frame::from_bytes(&bytes) {
+                decode_all(&frame);
+            }
+        }
+    }
+
+    #[test]
+    fn message_broadcast_roundtrip(
+        isgateway in any::<bool>(),
+        ipaddr in proptest::option::of(ipv4addr()),
+        epoch in proptest::option::of(any::<u32>()),
+        capabilities in proptest::option::of(any::<[u8; 3]>()),
+        subnet in proptest::option::of((any::<[u8; 3]>(), 8u8..=30)),
+        serial in proptest::option::of(any::<u64>()),
+        signature in signature(),
+    ) {
+        let ipOffset = if ipaddr.is_some() { 4 } else { 0 };
+        // gateways always broadcast their address
+        let isgateway = isgateway && ipaddr.is_some();
+        let capabilities = capabilities.map(|bytes| Capabilities::from_bytes(&bytes).unwrap());
+        let subnet = subnet.map(|(host, prefixlen)| MeshSubnet::new(Ipv4Addr::new(10, host[0], host[1], host[2]), prefixlen).unwrap());
+        let msg = BroadcastMessage { header: None, isgateway, ipOffset, ipaddr, epoch, capabilities, subnet, serial, signature };
+        let decoded = roundtrip(&msg, vec![1])?;
+        prop_assert_eq!(decoded.isgateway, msg.isgateway);
+        prop_assert_eq!(decoded.ipaddr, msg.ipaddr);
+        prop_assert_eq!(decoded.epoch, msg.epoch);
+        prop_assert_eq!(decoded.capabilities, msg.capabilities);
+        prop_assert_eq!(decoded.subnet, msg.subnet);
+        prop_assert_eq!(decoded.serial, msg.serial);
+        prop_assert_eq!(decoded.signature, msg.signature);
+    }
+
+    #[test]
+    fn message_ipassign_roundtrip(nodeid in any::<NodeId>(), ipaddr in ipv4addr(), serial in any::<u64>(), signature in signature(), reason in ".{0,100}") {
+        // the serial is only sent along with a signature
+        let mut msg = IPAssignSuccessMessage::new(nodeid, ipaddr);
+        msg.serial = signature.as_ref().map(|_| serial);
+        msg.signature = signature;
+        let decoded = roundtrip(&msg, vec![nodeid])?;
+        prop_assert_eq!(decoded.nodeid, nodeid);
+        prop_assert_eq!(decoded.ipaddr, ipaddr);
+        prop_assert_eq!(decoded.serial, msg.serial);
+        prop_assert_eq!(decoded.signature, msg.signature);
+
+        let decoded = roundtrip(&IPAssignFailureMessage::new(reason.clone()), vec![nodeid])?;
+        prop_assert_eq!(decoded.reason, reason);
+    }
+
+    #[test]
+    fn message_route_roundtrip(target in ipv4addr(), path in proptest::collection::vec(any::<NodeId>(), 0..50), failed in any::<NodeId>()) {
+        prop_assert_eq!(roundtrip(&RouteDiscoveryMessage::new(target), Vec::new())?.target, target);
+        let decoded = roundtrip(&RouteSuccessMessage::new(target, path.clone()), path.clone())?;
+        prop_assert_eq!(decoded.target, target);
+        prop_assert_eq!(&decoded.path, &path);
+        prop_assert_eq!(roundtrip(&RouteFailureMessage::new(failed), path)?.failednodeid, failed);
+    }
+
+    #[test]
+    fn message_transmit_roundtrip(node in any::<NodeId>(), duration in any::<u32>()) {
+        let decoded = roundtrip(&TransmitRequestMessage::new(node, duration), vec![node])?;
+        prop_assert_eq!((decoded.dest, decoded.duration), (node, duration));
+        let decoded = roundtrip(&TransmitConfirmMessage::new(node, duration), vec![node])?;
+        prop_assert_eq!((decoded.requester, decoded.duration), (node, duration));
+    }
+
+    #[test]
+    fn message_ack_roundtrip(sender in any::<NodeId>(), frameid in any::<u8>(), chunk in any::<u8>()) {
+        prop_assert_eq!(roundtrip(&AckMessage::new(sender, frameid, chunk), vec![2])?.key(), (sender, frameid, chunk));
+    }
+
+    #[test]
+    fn message_ippacket_roundtrip(packet in ipv4_packet(), dest in any::<NodeId>()) {
+        let decoded = roundtrip(&IPPacketMessage::new(packet.clone()), vec![1, dest])?;
+        prop_assert_eq!(decoded.packet().as_ref().to_vec(), packet.as_ref().to_vec());
+
+        // compressed headers lose checksums and the identification of
+        // unfragmentable packets, a packet rebuilt once goes through unchanged
+        let rebuilt = roundtrip(&CompressedIPPacketMessage::new(packet.clone()), vec![1, dest])?.packet();
+        let (original, bytes) = (packet.as_ref(), rebuilt.as_ref());
+        let transport = if original[9] == 17 { 8 } else { 0 };
+        prop_assert_eq!(&bytes[8..10], &original[8..10]);
+        prop_assert_eq!(&bytes[12..20], &original[12..20]);
+        prop_assert_eq!(&bytes[(20+transport)..], &original[(20+transport)..]);
+        let decoded = roundtrip(&CompressedIPPacketMessage::new(rebuilt.clone()), vec![1, dest])?;
+        prop_assert_eq!(decoded.packet().as_ref().to_vec(), rebuilt.as_ref().to_vec());
+    }
 }
 
-/// Confirm to original requester that it is okay to transmit.
-pub struct TransmitConfirmMessage {
-    pub header: Option<FrameHeader>,
-    pub requester: u8 // the original requester
-}
\ No newline at end of file
+#[cfg(test)]
+#[test]
+fn message_control_frames_uncompressed() {
+    // broadcasts and control frames go out plain even when they'd shrink,
+    // a node that doesn't understand LZ4 takes the payload as it is
+    let reason = "No addresses left ".repeat(8);
+    let path = vec![7 as NodeId; 40];
+    let mut frames = [
+        BroadcastMessage { header: None, isgateway: true, ipOffset: 4, ipaddr: Some(Ipv4Addr::new(172,16,0,1)), epoch: Some(7), capabilities: Some(Capabilities::ours()), subnet: None, serial: Some(3), signature: Some(vec![0u8; SIGNATURE_LEN]) }.to_frame(5, 1, Vec::new()),
+        IPAssignSuccessMessage::new(2, Ipv4Addr::new(172,16,0,2)).to_frame(5, 1, vec![2]),
+        IPAssignFailureMessage::new(reason.clone()).to_frame(5, 1, vec![2]),
+        RouteSuccessMessage::new(Ipv4Addr::new(172,16,0,2), path.clone()).to_frame(5, 1, vec![2]),
+        TransmitRequestMessage::new(2, 0u32).to_frame(5, 1, vec![2]),
+        AckMessage::new(1, 5, 0).to_frame(5, 1, vec![2]),
+    ];
+    for frame in frames.iter_mut() {
+        let bytes = frame.to_bytes();
+        assert_eq!(bytes[1] & FLAG_COMPRESSED, 0);
+        let len = header_len(&bytes).unwrap();
+        assert_eq!(&bytes[len..], &frame.payload()[..]);
+    }
+    let mut failure = Frame::from_bytes(&frames[2].to_bytes()).unwrap();
+    assert_eq!(IPAssignFailureMessage::from_frame(&mut failure).unwrap().reason, reason);
+    let mut success = Frame::from_bytes(&frames[3].to_bytes()).unwrap();
+    assert_eq!(RouteSuccessMessage::from_frame(&mut success).unwrap().path, path);
+}
@@ -1,11 +1,22 @@
-pub(crate) mod message;
-pub(crate) use message::*;
+pub mod message;
+pub use message::*;
 
-pub(crate) mod broadcast;
-pub(crate) use broadcast::*;
+pub mod broadcast;
+pub use broadcast::*;
 
-pub(crate) mod ippacket;
-pub(crate) use ippacket::*;
+pub mod ippacket;
+pub use ippacket::*;
 
-pub(crate) mod ipassign;
-pub(crate) use ipassign::*;
+pub mod iphc;
+pub use iphc::*;
+
+pub mod ipassign;
+pub use ipassign::*;
+
+pub mod transmit;
+pub use transmit::*;
+
+pub mod route;
+pub use route::*;
+pub mod ack;
+pub use ack::*;
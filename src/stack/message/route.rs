@@ -0,0 +1,159 @@
+use std::net::Ipv4Addr;
+use crate::error::FrameError;
+use crate::stack::{Frame, MessageType};
+use crate::stack::frame::{FrameHeader, NodeId, ToFromFrame, TransmissionState};
+use crate::stack::util::{parse_ipv4, parse_nodeid};
+
+/// Ask the mesh for a path to a node that owns an IP address.
+/* Flooded like a broadcast, each node relaying it prepends itself to the
+frame's route so the target learns the reverse path to the origin. */
+pub struct RouteDiscoveryMessage {
+    pub header: Option<FrameHeader>,
+    pub target: Ipv4Addr
+}
+
+impl RouteDiscoveryMessage {
+    pub fn new(target: Ipv4Addr) -> Self {
+        RouteDiscoveryMessage{ header: None, target }
+    }
+}
+
+impl ToFromFrame for RouteDiscoveryMessage {
+    fn from_frame(f: &mut Frame) -> Result<Box<Self>, FrameError> {
+        let header = f.header();
+        let data = f.payload();
+        let octets = data.get(0..4).ok_or(FrameError::Truncated("target"))?;
+
+        Ok(Box::new(RouteDiscoveryMessage {
+            header: Some(header),
+            target: parse_ipv4(octets)
+        }))
+    }
+
+    fn to_frame(&self, frameid: u8, sender: NodeId, route: Vec<NodeId>) -> Frame {
+        let routeoffset = route.len() as u8;
+
+        // write the payload
+        let payload: Vec<u8> = Vec::from(&self.target.octets()[..]);
+
+        Frame::new(
+            TransmissionState::FinalChunk,
+            frameid,
+            MessageType::RouteDiscovery,
+            sender,
+            routeoffset,
+            route,
+            payload
+        )
+    }
+}
+
+/// Answer to a route discovery, sent back by the target along the reverse path.
+pub struct RouteSuccessMessage {
+    pub header: Option<FrameHeader>,
+    pub target: Ipv4Addr,
+    pub path: Vec<NodeId> // node IDs from the origin to the target
+}
+
+impl RouteSuccessMessage {
+    pub fn new(target: Ipv4Addr, path: Vec<NodeId>) -> Self {
+        RouteSuccessMessage{ header: None, target, path }
+    }
+}
+
+impl ToFromFrame for RouteSuccessMessage {
+    fn from_frame(f: &mut Frame) -> Result<Box<Self>, FrameError> {
+        let header = f.header();
+        let data = f.payload();
+        let octets = data.get(0..4).ok_or(FrameError::Truncated("target"))?;
+        let pathlen = *data.get(4).ok_or(FrameError::Truncated("path length"))? as usize;
+        let path = data.get(5..(5+2*pathlen)).ok_or(FrameError::Truncated("path"))?;
+
+        Ok(Box::new(RouteSuccessMessage {
+            header: Some(header),
+            target: parse_ipv4(octets),
+            path: path.chunks(2).map(parse_nodeid).collect()
+        }))
+    }
+
+    fn to_frame(&self, frameid: u8, sender: NodeId, route: Vec<NodeId>) -> Frame {
+        let routeoffset = route.len() as u8;
+
+        // write the payload
+        let mut payload: Vec<u8> = Vec::from(&self.target.octets()[..]);
+        payload.push(self.path.len() as u8);
+        self.path.iter().for_each(|n| payload.extend_from_slice(&n.to_be_bytes()));
+
+        Frame::new(
+            TransmissionState::FinalChunk,
+            frameid,
+            MessageType::RouteSuccess,
+            sender,
+            routeoffset,
+            route,
+            payload
+        )
+    }
+}
+
+/// A node is no longer reachable from the sender.
+pub struct RouteFailureMessage {
+    pub header: Option<FrameHeader>,
+    pub failednodeid: NodeId
+}
+
+impl RouteFailureMessage {
+    pub fn new(failednodeid: NodeId) -> Self {
+        RouteFailureMessage{ header: None, failednodeid }
+    }
+}
+
+impl ToFromFrame for RouteFailureMessage {
+    fn from_frame(f: &mut Frame) -> Result<Box<Self>, FrameError> {
+        let header = f.header();
+        let data = f.payload();
+        let failednodeid = parse_nodeid(data.get(0..2).ok_or(FrameError::Truncated("failed node"))?);
+
+        Ok(Box::new(RouteFailureMessage {
+            header: Some(header),
+            failednodeid
+        }))
+    }
+
+    fn to_frame(&self, frameid: u8, sender: NodeId, route: Vec<NodeId>) -> Frame {
+        let routeoffset = route.len() as u8;
+
+        Frame::new(
+            TransmissionState::FinalChunk,
+            frameid,
+            MessageType::RouteFailure,
+            sender,
+            routeoffset,
+            route,
+            Vec::from(&self.failednodeid.to_be_bytes()[..])
+        )
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn route_tofrom_frame() {
+    let target = Ipv4Addr::new(172,16,0,9);
+    let bytes = RouteDiscoveryMessage::new(target).to_frame(3u8, 1, vec![1]).to_bytes();
+    let mut frame = Frame::from_bytes(&bytes).unwrap();
+    assert_eq!(frame.msgtype(), MessageType::RouteDiscovery);
+    assert_eq!(RouteDiscoveryMessage::from_frame(&mut frame).unwrap().target, target);
+
+    let path: Vec<NodeId> = vec![1, 4, 300];
+    let bytes = RouteSuccessMessage::new(target, path.clone()).to_frame(3, 9, vec![4, 1]).to_bytes();
+    let mut frame = Frame::from_bytes(&bytes).unwrap();
+    assert_eq!(frame.msgtype(), MessageType::RouteSuccess);
+    let msg = RouteSuccessMessage::from_frame(&mut frame).unwrap();
+    assert_eq!(msg.target, target);
+    assert_eq!(msg.path, path);
+
+    let bytes = RouteFailureMessage::new(4).to_frame(3, 9, vec![1]).to_bytes();
+    let mut frame = Frame::from_bytes(&bytes).unwrap();
+    assert_eq!(frame.msgtype(), MessageType::RouteFailure);
+    assert_eq!(RouteFailureMessage::from_frame(&mut frame).unwrap().failednodeid, 4);
+}
@@ -0,0 +1,123 @@
+use std::convert::TryInto;
+use crate::error::FrameError;
+use crate::stack::{Frame, MessageType};
+use crate::stack::frame::{FrameHeader, NodeId, ToFromFrame, TransmissionState};
+use crate::stack::util::parse_nodeid;
+
+/// Request destination node if okay to transmit.
+pub struct TransmitRequestMessage {
+    pub header: Option<FrameHeader>,
+    pub dest: NodeId, // the intended receiver
+    pub duration: u32 // how long (ms) the transfer will occupy the channel
+}
+
+impl TransmitRequestMessage {
+    pub fn new(dest: NodeId, duration: u32) -> Self {
+        TransmitRequestMessage{ header: None, dest, duration }
+    }
+}
+
+impl ToFromFrame for TransmitRequestMessage {
+    fn from_frame(f: &mut Frame) -> Result<Box<Self>, FrameError> {
+        let header = f.header();
+        let data = f.payload();
+        let dest = parse_nodeid(data.get(0..2).ok_or(FrameError::Truncated("destination"))?);
+        let duration = data.get(2..6).ok_or(FrameError::Truncated("duration"))?;
+
+        Ok(Box::new(TransmitRequestMessage {
+            header: Some(header),
+            dest,
+            duration: u32::from_be_bytes(duration.try_into().unwrap())
+        }))
+    }
+
+    fn to_frame(&self, frameid: u8, sender: NodeId, route: Vec<NodeId>) -> Frame {
+        let routeoffset = route.len() as u8;
+
+        // write the payload
+        let mut payload: Vec<u8> = Vec::new();
+        payload.extend_from_slice(&self.dest.to_be_bytes());
+        payload.extend_from_slice(&self.duration.to_be_bytes());
+
+        Frame::new(
+            TransmissionState::FinalChunk,
+            frameid,
+            MessageType::TransmitRequest,
+            sender,
+            routeoffset,
+            route,
+            payload
+        )
+    }
+}
+
+/// Confirm to original requester that it is okay to transmit.
+pub struct TransmitConfirmMessage {
+    pub header: Option<FrameHeader>,
+    pub requester: NodeId, // the original requester
+    pub duration: u32 // how long (ms) others should stay off the channel
+}
+
+impl TransmitConfirmMessage {
+    pub fn new(requester: NodeId, duration: u32) -> Self {
+        TransmitConfirmMessage{ header: None, requester, duration }
+    }
+}
+
+impl ToFromFrame for TransmitConfirmMessage {
+    fn from_frame(f: &mut Frame) -> Result<Box<Self>, FrameError> {
+        let header = f.header();
+        let data = f.payload();
+        let requester = parse_nodeid(data.get(0..2).ok_or(FrameError::Truncated("requester"))?);
+        let duration = data.get(2..6).ok_or(FrameError::Truncated("duration"))?;
+
+        Ok(Box::new(TransmitConfirmMessage {
+            header: Some(header),
+            requester,
+            duration: u32::from_be_bytes(duration.try_into().unwrap())
+        }))
+    }
+
+    fn to_frame(&self, frameid: u8, sender: NodeId, route: Vec<NodeId>) -> Frame {
+        let routeoffset = route.len() as u8;
+
+        // write the payload
+        let mut payload: Vec<u8> = Vec::new();
+        payload.extend_from_slice(&self.requester.to_be_bytes());
+        payload.extend_from_slice(&self.duration.to_be_bytes());
+
+        Frame::new(
+            TransmissionState::FinalChunk,
+            frameid,
+            MessageType::TransmitConfirm,
+            sender,
+            routeoffset,
+            route,
+            payload
+        )
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn transmit_tofrom_frame() {
+    let rts = TransmitRequestMessage::new(4, 2500);
+    let bytes = rts.to_frame(9u8, 2, vec![4]).to_bytes();
+    let mut frame = Frame::from_bytes(&bytes).unwrap();
+    assert_eq!(frame.msgtype(), MessageType::TransmitRequest);
+    let rts2 = TransmitRequestMessage::from_frame(&mut frame).unwrap();
+    assert_eq!(rts2.dest, 4);
+    assert_eq!(rts2.duration, 2500);
+
+    let cts = TransmitConfirmMessage::new(2, 2500);
+    let bytes = cts.to_frame(9u8, 4, vec![2]).to_bytes();
+    let mut frame = Frame::from_bytes(&bytes).unwrap();
+    assert_eq!(frame.msgtype(), MessageType::TransmitConfirm);
+    let cts2 = TransmitConfirmMessage::from_frame(&mut frame).unwrap();
+    assert_eq!(cts2.requester, 2);
+    assert_eq!(cts2.duration, 2500);
+
+    // truncated payloads are rejected
+    let mut short = Frame::new(TransmissionState::FinalChunk, 9u8, MessageType::TransmitConfirm, 4, 0u8, Vec::new(), vec![2u8, 0]);
+    assert!(TransmitConfirmMessage::from_frame(&mut short).is_err());
+}
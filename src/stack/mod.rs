@@ -1,15 +1,52 @@
 pub(crate) mod chunk;
 
-pub(crate) mod frame;
-pub(crate) use frame::*;
+pub(crate) mod compression;
 
-pub(crate) mod message;
-pub(crate) use message::*;
+pub mod frame;
+pub use frame::*;
+
+pub mod capabilities;
+pub use capabilities::Capabilities;
+
+pub mod subnet;
+pub use subnet::MeshSubnet;
+
+pub mod message;
+pub use message::*;
+
+pub(crate) mod scheduler;
+pub(crate) use scheduler::TdmaScheduler;
+
+pub(crate) mod replay;
+
+pub(crate) mod cipher;
+pub(crate) use cipher::FrameCipher;
+
+pub(crate) mod signing;
+pub(crate) use signing::GatewayKeys;
+
+pub(crate) mod arq;
+pub(crate) use arq::LinkArq;
+
+pub(crate) mod discovery;
+pub(crate) use discovery::RouteDiscovery;
+
+pub(crate) mod reservation;
+pub(crate) use reservation::{Reservations, Unconfirmed};
+
+pub mod reassembly;
+pub use reassembly::Reassembly;
+
+pub(crate) mod lease;
+pub(crate) use lease::Leases;
+
+pub(crate) mod state;
+pub(crate) use state::GatewayState;
 
 pub(crate) mod router;
 pub(crate) use router::MeshRouter;
 
-pub(crate) mod tun;
-pub(crate) use tun::NetworkTunnel;
+pub mod tun;
+pub use tun::{NetworkTunnel, Tunnel};
 
 pub(crate) mod util;
\ No newline at end of file
@@ -0,0 +1,164 @@
+use log::*;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use crate::error::FrameError;
+use crate::stack::{Frame, NodeId};
+use crate::stack::frame::{recombine_chunks, TransmissionState};
+
+/// The chunks of one frame received so far
+struct Partial {
+    chunks: Vec<Option<Frame>>,
+    deadline: Instant,
+}
+
+/// Puts chunked frames back together as their chunks arrive
+/* Chunks are keyed on their original sender and frame ID, and placed by
+their index so they can arrive in any order. Copies of a chunk we already
+have are dropped, and frames still missing chunks after the timeout are
+given up on so the buffer doesn't grow forever. A chunk flagged as the
+last one of the sender's transmit slot gives the rest another timeout. */
+pub struct Reassembly {
+    timeout: Duration,
+    partial: HashMap<(NodeId, u8), Partial>,
+}
+
+impl Reassembly {
+    pub fn new(timeout: Duration) -> Self {
+        Reassembly { timeout, partial: HashMap::new() }
+    }
+
+    /// Add a received frame, returns the whole frame once all of its chunks arrived
+    pub fn push(&mut self, mut frame: Frame) -> Result<Option<Frame>, FrameError> {
+        self.expire();
+        let count = frame.chunks() as usize;
+        if count <= 1 {
+            return Ok(Some(frame));
+        }
+        let index = frame.chunk() as usize;
+        if index >= count {
+            return Err(FrameError::InvalidChunk { chunk: index as u8, chunks: count as u8 });
+        }
+
+        let key = (frame.sender(), frame.frameid());
+        let deadline = Instant::now() + self.timeout;
+        let partial = self.partial.entry(key).or_insert_with(|| Partial {
+            chunks: vec![None; count],
+            deadline,
+        });
+        // the frame ID was reused for another frame
+        if partial.chunks.len() != count {
+            debug!("Frame {:?} changed from {} to {} chunks, starting over", &key, &partial.chunks.len(), &count);
+            *partial = Partial { chunks: vec![None; count], deadline };
+        }
+        if partial.chunks[index].is_some() {
+            trace!("Dropping duplicate chunk {} of frame {:?}", &index, &key);
+            return Ok(None);
+        }
+        // the sender resumes in its next transmit slot
+        if frame.txflag() == TransmissionState::SlotExceeded {
+            trace!("Sender of frame {:?} ran out of its transmit slot, waiting longer", &key);
+            partial.deadline = deadline;
+        }
+        partial.chunks[index] = Some(frame);
+        if partial.chunks.iter().any(|chunk| chunk.is_none()) {
+            return Ok(None);
+        }
+
+        trace!("Recombining {} chunks of frame {:?}", &count, &key);
+        let chunks: Vec<Frame> = self.partial.remove(&key).unwrap().chunks.into_iter().flatten().collect();
+        let header = chunks[count - 1].clone().header();
+        recombine_chunks(chunks, header).map(Some)
+    }
+
+    /// Give up on frames still missing chunks after the timeout
+    pub fn expire(&mut self) {
+        let now = Instant::now();
+        self.partial.retain(|key, partial| {
+            let fresh = partial.deadline > now;
+            if !fresh {
+                debug!("Dropping frame {:?}, {} of {} chunks received", key, partial.chunks.iter().flatten().count(), partial.chunks.len());
+            }
+            fresh
+        });
+    }
+
+    /// Number of frames waiting for more chunks
+    pub fn pending(&self) -> usize {
+        self.partial.len()
+    }
+}
+
+#[cfg(test)]
+use crate::stack::MessageType;
+#[cfg(test)]
+use crate::stack::frame::mark_slot_exceeded;
+#[cfg(test)]
+use std::thread;
+
+#[cfg(test)]
+#[test]
+fn reassembly_out_of_order() {
+    let payload: Vec<u8> = (0..100u8).collect();
+    let mut frame = Frame::new(TransmissionState::FinalChunk, 7u8, MessageType::IPPacket, 1, 2u8, vec![1, 2], payload.clone());
+    let chunks: Vec<Frame> = frame.chunked(&30usize).unwrap().iter().map(|chunk| Frame::from_bytes(chunk).unwrap()).collect();
+    assert_eq!(chunks.len(), 4);
+
+    // reordered and duplicated chunks still make up the frame
+    let mut reassembly = Reassembly::new(Duration::from_secs(10));
+    assert!(reassembly.push(chunks[2].clone()).unwrap().is_none());
+    assert!(reassembly.push(chunks[0].clone()).unwrap().is_none());
+    assert!(reassembly.push(chunks[2].clone()).unwrap().is_none());
+    assert!(reassembly.push(chunks[3].clone()).unwrap().is_none());
+    let mut whole = reassembly.push(chunks[1].clone()).unwrap().expect("Frame was not reassembled");
+    assert_eq!(whole.payload(), payload);
+    assert_eq!(whole.chunks(), 1u8);
+    assert_eq!(reassembly.pending(), 0);
+
+    // frames that weren't chunked pass straight through
+    let single = Frame::new(TransmissionState::FinalChunk, 8u8, MessageType::IPPacket, 1, 0u8, Vec::new(), vec![1u8]);
+    assert!(reassembly.push(single).unwrap().is_some());
+}
+
+#[cfg(test)]
+#[test]
+fn reassembly_timeout() {
+    let mut frame = Frame::new(TransmissionState::FinalChunk, 7u8, MessageType::IPPacket, 1, 0u8, Vec::new(), (0..100u8).collect());
+    let chunks: Vec<Frame> = frame.chunked(&30usize).unwrap().iter().map(|chunk| Frame::from_bytes(chunk).unwrap()).collect();
+
+    // incomplete frames are evicted
+    let mut reassembly = Reassembly::new(Duration::from_millis(5));
+    assert!(reassembly.push(chunks[0].clone()).unwrap().is_none());
+    assert_eq!(reassembly.pending(), 1);
+    thread::sleep(Duration::from_millis(20));
+    reassembly.expire();
+    assert_eq!(reassembly.pending(), 0);
+
+    // and the rest of their chunks start a new frame
+    for chunk in &chunks[1..] {
+        assert!(reassembly.push(chunk.clone()).unwrap().is_none());
+    }
+
+    // chunks beyond the count are rejected
+    let mut bogus = chunks[0].clone().to_bytes();
+    bogus[3] = 9u8;
+    assert_eq!(Frame::from_bytes(&bogus).err(), Some(FrameError::InvalidChunk { chunk: 9, chunks: 4 }));
+}
+
+#[cfg(test)]
+#[test]
+fn reassembly_slot_exceeded() {
+    let mut frame = Frame::new(TransmissionState::FinalChunk, 7u8, MessageType::IPPacket, 1, 0u8, Vec::new(), (0..90u8).collect());
+    let mut chunks: Vec<Vec<u8>> = frame.chunked(&30usize).unwrap();
+    mark_slot_exceeded(&mut chunks[1]);
+
+    // a sender out of its slot gets another timeout for the rest of the frame
+    let mut reassembly = Reassembly::new(Duration::from_millis(60));
+    assert!(reassembly.push(Frame::from_bytes(&chunks[0]).unwrap()).unwrap().is_none());
+    thread::sleep(Duration::from_millis(40));
+    let mut paused = Frame::from_bytes(&chunks[1]).unwrap();
+    assert_eq!(paused.txflag(), TransmissionState::SlotExceeded);
+    assert!(reassembly.push(paused).unwrap().is_none());
+    thread::sleep(Duration::from_millis(40));
+    let mut whole = reassembly.push(Frame::from_bytes(&chunks[2]).unwrap()).unwrap().expect("Frame expired");
+    assert_eq!(whole.payload(), (0..90u8).collect::<Vec<u8>>());
+}
@@ -0,0 +1,81 @@
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind};
+use crate::stack::NodeId;
+
+/// How many counters behind the newest one are still accepted
+pub const REPLAY_WINDOW: u64 = 64;
+
+/// Counters recently received from one transmitter
+struct Window {
+    highest: u64,
+    /// bit n is set if counter `highest - n` was received
+    bitmap: u64,
+}
+
+/// Rejects frames whose counter was already used or is too old
+/* Every transmitter numbers its frames with a counter that only goes up.
+We remember the newest counter from each transmitter and which of the
+ones just before it arrived, like IPsec's anti-replay window, so frames
+reordered by retransmissions still get through but a captured frame sent
+again does not. */
+pub struct ReplayWindows {
+    windows: HashMap<NodeId, Window>,
+}
+
+impl ReplayWindows {
+    pub fn new() -> Self {
+        ReplayWindows { windows: HashMap::new() }
+    }
+
+    /// Accept a counter from a transmitter, errors on stale or duplicate counters
+    pub fn check(&mut self, transmitter: NodeId, counter: u64) -> std::io::Result<()> {
+        let window = match self.windows.get_mut(&transmitter) {
+            None => {
+                self.windows.insert(transmitter, Window { highest: counter, bitmap: 1 });
+                return Ok(());
+            },
+            Some(window) => window,
+        };
+
+        if counter > window.highest {
+            let shift = counter - window.highest;
+            window.bitmap = if shift >= REPLAY_WINDOW { 0 } else { window.bitmap << shift };
+            window.bitmap |= 1;
+            window.highest = counter;
+            return Ok(());
+        }
+
+        let age = window.highest - counter;
+        if age >= REPLAY_WINDOW {
+            return Err(Error::new(ErrorKind::InvalidData,
+                format!("Stale counter {} from node {}, newest is {}", counter, transmitter, window.highest)));
+        }
+        if window.bitmap & (1 << age) != 0 {
+            return Err(Error::new(ErrorKind::InvalidData,
+                format!("Duplicate counter {} from node {}", counter, transmitter)));
+        }
+        window.bitmap |= 1 << age;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn replay_window() {
+    let mut windows = ReplayWindows::new();
+    assert!(windows.check(3, 1000).is_ok());
+    assert!(windows.check(3, 1000).is_err());
+
+    // reordered frames are fine, once
+    assert!(windows.check(3, 1002).is_ok());
+    assert!(windows.check(3, 1001).is_ok());
+    assert!(windows.check(3, 1001).is_err());
+
+    // frames older than the window are not
+    assert!(windows.check(3, 1002 + REPLAY_WINDOW).is_ok());
+    assert!(windows.check(3, 1002).is_err());
+    assert!(windows.check(3, 1003).is_ok());
+
+    // each transmitter has its own counters
+    assert!(windows.check(4, 1000).is_ok());
+}
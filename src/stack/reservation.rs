@@ -0,0 +1,110 @@
+use log::*;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use crate::stack::NodeId;
+
+/// A multi-chunk transfer waiting for the next hop's CTS
+struct Transfer {
+    chunks: Vec<Vec<u8>>,
+    requested: Instant,
+    attempts: u8,
+}
+
+/// What to do with a transfer whose RTS went unanswered
+pub enum Unconfirmed {
+    /// ask the next hop again, for this many frames
+    Retry { dest: NodeId, frames: usize },
+    /// give up on the reservation and send the chunks anyway
+    Abandon { dest: NodeId, chunks: Vec<Vec<u8>> },
+}
+
+/// Tracks transfers held back until the next hop confirms with a CTS
+/* Large transfers are announced with a TransmitRequest so that nodes near
+the receiver, which may not hear us, stay quiet once the receiver answers
+with a TransmitConfirm. */
+pub struct Reservations {
+    transfers: HashMap<NodeId, Transfer>,
+    timeout: Duration,
+    maxattempts: u8,
+}
+
+impl Reservations {
+    pub fn new(timeout: Duration, maxattempts: u8) -> Self {
+        Reservations {
+            transfers: HashMap::new(),
+            timeout,
+            maxattempts,
+        }
+    }
+
+    /// Hold chunks bound for the next hop, returns true if a new
+    /// request needs to be sent for them
+    pub fn queue(&mut self, dest: NodeId, chunks: Vec<Vec<u8>>) -> bool {
+        match self.transfers.get_mut(&dest) {
+            Some(transfer) => {
+                // a request is already outstanding, ride along with it
+                transfer.chunks.extend(chunks);
+                false
+            },
+            None => {
+                self.transfers.insert(dest, Transfer { chunks, requested: Instant::now(), attempts: 1 });
+                true
+            }
+        }
+    }
+
+    /// The next hop confirmed, release the chunks waiting for it
+    pub fn confirm(&mut self, dest: NodeId) -> Option<Vec<Vec<u8>>> {
+        self.transfers.remove(&dest).map(|transfer| transfer.chunks)
+    }
+
+    /// Collect transfers that weren't confirmed in time
+    pub fn expired(&mut self) -> Vec<Unconfirmed> {
+        let now = Instant::now();
+        let timeout = self.timeout;
+        let expired: Vec<NodeId> = self.transfers.iter()
+            .filter(|(_, t)| now.duration_since(t.requested) >= timeout)
+            .map(|(dest, _)| *dest)
+            .collect();
+
+        let mut unconfirmed = Vec::new();
+        for dest in expired {
+            let transfer = self.transfers.get_mut(&dest).unwrap();
+            if transfer.attempts < self.maxattempts {
+                transfer.attempts += 1;
+                transfer.requested = now;
+                debug!("No transmit confirmation from {}, retrying", &dest);
+                unconfirmed.push(Unconfirmed::Retry { dest, frames: transfer.chunks.len() });
+            } else {
+                warn!("Node {} never confirmed transmit request", &dest);
+                let transfer = self.transfers.remove(&dest).unwrap();
+                unconfirmed.push(Unconfirmed::Abandon { dest, chunks: transfer.chunks });
+            }
+        }
+        unconfirmed
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn reservations_lifecycle() {
+    let mut reservations = Reservations::new(Duration::from_millis(0), 2);
+
+    // only the first transfer to a node sends a request
+    assert!(reservations.queue(4, vec![vec![1], vec![2]]));
+    assert!(!reservations.queue(4, vec![vec![3]]));
+    assert_eq!(reservations.confirm(4).unwrap().len(), 3);
+    assert!(reservations.confirm(4).is_none());
+
+    // unanswered requests are retried, then abandoned
+    reservations.queue(5, vec![vec![1], vec![2]]);
+    match reservations.expired().pop() {
+        Some(Unconfirmed::Retry { dest, frames }) => assert_eq!((dest, frames), (5, 2)),
+        _ => panic!("Expected a retry"),
+    }
+    match reservations.expired().pop() {
+        Some(Unconfirmed::Abandon { dest, chunks }) => assert_eq!((dest, chunks.len()), (5, 2)),
+        _ => panic!("Expected transfer to be abandoned"),
+    }
+    assert!(reservations.expired().is_empty());
+}
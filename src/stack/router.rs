@@ -10,36 +10,47 @@ use petgraph::data::FromElements;
 use std::cell::{RefCell};
 use std::borrow::{BorrowMut};
 use crate::stack::message::{BroadcastMessage, IPAssignFailureMessage};
+use crate::hardware::LinkMetrics;
+use crate::stack::{Capabilities, GatewayState, Leases, NodeId};
+use crate::stack::state::{from_unix, to_unix, NodeRecord};
+
+/// Weight given to the newest measurement when smoothing link costs
+const LINK_COST_ALPHA: f32 = 0.25;
 
 #[derive(Clone)]
 pub struct MeshRouter {
-    nodeid: u8,
+    nodeid: NodeId,
     gatewayipaddr: Option<Ipv4Addr>,
+    /// addresses we lease to other nodes, when we are the gateway
+    leases: Leases,
     maxhops: u8,
     lastSequenceNumber: u8,
     timeout: Duration,
-    retries: u8,
-    observations: RefCell<HashMap<u8, Instant>>,
-    graph: UnGraphMap<u8, u8>,
-    id2ip: RefCell<HashMap<u8, Ipv4Addr>>,
-    ip2id: RefCell<HashMap<Ipv4Addr, u8>>,
+    observations: RefCell<HashMap<NodeId, Instant>>,
+    /// mesh links, weighted by their expected transmission count
+    graph: UnGraphMap<NodeId, f32>,
+    id2ip: RefCell<HashMap<NodeId, Ipv4Addr>>,
+    ip2id: RefCell<HashMap<Ipv4Addr, NodeId>>,
+    /// what each node advertised it understands
+    capabilities: HashMap<NodeId, Capabilities>,
     isgateway: bool
 
 }
 
 impl MeshRouter {
-    pub fn new(nodeid: u8, gatewayipaddr: Option<Ipv4Addr>, maxhops: u8, timeout: Duration, isgateway: bool) -> Self {
+    pub fn new(nodeid: NodeId, gatewayipaddr: Option<Ipv4Addr>, leases: Leases, maxhops: u8, timeout: Duration, isgateway: bool) -> Self {
         MeshRouter{
             nodeid,
             gatewayipaddr,
+            leases,
             maxhops,
             lastSequenceNumber: 0,
             timeout,
-            retries: 1, // TODO
             observations: RefCell::new(HashMap::new()),
             graph: UnGraphMap::new(),
             id2ip: RefCell::new(HashMap::new()),
             ip2id: RefCell::new(HashMap::new()),
+            capabilities: HashMap::new(),
             isgateway
         }
     }
@@ -51,7 +62,7 @@ impl MeshRouter {
     }
 
     /// Adds a new route to the mesh, fail if route does not exist
-    pub fn route_add(&mut self, route: Vec<(u8, u8)>) {
+    pub fn route_add(&mut self, route: Vec<(NodeId, NodeId)>) {
         route.iter().for_each( |(src, dest)| {
             // we track each observation of every node
             self.node_observe_put(src.clone());
@@ -66,11 +77,13 @@ impl MeshRouter {
         });
     }
 
-    /// Update our router with new IP
+    /// Update our router with new IP, replacing the old one
     pub fn handle_ip_assignment(&mut self, ipaddr: &Ipv4Addr) {
         self.node_add(self.nodeid.clone());
-        self.id2ip.borrow_mut().insert(self.nodeid.clone(), ipaddr.clone());
-        self.ip2id.borrow_mut().insert(ipaddr.clone(), self.nodeid.clone());
+        self.ipaddr_put(self.nodeid, *ipaddr);
+        if self.isgateway {
+            self.leases.reserve(self.nodeid, *ipaddr);
+        }
     }
 
     pub fn handle_gateway_assignment(&mut self, gatewayip: &Ipv4Addr) {
@@ -78,11 +91,11 @@ impl MeshRouter {
     }
 
     /// Handle a network broadcast, maybe node needs an IP?
-    pub fn handle_broadcast(&mut self, broadcast: Box<BroadcastMessage>, route: Vec<u8>) -> Result<Option<(Ipv4Addr, bool)>, IPAssignFailureMessage> {
+    pub fn handle_broadcast(&mut self, broadcast: Box<BroadcastMessage>, route: Vec<NodeId>) -> Result<Option<(Ipv4Addr, bool)>, IPAssignFailureMessage> {
         let srcid = broadcast.header.expect("Broadcast did not have a frame header.").sender();
-        if broadcast.isgateway && srcid != self.nodeid {
-            info!("Gateway {} observed with IP {}", &srcid, &broadcast.ipaddr.expect("Gateways must broadcast their IP"));
-            self.handle_gateway_assignment(&broadcast.ipaddr.unwrap());
+        if let (true, Some(gatewayip)) = (broadcast.isgateway && srcid != self.nodeid, broadcast.ipaddr) {
+            info!("Gateway {} observed with IP {}", &srcid, &gatewayip);
+            self.handle_gateway_assignment(&gatewayip);
         }
 
         // observe our latest sighting
@@ -91,66 +104,221 @@ impl MeshRouter {
             self.node_add(nodeid.clone());
         });
 
-        // add IP to graph
-        if broadcast.ipaddr.is_some() {
-            self.id2ip.borrow_mut().insert(srcid.clone(), broadcast.ipaddr.unwrap());
-            self.ip2id.borrow_mut().insert(broadcast.ipaddr.unwrap(), srcid.clone());
+        // gateways lease addresses to those without, and renew the leases of those with
+        let mut lease = Ok(None);
+        if self.isgateway && srcid != self.nodeid {
+            lease = match (broadcast.ipaddr, broadcast.ipOffset) {
+                (None, 0) => self.ip_assign(srcid).map(Some),
+                (None, _) => Ok(None),
+                // nodes using an address from outside our subnet are moved into it
+                (Some(ipaddr), _) if !self.leases.contains(&ipaddr) => self.ip_assign(srcid).map(Some),
+                (Some(ipaddr), _) => self.ip_claim(srcid, ipaddr).map(|isnew| if isnew { Some((ipaddr, true)) } else { None }),
+            };
+        }
+
+        // add IP to graph, unless it isn't the sender's to use or we moved it
+        let moved = matches!(lease, Ok(Some((ipaddr, _))) if broadcast.ipaddr != Some(ipaddr));
+        if let (Some(ipaddr), Ok(_), false) = (broadcast.ipaddr, &lease, moved) {
+            self.ipaddr_put(srcid, ipaddr);
+        }
+
+        // remember what the sender understands
+        if let Some(capabilities) = broadcast.capabilities {
+            if self.capabilities.insert(srcid, capabilities) != Some(capabilities) {
+                debug!("Node {} speaks protocol versions {} to {} with features {:#04x}", &srcid, &capabilities.minversion, &capabilities.maxversion, &capabilities.features);
+            }
         }
 
         // add edges for each node in the route
         route.windows(2).for_each(|pair| self.edge_add(pair[0], pair[1]));
 
-        // add edge for ourself
-        self.edge_add(self.nodeid, route.last().expect("Received broadcast with empty route").clone());
+        // add edge for ourself, to the neighbour we heard it from
+        if let Some(neighbour) = route.iter().find(|nodeid| **nodeid != self.nodeid) {
+            self.edge_add(self.nodeid, *neighbour);
+        }
+
+        return lease;
+    }
+
+    /// Learn the reverse path carried by a route discovery flood
+    pub fn handle_route_discovery(&mut self, route: &[NodeId]) {
+        route.iter().for_each(|nodeid| {
+            self.node_observe_put(*nodeid);
+            self.node_add(*nodeid);
+        });
+        route.windows(2).for_each(|pair| self.edge_add(pair[0], pair[1]));
+
+        // the last relay is our neighbour
+        if let Some(neighbour) = route.first() {
+            self.edge_add(self.nodeid, *neighbour);
+        }
+    }
 
-        let mut ipaddrtup = None;
-        if broadcast.ipOffset == 0 && self.isgateway {
-            ipaddrtup = Some(self.ip_assign(srcid)?);
+    /// Learn a discovered path and the IP address of its target
+    pub fn handle_route_success(&mut self, target: &Ipv4Addr, path: &[NodeId]) {
+        let pairs: Vec<(NodeId, NodeId)> = path.windows(2).map(|pair| (pair[0], pair[1])).collect();
+        self.route_add(pairs);
+        if let Some(targetid) = path.last() {
+            self.id2ip.borrow_mut().insert(*targetid, *target);
+            self.ip2id.borrow_mut().insert(*target, *targetid);
         }
-        return Ok(ipaddrtup);
     }
 
-    /// Assign IP address to node
-    // TODO implement proper DHCP later
-    fn ip_assign(&mut self, nodeid: u8) -> Result<(Ipv4Addr, bool), IPAssignFailureMessage> {
-        match self.id2ip.get_mut().get(&nodeid) {
-            None => {
-                let ipaddr = Ipv4Addr::new(172,16,0, nodeid);
-                self.id2ip.get_mut().insert(nodeid, ipaddr);
-                self.ip2id.get_mut().insert(ipaddr, nodeid);
-                return Ok((ipaddr, true));
+    /// Lease an IP address to a node
+    fn ip_assign(&mut self, nodeid: NodeId) -> Result<(Ipv4Addr, bool), IPAssignFailureMessage> {
+        match self.leases.assign(nodeid) {
+            Err(e) => {
+                warn!("Not leasing an address to node {}: {}", &nodeid, &e);
+                Err(IPAssignFailureMessage::new(e.to_string()))
             },
-            Some(ip) => {
-                return Ok((ip.clone(), false));
+            Ok((ipaddr, isnew)) => {
+                self.ipaddr_put(nodeid, ipaddr);
+                Ok((ipaddr, isnew))
+            }
+        }
+    }
+
+    /// Renew the lease of a node using an IP address, true if it's new to us
+    fn ip_claim(&mut self, nodeid: NodeId, ipaddr: Ipv4Addr) -> Result<bool, IPAssignFailureMessage> {
+        self.leases.claim(nodeid, ipaddr).map_err(|e| {
+            warn!("Node {} can't use address {}: {}", &nodeid, &ipaddr, &e);
+            IPAssignFailureMessage::new(e.to_string())
+        })
+    }
+
+    /// Removes nodes, and their links and IP addresses, that weren't
+    /// seen within the window, returns each expired node with its IP
+    pub fn expire(&mut self, window: Duration) -> Vec<(NodeId, Option<Ipv4Addr>)> {
+        let now = Instant::now();
+        let expired: Vec<NodeId> = self.observations.borrow().iter()
+            .filter(|(nodeid, seen)| **nodeid != self.nodeid && now.duration_since(**seen) > window)
+            .map(|(nodeid, _)| *nodeid)
+            .collect();
+
+        expired.into_iter().map(|nodeid| {
+            self.observations.borrow_mut().remove(&nodeid);
+            self.node_remove(nodeid);
+            if self.isgateway {
+                self.leases.release(nodeid);
+            }
+            let ipaddr = self.id2ip.borrow_mut().remove(&nodeid);
+            if let Some(ip) = ipaddr {
+                self.ip2id.borrow_mut().remove(&ip);
+                if self.gatewayipaddr == Some(ip) {
+                    warn!("Gateway {} expired", &nodeid);
+                    self.gatewayipaddr = None;
+                }
+            }
+            (nodeid, ipaddr)
+        }).collect()
+    }
+
+    /// What a gateway needs to remember across restarts
+    pub fn state(&self) -> GatewayState {
+        let id2ip = self.id2ip.borrow();
+        GatewayState {
+            leases: self.leases.records(),
+            nodes: self.observations.borrow().iter()
+                .filter(|(nodeid, _)| **nodeid != self.nodeid)
+                .map(|(nodeid, seen)| NodeRecord { nodeid: *nodeid, ipaddr: id2ip.get(nodeid).cloned(), lastseen: to_unix(*seen) })
+                .collect(),
+            links: self.graph.all_edges().map(|(src, dest, cost)| (src, dest, *cost)).collect(),
+            ..Default::default()
+        }
+    }
+
+    /// Take back the state saved before a restart, returns
+    /// the nodes with addresses so they can be routed again
+    pub fn restore(&mut self, state: &GatewayState) -> Vec<(NodeId, Ipv4Addr)> {
+        self.leases.restore(&state.leases);
+        let ourid = self.nodeid;
+        for node in state.nodes.iter().filter(|node| node.nodeid != ourid) {
+            self.observations.borrow_mut().insert(node.nodeid, from_unix(node.lastseen));
+            self.node_add(node.nodeid);
+            if let Some(ipaddr) = node.ipaddr {
+                self.id2ip.borrow_mut().insert(node.nodeid, ipaddr);
+                self.ip2id.borrow_mut().insert(ipaddr, node.nodeid);
+            }
+        }
+        for (src, dest, cost) in &state.links {
+            self.graph.add_edge(*src, *dest, *cost);
+        }
+        self.id2ip.borrow().iter()
+            .filter(|(nodeid, _)| **nodeid != self.nodeid)
+            .map(|(nodeid, ipaddr)| (*nodeid, *ipaddr))
+            .collect()
+    }
+
+    /// The IP address a node uses, as far as we know
+    pub fn node_ipaddr(&self, nodeid: &NodeId) -> Option<Ipv4Addr> {
+        self.id2ip.borrow().get(nodeid).cloned()
+    }
+
+    /// Map a node to its IP address, forgetting the one it used before
+    fn ipaddr_put(&mut self, nodeid: NodeId, ipaddr: Ipv4Addr) {
+        if let Some(old) = self.id2ip.get_mut().insert(nodeid, ipaddr) {
+            if old != ipaddr && self.ip2id.get_mut().get(&old) == Some(&nodeid) {
+                self.ip2id.get_mut().remove(&old);
             }
         }
+        self.ip2id.get_mut().insert(ipaddr, nodeid);
     }
 
     /// Track each node observation for routing purposes
-    fn node_observe_put(&mut self, nodeid: u8) {
+    fn node_observe_put(&mut self, nodeid: NodeId) {
         self.observations.borrow_mut().insert(nodeid, Instant::now());
     }
 
-    pub fn node_observe_get(&mut self, nodeid: &u8) -> Option<&Instant> {
+    pub fn node_observe_get(&mut self, nodeid: &NodeId) -> Option<&Instant> {
         self.observations.get_mut().get(nodeid)
     }
 
-    fn edge_add(&mut self, src: u8, dest: u8) {
-        self.graph.add_edge(src.clone(), dest.clone(), 1);
+    /// Adds a link between two nodes, new links start out as perfect
+    fn edge_add(&mut self, src: NodeId, dest: NodeId) {
+        if !self.graph.contains_edge(src, dest) {
+            self.graph.add_edge(src, dest, 1.0);
+        }
+    }
+
+    /// Update the cost of our link to a neighbour with the quality a frame was received at
+    /* Costs are an exponentially weighted moving average of the ETX
+    estimated from each frame, so a single bad frame doesn't reroute the
+    mesh but a link that keeps degrading is avoided. */
+    pub fn edge_metrics(&mut self, neighbour: NodeId, metrics: &LinkMetrics) {
+        if let Some(cost) = self.graph.edge_weight_mut(self.nodeid, neighbour) {
+            *cost = LINK_COST_ALPHA * metrics.etx() + (1.0 - LINK_COST_ALPHA) * *cost;
+            trace!("Link cost to node {} is now {}", &neighbour, &cost);
+        }
+    }
+
+    /// Removes a link between two nodes, e.g. after a route failure
+    pub fn edge_remove(&mut self, src: NodeId, dest: NodeId) -> bool {
+        self.graph.remove_edge(src, dest).is_some()
+    }
+
+    /// Check if a node is part of our mesh
+    pub fn node_known(&self, nodeid: &NodeId) -> bool {
+        self.graph.contains_node(*nodeid)
     }
 
     /// Add a new node to our mesh
-    fn node_add(&mut self, nodeid: u8) {
+    fn node_add(&mut self, nodeid: NodeId) {
         self.graph.add_node(nodeid);
     }
 
     /// Removes a node from the mesh
-    pub fn node_remove(&mut self, nodeid: u8) {
+    pub fn node_remove(&mut self, nodeid: NodeId) {
         self.graph.borrow_mut().remove_node(nodeid);
+        self.capabilities.remove(&nodeid);
+    }
+
+    /// What a node advertised it understands, nothing optional if it didn't
+    pub fn node_capabilities(&self, nodeid: &NodeId) -> Capabilities {
+        self.capabilities.get(nodeid).cloned().unwrap_or_default()
     }
 
     /// Routes an IP packet to a node in the mesh, if it's possible
-    pub fn packet_route(&mut self, packet: &Packet<Vec<u8>>) -> Option<Vec<u8>> {
+    pub fn packet_route(&mut self, packet: &Packet<Vec<u8>>) -> Option<Vec<NodeId>> {
         trace!("Routing packet from {} to {}", &packet.source(), &packet.destination());
 
         // look up ip and ensure it's in our mesh
@@ -159,15 +327,200 @@ impl MeshRouter {
         let dest = ip2id.get(&packet.destination())?;
         trace!("Found node route source {:?} destination {:?}", &src, &dest);
 
+        self.node_route(*src, *dest)
+    }
+
+    /// Finds the shortest path between two nodes, both ends included
+    pub fn node_route(&self, src: NodeId, dest: NodeId) -> Option<Vec<NodeId>> {
         match astar(
             &self.graph,
-            src.clone(),
-            |finish| finish == dest.clone(),
-            |e| e.1,
-            |_e| 0,
+            src,
+            |finish| finish == dest,
+            |e| *e.2,
+            |_e| 0.0,
         ) {
             None => None,
             Some(aresult) => Some(aresult.1)
         }
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+use crate::stack::MeshSubnet;
+
+#[cfg(test)]
+fn leases() -> Leases {
+    Leases::new(&MeshSubnet::default(), None, None, Duration::from_secs(3600), &[]).unwrap()
+}
+
+#[cfg(test)]
+#[test]
+fn router_edge_remove() {
+    let mut router = MeshRouter::new(1, None, leases(), 2, Duration::from_secs(10), false);
+    // a square mesh, 1 reaches 4 through either 2 or 3
+    router.route_add(vec![(1, 2), (2, 4), (1, 3), (3, 4)]);
+    assert_eq!(router.node_route(1, 4).unwrap().len(), 3);
+
+    // after a failure between 2 and 4 only the path through 3 remains
+    router.edge_remove(2, 4);
+    assert_eq!(router.node_route(1, 4), Some(vec![1, 3, 4]));
+    assert!(router.node_known(&2));
+
+    router.edge_remove(3, 4);
+    assert_eq!(router.node_route(1, 4), None);
+}
+
+
+#[cfg(test)]
+#[test]
+fn router_expire() {
+    let mut router = MeshRouter::new(1, None, leases(), 2, Duration::from_secs(10), false);
+    router.handle_ip_assignment(&Ipv4Addr::new(172,16,0,1));
+    router.route_add(vec![(1, 2), (2, 3)]);
+    router.handle_route_success(&Ipv4Addr::new(172,16,0,3), &[1, 2, 3]);
+
+    // recently seen nodes stay
+    assert!(router.expire(Duration::from_secs(60)).is_empty());
+
+    // everyone but ourselves goes once the window passes
+    let mut expired = router.expire(Duration::from_millis(0));
+    expired.sort();
+    assert_eq!(expired, vec![(2, None), (3, Some(Ipv4Addr::new(172,16,0,3)))]);
+    assert!(!router.node_known(&3));
+    assert!(router.node_known(&1));
+    assert_eq!(router.node_route(1, 3), None);
+}
+
+#[cfg(test)]
+#[test]
+fn router_edge_metrics() {
+    let mut router = MeshRouter::new(1, None, leases(), 2, Duration::from_secs(10), false);
+    router.route_add(vec![(1, 2), (2, 4), (1, 3), (3, 4)]);
+
+    // the link to 2 is marginal, traffic goes through 3 instead
+    let marginal = LinkMetrics { rssi: Some(-128), snr: Some(-17) };
+    for _ in 0..4 {
+        router.edge_metrics(2, &marginal);
+    }
+    router.edge_metrics(3, &LinkMetrics { rssi: Some(-70), snr: Some(8) });
+    assert_eq!(router.node_route(1, 4), Some(vec![1, 3, 4]));
+
+    // re-learning a link keeps its cost
+    router.route_add(vec![(1, 2)]);
+    assert_eq!(router.node_route(1, 4), Some(vec![1, 3, 4]));
+
+    // once it recovers it's preferred again over a worse one
+    let good = LinkMetrics { rssi: Some(-70), snr: Some(8) };
+    for _ in 0..20 {
+        router.edge_metrics(2, &good);
+        router.edge_metrics(3, &marginal);
+    }
+    assert_eq!(router.node_route(1, 4), Some(vec![1, 2, 4]));
+}
+
+#[cfg(test)]
+use crate::stack::{Frame, ToFromFrame};
+
+#[cfg(test)]
+#[test]
+fn router_capabilities() {
+    let mut router = MeshRouter::new(1, None, leases(), 2, Duration::from_secs(10), false);
+    let heard = |sender: NodeId, capabilities: Option<Capabilities>| {
+        let msg = BroadcastMessage { header: None, isgateway: false, ipOffset: 0, ipaddr: None, epoch: None, capabilities, subnet: None, serial: None, signature: None };
+        let mut frame = Frame::from_bytes(&msg.to_frame(1, sender, vec![sender]).to_bytes()).unwrap();
+        BroadcastMessage::from_frame(&mut frame).unwrap()
+    };
+
+    // nodes get what they advertised, silent ones nothing optional
+    assert!(router.handle_broadcast(heard(2, Some(Capabilities::ours())), vec![2]).is_ok());
+    assert!(router.handle_broadcast(heard(3, None), vec![3]).is_ok());
+    assert_eq!(router.node_capabilities(&2), Capabilities::ours());
+    assert_eq!(router.node_capabilities(&3), Capabilities::default());
+    assert_eq!(router.node_capabilities(&4), Capabilities::default());
+
+    // and forget it when they expire
+    router.expire(Duration::from_millis(0));
+    assert_eq!(router.node_capabilities(&2), Capabilities::default());
+}
+
+#[cfg(test)]
+#[test]
+fn router_leases() {
+    let mut router = MeshRouter::new(1, None, leases(), 2, Duration::from_secs(10), true);
+    router.handle_ip_assignment(&Ipv4Addr::new(172,16,0,1));
+    let heard = |sender: NodeId, ipaddr: Option<Ipv4Addr>| {
+        let ipOffset = if ipaddr.is_some() { 4 } else { 0 };
+        let msg = BroadcastMessage { header: None, isgateway: false, ipOffset, ipaddr, epoch: None, capabilities: None, subnet: None, serial: None, signature: None };
+        let mut frame = Frame::from_bytes(&msg.to_frame(1, sender, vec![sender]).to_bytes()).unwrap();
+        BroadcastMessage::from_frame(&mut frame).unwrap()
+    };
+
+    // nodes without an address are leased one, and renew it by broadcasting it
+    assert_eq!(router.handle_broadcast(heard(2, None), vec![2]).ok(), Some(Some((Ipv4Addr::new(172,16,0,2), true))));
+    assert_eq!(router.handle_broadcast(heard(2, Some(Ipv4Addr::new(172,16,0,2))), vec![2]).ok(), Some(None));
+
+    // another node with the same ID, or claiming the address, is refused
+    let conflict = router.handle_broadcast(heard(2, None), vec![2]).err().unwrap();
+    assert_eq!(conflict.reason, "Node ID 2 is already in use");
+    let conflict = router.handle_broadcast(heard(3, Some(Ipv4Addr::new(172,16,0,2))), vec![3]).err().unwrap();
+    assert_eq!(conflict.reason, "Address 172.16.0.2 is leased to node 2");
+    assert_eq!(router.ip2id.borrow().get(&Ipv4Addr::new(172,16,0,2)), Some(&2));
+    let conflict = router.handle_broadcast(heard(4, Some(Ipv4Addr::new(172,16,0,1))), vec![4]).err().unwrap();
+    assert_eq!(conflict.reason, "Address 172.16.0.1 is leased to node 1");
+
+    // leases are released when their node expires
+    router.expire(Duration::from_millis(0));
+    assert_eq!(router.handle_broadcast(heard(3, Some(Ipv4Addr::new(172,16,0,2))), vec![3]).ok(), Some(Some((Ipv4Addr::new(172,16,0,2), true))));
+}
+
+#[cfg(test)]
+#[test]
+fn router_state() {
+    let mut router = MeshRouter::new(1, None, leases(), 2, Duration::from_secs(10), true);
+    router.handle_ip_assignment(&Ipv4Addr::new(172,16,0,1));
+    router.route_add(vec![(1, 2), (2, 3)]);
+    assert_eq!(router.ip_assign(3).ok(), Some((Ipv4Addr::new(172,16,0,3), true)));
+
+    // a restarted gateway routes and leases as before
+    let state = router.state();
+    let mut restarted = MeshRouter::new(1, None, leases(), 2, Duration::from_secs(10), true);
+    restarted.handle_ip_assignment(&Ipv4Addr::new(172,16,0,1));
+    assert_eq!(restarted.restore(&state), vec![(3, Ipv4Addr::new(172,16,0,3))]);
+    assert_eq!(restarted.node_route(1, 3), Some(vec![1, 2, 3]));
+    assert_eq!(restarted.ip_assign(3).ok(), Some((Ipv4Addr::new(172,16,0,3), false)));
+    assert_eq!(restarted.ip_assign(4).ok(), Some((Ipv4Addr::new(172,16,0,4), true)));
+
+    // and still forgets nodes it hasn't heard from
+    assert!(restarted.expire(Duration::from_secs(60)).is_empty());
+    let mut expired = restarted.expire(Duration::from_millis(0));
+    expired.sort();
+    assert_eq!(expired, vec![(2, None), (3, Some(Ipv4Addr::new(172,16,0,3)))]);
+}
+
+#[cfg(test)]
+#[test]
+fn router_ip_changes() {
+    let subnet = MeshSubnet::new(Ipv4Addr::new(10,42,7,0), 24).unwrap();
+    let leases = Leases::new(&subnet, None, None, Duration::from_secs(3600), &[]).unwrap();
+    let mut router = MeshRouter::new(1, None, leases, 2, Duration::from_secs(10), true);
+    router.handle_ip_assignment(&Ipv4Addr::new(10,42,7,1));
+    let heard = |sender: NodeId, ipaddr: Ipv4Addr| {
+        let msg = BroadcastMessage { header: None, isgateway: false, ipOffset: 4, ipaddr: Some(ipaddr), epoch: None, capabilities: None, subnet: None, serial: None, signature: None };
+        let mut frame = Frame::from_bytes(&msg.to_frame(1, sender, vec![sender]).to_bytes()).unwrap();
+        BroadcastMessage::from_frame(&mut frame).unwrap()
+    };
+
+    // a node still using an address from another subnet is moved into ours
+    let moved = router.handle_broadcast(heard(2, Ipv4Addr::new(172,16,0,2)), vec![2]).ok();
+    assert_eq!(moved, Some(Some((Ipv4Addr::new(10,42,7,2), true))));
+    assert_eq!(router.node_ipaddr(&2), Some(Ipv4Addr::new(10,42,7,2)));
+    assert_eq!(router.ip2id.borrow().get(&Ipv4Addr::new(172,16,0,2)), None);
+
+    // and once it announces its new address that's all we know of it
+    assert_eq!(router.handle_broadcast(heard(2, Ipv4Addr::new(10,42,7,2)), vec![2]).ok(), Some(None));
+    assert_eq!(router.ip2id.borrow().get(&Ipv4Addr::new(10,42,7,2)), Some(&2));
+
+    // our own address can change too
+    router.handle_ip_assignment(&Ipv4Addr::new(10,42,7,9));
+    assert_eq!(router.node_ipaddr(&1), Some(Ipv4Addr::new(10,42,7,9)));
+    assert_eq!(router.ip2id.borrow().get(&Ipv4Addr::new(10,42,7,1)), None);
+}
@@ -0,0 +1,191 @@
+use log::*;
+use std::time::{Duration, Instant};
+use std::sync::{Arc, Mutex};
+use crate::stack::NodeId;
+
+/// Frames the radio loop may send per slot before it is rate limited
+pub const FRAMES_PER_SLOT: u32 = 3;
+
+/// Slotted (TDMA) transmit scheduler
+/* Time is divided into superframes of `numslots` slots, each node owns the
+slot matching its node ID and only keys up its radio inside it. Nodes share
+the epoch the superframes are counted from by learning it from the gateway's
+broadcast. A scheduler with no slots lets the radio transmit at any time.
+Independently of slots, transmissions are deferred while a neighbour has
+reserved the channel with RTS/CTS. */
+#[derive(Clone)]
+pub struct TdmaScheduler {
+    nodeid: NodeId,
+    slotlen: Duration,
+    numslots: u8,
+    guard: Duration,
+    epoch: Arc<Mutex<Instant>>,
+    /// channel reserved by a neighbour until this time
+    deferred: Arc<Mutex<Option<Instant>>>,
+}
+
+impl TdmaScheduler {
+    pub fn new(nodeid: NodeId, slotlen: Duration, numslots: u8, guard: Duration) -> Self {
+        TdmaScheduler {
+            nodeid,
+            slotlen,
+            numslots,
+            guard,
+            epoch: Arc::new(Mutex::new(Instant::now())),
+            deferred: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// A scheduler that never holds back transmissions
+    pub fn disabled(nodeid: NodeId) -> Self {
+        TdmaScheduler::new(nodeid, Duration::from_millis(0), 0, Duration::from_millis(0))
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.numslots > 0 && self.slotlen > Duration::from_millis(0)
+    }
+
+    /// The slot this node is allowed to transmit in
+    pub fn slot(&self) -> u8 {
+        (self.nodeid % self.numslots.max(1) as NodeId) as u8
+    }
+
+    /// Length of a full cycle through every slot
+    pub fn superframe(&self) -> Duration {
+        self.slotlen * self.numslots as u32
+    }
+
+    /// Milliseconds elapsed in the current superframe, this is what
+    /// gateways advertise so other nodes can align to them
+    pub fn phase(&self) -> u32 {
+        if !self.enabled() { return 0; }
+        let elapsed = self.epoch.lock().unwrap().elapsed();
+        (elapsed.as_millis() % self.superframe().as_millis()) as u32
+    }
+
+    /// Phase at which a frame queued now will likely go on air, either
+    /// right away or at the start of our next slot
+    pub fn next_phase(&self) -> u32 {
+        if self.can_transmit() {
+            self.phase()
+        } else {
+            (self.slotlen * self.slot() as u32).as_millis() as u32
+        }
+    }
+
+    /// Align our superframes with a phase advertised by another node
+    pub fn sync(&self, phase: u32) {
+        if !self.enabled() { return; }
+        let phase = Duration::from_millis(phase as u64 % self.superframe().as_millis() as u64);
+        let now = Instant::now();
+        match now.checked_sub(phase) {
+            None => debug!("Ignoring TDMA epoch too far in the past"),
+            Some(epoch) => {
+                trace!("TDMA epoch synchronized at phase {}ms", phase.as_millis());
+                *self.epoch.lock().unwrap() = epoch;
+            }
+        }
+    }
+
+    /// Time left in our own slot, none if outside of it
+    pub fn remaining(&self) -> Option<Duration> {
+        if !self.enabled() { return None; }
+        let phase = Duration::from_millis(self.phase() as u64);
+        let start = self.slotlen * self.slot() as u32;
+        let end = start + self.slotlen;
+        if phase >= start && phase < end {
+            Some(end - phase)
+        } else {
+            None
+        }
+    }
+
+    /// Stay off the channel for the given time, a neighbour reserved it
+    pub fn defer(&self, duration: Duration) {
+        let until = Instant::now() + duration;
+        let mut deferred = self.deferred.lock().unwrap();
+        if deferred.map(|d| d < until).unwrap_or(true) {
+            *deferred = Some(until);
+        }
+    }
+
+    /// Check if a neighbour's reservation is keeping us off the channel
+    pub fn is_deferred(&self) -> bool {
+        match *self.deferred.lock().unwrap() {
+            None => false,
+            Some(until) => Instant::now() < until,
+        }
+    }
+
+    /// Estimate how long sending the given number of frames occupies the
+    /// channel, used to size RTS/CTS reservations
+    pub fn reservation(&self, frames: usize) -> Duration {
+        let slots = (frames as u32).div_ceil(FRAMES_PER_SLOT);
+        if !self.enabled() || slots == 0 {
+            return self.slotlen * slots;
+        }
+        // we only get one slot per superframe
+        self.superframe() * (slots - 1) + self.slotlen
+    }
+
+    /// Longest we expect to wait for a neighbour to answer
+    pub fn turnaround(&self) -> Duration {
+        if self.enabled() {
+            self.superframe() * 2
+        } else {
+            self.slotlen * 2
+        }
+    }
+
+    /// Check if the radio may key up right now, we stop short of the
+    /// end of our slot by the guard time so transmissions don't spill
+    /// into the next node's slot
+    pub fn can_transmit(&self) -> bool {
+        if self.is_deferred() { return false; }
+        if !self.enabled() { return true; }
+        match self.remaining() {
+            None => false,
+            Some(remaining) => remaining > self.guard,
+        }
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn scheduler_slots() {
+    let slotlen = Duration::from_millis(100);
+    let scheduler = TdmaScheduler::new(6, slotlen, 4, Duration::from_millis(10));
+    assert!(scheduler.enabled());
+    assert_eq!(scheduler.slot(), 2);
+    assert_eq!(scheduler.superframe(), Duration::from_millis(400));
+
+    // align the superframe so we're in the middle of our slot
+    scheduler.sync(250);
+    assert!(scheduler.phase() >= 250 && scheduler.phase() < 300);
+    assert!(scheduler.can_transmit());
+    assert!(scheduler.remaining().unwrap() <= Duration::from_millis(50));
+
+    // inside the guard time we hold back
+    scheduler.sync(295);
+    assert!(!scheduler.can_transmit());
+
+    // and another node's slot is off limits
+    scheduler.sync(50);
+    assert!(!scheduler.can_transmit());
+    assert_eq!(scheduler.remaining(), None);
+    assert_eq!(scheduler.next_phase(), 200);
+
+    // reservations span one slot per superframe
+    assert_eq!(scheduler.reservation(3), Duration::from_millis(100));
+    assert_eq!(scheduler.reservation(7), Duration::from_millis(900));
+
+    // without slots, we can always transmit
+    let disabled = TdmaScheduler::disabled(6);
+    assert!(!disabled.enabled());
+    assert!(disabled.can_transmit());
+
+    // unless a neighbour reserved the channel
+    disabled.defer(Duration::from_millis(50));
+    assert!(disabled.is_deferred());
+    assert!(!disabled.can_transmit());
+}
@@ -0,0 +1,174 @@
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::io::{Error, ErrorKind};
+use std::time::{SystemTime, UNIX_EPOCH};
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use crate::stack::{MessageType, NodeId};
+
+/// Bytes of an Ed25519 signature
+pub const SIGNATURE_LEN: usize = 64;
+
+/// Decode a hex encoded 32 byte key
+fn decode_key(hexkey: &str, name: &str) -> std::io::Result<[u8; 32]> {
+    let bytes = hex::decode(hexkey.trim()).map_err(|_| Error::new(ErrorKind::InvalidInput, format!("{} is not valid hex", name)))?;
+    bytes.as_slice().try_into().map_err(|_| Error::new(ErrorKind::InvalidInput, format!("{} must be 32 bytes", name)))
+}
+
+/// Signs and checks the claims gateways make to the mesh
+/* Any node that knows the network key could otherwise claim to be a
+gateway and hand out addresses. Gateways sign their broadcasts and IP
+assignments with an Ed25519 key, and nodes only believe gateways whose
+public key they were given. When no trusted keys are configured, every
+gateway is believed as before.
+
+A signature alone could be captured and replayed forever, so every signed
+claim carries a serial that only grows, starting from the clock in
+microseconds like the frame counter. Nodes drop claims older than the
+newest of the same kind they believed from that gateway. */
+#[derive(Clone)]
+pub struct GatewayKeys {
+    signing: Option<SigningKey>,
+    trusted: Vec<VerifyingKey>,
+    /// serial of our next signed claim
+    serial: u64,
+    /// newest serial believed from each gateway, per kind of claim
+    newest: HashMap<(NodeId, u8), u64>,
+}
+
+impl GatewayKeys {
+    /// Keys from the settings, our hex encoded secret key if we're a
+    /// gateway and the hex encoded public keys of the trusted gateways
+    pub fn new(secret: &Option<String>, trusted: &[String]) -> std::io::Result<Self> {
+        let signing = match secret {
+            None => None,
+            Some(secret) => Some(SigningKey::from_bytes(&decode_key(secret, "Gateway key")?)),
+        };
+        let trusted = trusted.iter()
+            .map(|key| VerifyingKey::from_bytes(&decode_key(key, "Trusted gateway key")?)
+                .map_err(|_| Error::new(ErrorKind::InvalidInput, "Trusted gateway key is not a valid public key")))
+            .collect::<std::io::Result<Vec<VerifyingKey>>>()?;
+        let serial = SystemTime::now().duration_since(UNIX_EPOCH).map(|now| now.as_micros() as u64).unwrap_or(0);
+        Ok(GatewayKeys { signing, trusted, serial, newest: HashMap::new() })
+    }
+
+    /// Neither sign nor check gateway claims
+    #[cfg(test)]
+    pub fn none() -> Self {
+        GatewayKeys { signing: None, trusted: Vec::new(), serial: 0, newest: HashMap::new() }
+    }
+
+    /// Our hex encoded public key, for the trusted gateways of other nodes
+    pub fn public(&self) -> Option<String> {
+        self.signing.as_ref().map(|key| hex::encode(key.verifying_key().as_bytes()))
+    }
+
+    /// Sign a claim, if we have a key
+    pub fn sign(&self, data: &[u8]) -> Option<Vec<u8>> {
+        self.signing.as_ref().map(|key| key.sign(data).to_bytes().to_vec())
+    }
+
+    /// Serial for the next claim we sign, if we have a key
+    pub fn next_serial(&mut self) -> Option<u64> {
+        self.signing.as_ref()?;
+        self.serial += 1;
+        Some(self.serial)
+    }
+
+    /// Serial of the last claim we signed
+    pub fn serial(&self) -> u64 {
+        self.serial
+    }
+
+    /// Continue from a serial saved before we restarted, if it's ahead of ours
+    pub fn resume(&mut self, serial: u64) {
+        self.serial = self.serial.max(serial);
+    }
+
+    /// Whether a claim should be believed, it must be signed by
+    /// a trusted gateway if there are any
+    pub fn verify(&self, data: &[u8], signature: &Option<Vec<u8>>) -> bool {
+        if self.trusted.is_empty() { return true; }
+        let signature = match signature.as_ref().and_then(|sig| Signature::from_slice(sig).ok()) {
+            None => return false,
+            Some(signature) => signature,
+        };
+        self.trusted.iter().any(|key| key.verify(data, &signature).is_ok())
+    }
+
+    /// Whether a claim of `kind` from `gateway` should be believed, it must
+    /// be verified and no older than the newest one believed before
+    /* Copies of a broadcast relayed along other paths carry the same serial
+    and are still believed, they only tell us about other routes. */
+    pub fn accept(&mut self, gateway: NodeId, kind: MessageType, serial: Option<u64>, data: &[u8], signature: &Option<Vec<u8>>) -> bool {
+        if self.trusted.is_empty() { return true; }
+        let serial = match serial {
+            Some(serial) if self.verify(data, signature) => serial,
+            _ => return false,
+        };
+        let newest = self.newest.entry((gateway, kind as u8)).or_insert(serial);
+        if serial < *newest { return false; }
+        *newest = serial;
+        true
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn gateway_keys_sign_verify() {
+    let secret = "9d61b19deffd5a60ba844af492ec2cc44449c5697b326919703bac031cae7f60";
+    let public = "d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a";
+    let gateway = GatewayKeys::new(&Some(String::from(secret)), &[]).unwrap();
+    assert_eq!(gateway.public().unwrap(), public);
+
+    let node = GatewayKeys::new(&None, &[String::from(public)]).unwrap();
+    let signature = gateway.sign(b"claim");
+    assert_eq!(signature.as_ref().unwrap().len(), SIGNATURE_LEN);
+    assert!(node.verify(b"claim", &signature));
+
+    // other claims, missing signatures and other gateways are not believed
+    assert!(!node.verify(b"other claim", &signature));
+    assert!(!node.verify(b"claim", &None));
+    let other = GatewayKeys::new(&Some(secret.replace("9d", "00")), &[]).unwrap();
+    assert!(!node.verify(b"claim", &other.sign(b"claim")));
+
+    // without trusted keys anything goes
+    assert!(GatewayKeys::none().verify(b"claim", &None));
+    assert!(GatewayKeys::none().sign(b"claim").is_none());
+    assert!(GatewayKeys::new(&None, &[String::from("abcd")]).is_err());
+}
+
+#[cfg(test)]
+#[test]
+fn gateway_keys_serials() {
+    let secret = "9d61b19deffd5a60ba844af492ec2cc44449c5697b326919703bac031cae7f60";
+    let public = "d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a";
+    let mut gateway = GatewayKeys::new(&Some(String::from(secret)), &[]).unwrap();
+    let mut node = GatewayKeys::new(&None, &[String::from(public)]).unwrap();
+    assert!(node.next_serial().is_none());
+
+    // serials grow, and resume from a saved one ahead of the clock
+    let first = gateway.next_serial().unwrap();
+    let second = gateway.next_serial().unwrap();
+    assert!(second > first);
+    gateway.resume(second + 100);
+    assert_eq!(gateway.next_serial(), Some(second + 101));
+    gateway.resume(first);
+    assert_eq!(gateway.serial(), second + 101);
+
+    // a signed claim is believed again, but not once a newer one was
+    let claim = |serial: u64| [b"claim".to_vec(), serial.to_be_bytes().to_vec()].concat();
+    let old = gateway.sign(&claim(first));
+    let new = gateway.sign(&claim(second));
+    assert!(node.accept(1, MessageType::Broadcast, Some(first), &claim(first), &old));
+    assert!(node.accept(1, MessageType::Broadcast, Some(first), &claim(first), &old));
+    assert!(node.accept(1, MessageType::Broadcast, Some(second), &claim(second), &new));
+    assert!(!node.accept(1, MessageType::Broadcast, Some(first), &claim(first), &old));
+
+    // serials are tracked per gateway and kind of claim, and required
+    assert!(node.accept(2, MessageType::Broadcast, Some(first), &claim(first), &old));
+    assert!(node.accept(1, MessageType::IPAssignSuccess, Some(first), &claim(first), &old));
+    assert!(!node.accept(3, MessageType::Broadcast, None, &claim(first), &old));
+    // a forged serial isn't counted
+    assert!(!node.accept(4, MessageType::Broadcast, Some(u64::MAX), &claim(u64::MAX), &old));
+    assert!(node.accept(4, MessageType::Broadcast, Some(first), &claim(first), &old));
+}
@@ -0,0 +1,127 @@
+use std::fs::{self, File};
+use std::io::{ErrorKind, Write};
+use std::net::Ipv4Addr;
+use std::path::Path;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use serde::{Deserialize, Serialize};
+use crate::stack::NodeId;
+
+/// An address leased to a node, and when the lease runs out
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct LeaseRecord {
+    pub nodeid: NodeId,
+    pub ipaddr: Ipv4Addr,
+    /// milliseconds since the Unix epoch
+    pub expires: u64,
+}
+
+/// A node the gateway knew of, and when it last heard of it
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct NodeRecord {
+    pub nodeid: NodeId,
+    pub ipaddr: Option<Ipv4Addr>,
+    /// milliseconds since the Unix epoch
+    pub lastseen: u64,
+}
+
+/// What a gateway remembers across restarts
+/* Without it a restarted gateway forgets which addresses it leased and the
+mesh topology, and has to rebuild both from broadcasts while nodes keep
+using addresses it no longer knows about. Times are kept as wall clock
+time, since instants don't survive a restart. Nodes that aren't gateways
+only keep their frame counter in it. */
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct GatewayState {
+    pub leases: Vec<LeaseRecord>,
+    pub nodes: Vec<NodeRecord>,
+    /// mesh links and their costs
+    pub links: Vec<(NodeId, NodeId, f32)>,
+    /// frame counter the cipher may use up to, it resumes from here
+    #[serde(default)]
+    pub counter: u64,
+    /// serial ahead of any a gateway signed a claim with, it resumes from here
+    #[serde(default)]
+    pub serial: u64,
+}
+
+impl GatewayState {
+    /// Read the state saved at `path`, none if it was never saved
+    pub fn load(path: &Path) -> std::io::Result<Option<Self>> {
+        match fs::read(path) {
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+            Ok(bytes) => Ok(Some(serde_json::from_slice(&bytes)?)),
+        }
+    }
+
+    /// Save the state to `path`, replacing what was there
+    /* The state is written to a temporary file next to it which is then
+    renamed over the old one, so a crash leaves either the old state or
+    the new one but never half of it. */
+    pub fn save(&self, path: &Path) -> std::io::Result<()> {
+        let dir = path.parent().filter(|dir| !dir.as_os_str().is_empty());
+        if let Some(dir) = dir {
+            fs::create_dir_all(dir)?;
+        }
+        let tmp = path.with_extension("tmp");
+        let mut file = File::create(&tmp)?;
+        file.write_all(&serde_json::to_vec_pretty(self)?)?;
+        file.sync_all()?;
+        fs::rename(&tmp, path)?;
+        // and make the rename itself durable
+        if let Some(dir) = dir {
+            File::open(dir)?.sync_all()?;
+        }
+        Ok(())
+    }
+}
+
+/// Wall clock time of an instant, in milliseconds since the Unix epoch
+pub fn to_unix(at: Instant) -> u64 {
+    let now = Instant::now();
+    let unix = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    let at = if at >= now { unix + (at - now) } else { unix.checked_sub(now - at).unwrap_or_default() };
+    at.as_millis() as u64
+}
+
+/// Instant of a wall clock time, in milliseconds since the Unix epoch
+pub fn from_unix(ms: u64) -> Instant {
+    let now = Instant::now();
+    let unix = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    let at = Duration::from_millis(ms);
+    if at >= unix { now + (at - unix) } else { now.checked_sub(unix - at).unwrap_or(now) }
+}
+
+#[cfg(test)]
+#[test]
+fn state_save_load() {
+    let dir = std::env::temp_dir().join(format!("loramesh-state-{}", std::process::id()));
+    let path = dir.join("state.json");
+    assert_eq!(GatewayState::load(&path).unwrap(), None);
+
+    let state = GatewayState {
+        leases: vec![LeaseRecord { nodeid: 2, ipaddr: Ipv4Addr::new(172,16,0,2), expires: 1_700_000_000_000 }],
+        nodes: vec![NodeRecord { nodeid: 2, ipaddr: Some(Ipv4Addr::new(172,16,0,2)), lastseen: 1_600_000_000_000 }],
+        links: vec![(1, 2, 1.5)],
+        counter: 1_700_000_000_000_000,
+        serial: 1_700_000_000_000_001,
+    };
+    state.save(&path).unwrap();
+    assert_eq!(GatewayState::load(&path).unwrap(), Some(state.clone()));
+
+    // a write that crashed halfway leaves the saved state alone
+    fs::write(path.with_extension("tmp"), b"{\"leases\": [").unwrap();
+    assert_eq!(GatewayState::load(&path).unwrap(), Some(state));
+    GatewayState::default().save(&path).unwrap();
+    assert_eq!(GatewayState::load(&path).unwrap(), Some(GatewayState::default()));
+
+    // but a corrupt file is an error, not an empty state
+    fs::write(&path, b"{\"leases\": [").unwrap();
+    assert!(GatewayState::load(&path).is_err());
+    fs::remove_dir_all(&dir).unwrap();
+
+    // times survive the trip through the wall clock
+    let soon = Instant::now() + Duration::from_secs(60);
+    let back = from_unix(to_unix(soon));
+    assert!(back.max(soon) - back.min(soon) < Duration::from_millis(10));
+}
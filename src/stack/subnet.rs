@@ -0,0 +1,154 @@
+use std::fmt;
+use std::io::{Error, ErrorKind};
+use std::net::Ipv4Addr;
+use crate::error::FrameError;
+use crate::stack::NodeId;
+use crate::stack::util::parse_ipv4;
+
+/// Bytes a subnet takes up in a broadcast
+pub const SUBNET_LEN: usize = 5;
+
+/// Shortest prefix, so a whole 16 bit node ID fits in the host part
+const MIN_PREFIX_LEN: u8 = 8;
+/// Longest prefix that still leaves room for a few nodes
+const MAX_PREFIX_LEN: u8 = 30;
+
+/// Networks the mesh may use: the private ones of RFC 1918 and the
+/// shared address space of RFC 6598, used for carrier-grade NAT
+const LOCAL_NETWORKS: [(Ipv4Addr, u8); 4] = [
+    (Ipv4Addr::new(10,0,0,0), 8),
+    (Ipv4Addr::new(172,16,0,0), 12),
+    (Ipv4Addr::new(192,168,0,0), 16),
+    (Ipv4Addr::new(100,64,0,0), 10),
+];
+
+/// The IPv4 network node addresses are carved from
+/* A node's address is the prefix with its ID in the host bits, so
+172.16.0.0/16 gives node 258 the address 172.16.1.2. Gateways use the
+subnet from their settings and advertise it in their broadcasts, the
+other nodes take it from their gateway so the whole mesh agrees on it.
+Compressed IP headers leave out addresses that match it. */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MeshSubnet {
+    prefix: Ipv4Addr,
+    prefixlen: u8,
+}
+
+impl MeshSubnet {
+    /// A subnet of the given prefix length, host bits of the prefix are ignored
+    pub fn new(prefix: Ipv4Addr, prefixlen: u8) -> std::io::Result<Self> {
+        if !(MIN_PREFIX_LEN..=MAX_PREFIX_LEN).contains(&prefixlen) {
+            return Err(Error::new(ErrorKind::InvalidInput, format!("Mesh prefix length must be between {} and {}", MIN_PREFIX_LEN, MAX_PREFIX_LEN)));
+        }
+        let mask = u32::MAX << (32 - prefixlen);
+        let subnet = MeshSubnet { prefix: Ipv4Addr::from(u32::from(prefix) & mask), prefixlen };
+        // we install routes for the whole subnet, they mustn't shadow the internet
+        if !subnet.local() {
+            return Err(Error::new(ErrorKind::InvalidInput, format!("Mesh subnet {} must be within a private (RFC 1918) or shared (RFC 6598) network", subnet)));
+        }
+        Ok(subnet)
+    }
+
+    pub fn prefix(&self) -> Ipv4Addr {
+        self.prefix
+    }
+
+    pub fn prefixlen(&self) -> u8 {
+        self.prefixlen
+    }
+
+    /// The address of a node, if its ID fits in the host bits and
+    /// doesn't make it the network or broadcast address
+    pub fn ipaddr(&self, nodeid: NodeId) -> Option<Ipv4Addr> {
+        let host = nodeid as u32;
+        let hostmask = u32::MAX >> self.prefixlen;
+        if host == 0 || host >= hostmask {
+            return None;
+        }
+        Some(Ipv4Addr::from(u32::from(self.prefix) | host))
+    }
+
+    /// First and last address a node can have, leaving out the
+    /// network and broadcast addresses
+    pub fn hosts(&self) -> (Ipv4Addr, Ipv4Addr) {
+        let first = u32::from(self.prefix) + 1;
+        let last = u32::from(self.prefix) + (u32::MAX >> self.prefixlen) - 1;
+        (Ipv4Addr::from(first), Ipv4Addr::from(last))
+    }
+
+    /// Whether the subnet lies within one of the local networks
+    fn local(&self) -> bool {
+        LOCAL_NETWORKS.iter().any(|(prefix, prefixlen)| {
+            self.prefixlen >= *prefixlen && MeshSubnet { prefix: *prefix, prefixlen: *prefixlen }.contains(&self.prefix)
+        })
+    }
+
+    /// Whether an address belongs to the mesh
+    pub fn contains(&self, ipaddr: &Ipv4Addr) -> bool {
+        let mask = u32::MAX << (32 - self.prefixlen);
+        u32::from(*ipaddr) & mask == u32::from(self.prefix)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::from(&self.prefix.octets()[..]);
+        bytes.push(self.prefixlen);
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, FrameError> {
+        let bytes = bytes.get(0..SUBNET_LEN).ok_or(FrameError::Truncated("subnet"))?;
+        let (prefix, prefixlen) = (parse_ipv4(&bytes[0..4]), bytes[4]);
+        if !(MIN_PREFIX_LEN..=MAX_PREFIX_LEN).contains(&prefixlen) {
+            return Err(FrameError::InvalidPrefixLength(prefixlen));
+        }
+        MeshSubnet::new(prefix, prefixlen).map_err(|_| FrameError::NonLocalSubnet(prefix, prefixlen))
+    }
+}
+
+impl Default for MeshSubnet {
+    /// 172.16.0.0/16, room for every node ID
+    fn default() -> Self {
+        MeshSubnet { prefix: Ipv4Addr::new(172,16,0,0), prefixlen: 16 }
+    }
+}
+
+impl fmt::Display for MeshSubnet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.prefix, self.prefixlen)
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn subnet_addresses() {
+    let subnet = MeshSubnet::default();
+    assert_eq!(subnet.ipaddr(258), Some(Ipv4Addr::new(172,16,1,2)));
+    assert_eq!(subnet.ipaddr(65534), Some(Ipv4Addr::new(172,16,255,254)));
+    assert_eq!(subnet.ipaddr(65535), None);
+    assert_eq!(subnet.ipaddr(0), None);
+    assert!(subnet.contains(&Ipv4Addr::new(172,16,9,9)));
+    assert!(!subnet.contains(&Ipv4Addr::new(172,17,0,1)));
+
+    // host bits of the prefix are dropped, and IDs must fit what's left
+    let small = MeshSubnet::new(Ipv4Addr::new(10,42,7,99), 24).unwrap();
+    assert_eq!(small.to_string(), "10.42.7.0/24");
+    assert_eq!(small.ipaddr(5), Some(Ipv4Addr::new(10,42,7,5)));
+    assert_eq!(small.ipaddr(255), None);
+    assert_eq!(small.ipaddr(256), None);
+    assert_eq!(small.hosts(), (Ipv4Addr::new(10,42,7,1), Ipv4Addr::new(10,42,7,254)));
+    assert!(MeshSubnet::new(Ipv4Addr::new(10,0,0,0), 31).is_err());
+    assert!(MeshSubnet::new(Ipv4Addr::new(10,0,0,0), 4).is_err());
+
+    // only private and shared networks can be used
+    assert!(MeshSubnet::new(Ipv4Addr::new(100,64,0,0), 16).is_ok());
+    assert!(MeshSubnet::new(Ipv4Addr::new(192,168,4,0), 24).is_ok());
+    assert!(MeshSubnet::new(Ipv4Addr::new(8,8,0,0), 16).is_err());
+    assert!(MeshSubnet::new(Ipv4Addr::new(172,0,0,0), 8).is_err());
+    assert!(MeshSubnet::new(Ipv4Addr::new(100,128,0,0), 16).is_err());
+
+    // and it goes over the air as the prefix and its length
+    assert_eq!(MeshSubnet::from_bytes(&small.to_bytes()).unwrap(), small);
+    assert_eq!(MeshSubnet::from_bytes(&[10u8, 0, 0]).err(), Some(FrameError::Truncated("subnet")));
+    assert_eq!(MeshSubnet::from_bytes(&[10u8, 0, 0, 0, 40]).err(), Some(FrameError::InvalidPrefixLength(40)));
+    assert_eq!(MeshSubnet::from_bytes(&[8u8, 8, 0, 0, 16]).err(), Some(FrameError::NonLocalSubnet(Ipv4Addr::new(8,8,0,0), 16)));
+}
@@ -9,11 +9,45 @@ use crossbeam_channel;
 use crossbeam_channel::{Receiver, Sender};
 use packet::ip::v4::Packet;
 use std::sync::Arc;
+use std::collections::HashMap;
+
+/// Local network interface the mesh exchanges IP packets with
+/* Implementations only move packets and install the addresses and routes
+`MeshNode` asks for. `NetworkTunnel` drives a kernel tun device, the
+simulated tunnel lets nodes run in tests without touching the host. */
+pub trait Tunnel {
+    /// start receiving packets, returns the channel they arrive on
+    fn run(&self) -> Receiver<Packet<Vec<u8>>>;
+
+    /// deliver a packet from the mesh to the local network
+    fn send(&mut self, packet: Packet<Vec<u8>>);
+
+    /// address of the local end of the tunnel, mesh routes go through it
+    fn tunip(&self) -> Ipv4Addr;
+
+    /// add an IP address to the interface
+    fn assignipaddr(&mut self, ipaddr: &Ipv4Addr);
+
+    /// remove an IP address added with `assignipaddr`
+    fn unassignipaddr(&mut self, ipaddr: &Ipv4Addr);
+
+    /// route traffic for `dest` into the mesh via `via`, replacing
+    /// the route it had
+    fn routeipaddr(&mut self, dest: &Ipv4Addr, via: &Ipv4Addr);
+
+    /// withdraw a route set up with `routeipaddr`
+    fn unrouteipaddr(&mut self, dest: &Ipv4Addr);
+
+    /// routes currently installed, the address they go via by destination
+    fn routes(&self) -> HashMap<Ipv4Addr, Ipv4Addr>;
+}
 
 pub struct NetworkTunnel {
     pub tunname: String,
     pub interface: Arc<Iface>,
     pub tunip: Option<Ipv4Addr>,
+    /// kernel routes we installed, the address they go via by destination
+    pub routes: HashMap<Ipv4Addr, Ipv4Addr>,
     /// receiver for packets coming from tun
     pub inboundSender: Sender<Packet<Vec<u8>>>,
     pub inboundReceiver: Receiver<Packet<Vec<u8>>>
@@ -38,14 +72,14 @@ fn tunloop(iface: Arc<Iface>, sender: Sender<Packet<Vec<u8>>>) {
 }
 
 impl NetworkTunnel {
-    pub fn new(iface: Arc<Iface>) -> Self {
+    pub fn new(iface: Arc<Iface>, iaddr: &Ipv4Addr) -> Self {
         trace!("Iface: {:?}", iface);
 
         let tunname = String::from(iface.name().clone());
 
         // Configure the local kernel interface with a kernel
         // IP and we will route and capture traffic through it
-        let iaddr = Ipv4Addr::new(10,107,1,3);
+        let iaddr = *iaddr;
         ipassign(tunname.as_str(), &iaddr);
         ipcmd("ip", &["link", "set", "dev", tunname.as_str(), "up"]);
         info!("Created interface {} with IP addr {}", tunname, iaddr.to_string());
@@ -57,13 +91,16 @@ impl NetworkTunnel {
             tunname: tunname,
             interface: iface,
             tunip: Some(iaddr),
+            routes: HashMap::new(),
             inboundSender,
             inboundReceiver
         }
     }
+}
 
+impl Tunnel for NetworkTunnel {
     /// Start the network tunnel thread
-    pub fn run(&self) -> Receiver<Packet<Vec<u8>>> {
+    fn run(&self) -> Receiver<Packet<Vec<u8>>> {
         let sender = self.inboundSender.clone();
         let iface = Arc::clone(&self.interface);
         thread::spawn(move || tunloop(iface, sender) );
@@ -71,7 +108,7 @@ impl NetworkTunnel {
     }
 
     /// Send packet on tunnel
-    pub fn send(&mut self, packet: Packet<Vec<u8>>) {
+    fn send(&mut self, packet: Packet<Vec<u8>>) {
         let mut data = vec![0x00u8, 0x00, 0x08, 0x00];
         data.extend(packet.as_ref().iter());
         self.interface.send(&data).map(|res| trace!("Network tunnel sent {} bytes", &res) );
@@ -80,16 +117,41 @@ impl NetworkTunnel {
     /// Add IP address to this tunnel's interface
     /* This performs a kernel ip route which allows us to capture
     traffic from local interface. */
-    pub fn assignipaddr(&mut self, ipaddr: &Ipv4Addr) {
+    fn assignipaddr(&mut self, ipaddr: &Ipv4Addr) {
         ipassign(self.tunname.as_str(), ipaddr);
     }
 
+    /// Remove an IP address added with `assignipaddr`
+    fn unassignipaddr(&mut self, ipaddr: &Ipv4Addr) {
+        ipunassign(self.tunname.as_str(), ipaddr);
+    }
+
     /// Set up a route to an IP through this node
     /* This performs a kernel ip route which allows us to capture
     traffic from local interface. */
-    pub fn routeipaddr(&mut self, dest: &Ipv4Addr, via: &Ipv4Addr) {
+    fn routeipaddr(&mut self, dest: &Ipv4Addr, via: &Ipv4Addr) {
+        match self.routes.insert(*dest, *via) {
+            Some(old) if old == *via => return,
+            Some(_) => iprouteremove(self.tunname.as_str(), dest),
+            None => {},
+        }
         iproute(self.tunname.as_str(), dest, via);
     }
+
+    /// Withdraw a route set up with `routeipaddr`
+    fn unrouteipaddr(&mut self, dest: &Ipv4Addr) {
+        if self.routes.remove(dest).is_some() {
+            iprouteremove(self.tunname.as_str(), dest);
+        }
+    }
+
+    fn routes(&self) -> HashMap<Ipv4Addr, Ipv4Addr> {
+        self.routes.clone()
+    }
+
+    fn tunip(&self) -> Ipv4Addr {
+        self.tunip.unwrap()
+    }
 }
 
 /// Run a shell command and panic if it fails
@@ -106,11 +168,21 @@ pub fn ipcmd(cmd: &str, args: &[&str]) {
 /// Kernel route IP traffic to interface
 pub fn iproute(tun: &str, dest: &Ipv4Addr, via: &Ipv4Addr) {
     trace!("Adding tunnel ip route dest {} via {}", &dest.to_string(), &via.to_string());
-    assert!(dest.is_private(), "Refusing to route mesh traffic to non-private IP.");
     ipcmd("ip", &["route", "add", &dest.to_string(), "via", &via.to_string(), "dev", tun]);
 }
 
+/// Kernel remove a route to interface
+pub fn iprouteremove(tun: &str, dest: &Ipv4Addr) {
+    trace!("Removing tunnel ip route dest {}", &dest.to_string());
+    ipcmd("ip", &["route", "del", &dest.to_string(), "dev", tun]);
+}
+
 /// Kernel assign IP address to interface
 pub fn ipassign(tun: &str, addr: &Ipv4Addr) {
     ipcmd("ip", &["addr", "add", &addr.to_string(), "dev", tun]);
-}
\ No newline at end of file
+}
+
+/// Kernel remove IP address from interface
+pub fn ipunassign(tun: &str, addr: &Ipv4Addr) {
+    ipcmd("ip", &["addr", "del", &addr.to_string(), "dev", tun]);
+}
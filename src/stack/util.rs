@@ -1,10 +1,12 @@
 use std::convert::TryInto;
 use std::net::Ipv4Addr;
+use crate::error::FrameError;
+use crate::stack::frame::NodeId;
 
-pub fn parse_bool(byte: u8) -> std::io::Result<bool> {
+pub fn parse_bool(byte: u8) -> Result<bool, FrameError> {
     if byte as i8 == 0i8 { return Ok(false); }
     else if byte as i8 == 1i8 { return Ok(true); }
-    panic!("Booleans should bubble an error when parsed incorrectly: {}", byte);
+    Err(FrameError::InvalidBool(byte))
 }
 
 pub fn parse_byte(boolean: bool) -> u8 {
@@ -20,10 +22,11 @@ pub fn parse_ipv4(arr: &[u8]) -> Ipv4Addr {
     Ipv4Addr::from(to_octets(arr))
 }
 
+/// Node ID from the 2 big endian bytes it is sent as in a payload
+pub fn parse_nodeid(arr: &[u8]) -> NodeId {
+    NodeId::from_be_bytes(arr.try_into().expect("Incorrect array length for node ID"))
+}
+
 pub fn parse_string(arr: &[u8]) -> Vec<u8> {
     Vec::from(arr)
 }
-
-pub fn composite_key(id1: &u8, id2: &u8) -> String {
-    format!("{}-{}", id1, id2)
-}
\ No newline at end of file